@@ -0,0 +1,204 @@
+//! Replays an allocation trace (recorded with a `TraceRecorder`) against hosted
+//! `ZoneAllocator`s, one per heap id in the trace, and reports
+//! the peak number of pages, the fragmentation over time and the latency of operations.
+//!
+//! Usage: `cargo run --example trace_replay --no-default-features --features hooks,hosted -- <trace file> [sample interval]`
+//!
+//! Every object is allocated with the size and alignment recorded in the trace.
+
+use std::alloc::Layout;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+use std::ptr::NonNull;
+use std::time::{Duration, Instant};
+
+use slabmalloc::hosted::{create_mapping, EntryFlags, MappedPages};
+use slabmalloc::*;
+
+/// Maps a new 8 KiB page for every refill
+/// (with the hosted stand-in for the kernel's memory crate).
+struct HostedPages;
+
+impl PageSource for HostedPages {
+    fn allocate_page(&self) -> Option<MappedPages> {
+        create_mapping(ObjectPage8k::SIZE, EntryFlags::WRITABLE).ok()
+    }
+
+    fn release_page(&self, mp: MappedPages) {
+        drop(mp);
+    }
+}
+
+static PAGES: HostedPages = HostedPages;
+
+/// Latency of one kind of operation.
+#[derive(Default)]
+struct Latency {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Latency {
+    fn add(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    fn report(&self, name: &str) {
+        if self.count == 0 {
+            return;
+        }
+        println!(
+            "{:>10}: {:>10} ops, mean {:>8} ns, max {:>10} ns",
+            name,
+            self.count,
+            self.total.as_nanos() / self.count as u128,
+            self.max.as_nanos()
+        );
+    }
+}
+
+/// The state of the replay.
+struct Replay {
+    zones: HashMap<usize, ZoneAllocator<'static>>,
+    /// Objects that are live in the replay, by their address in the trace.
+    live: HashMap<usize, (usize, NonNull<u8>, Layout)>,
+    live_bytes: usize,
+    peak_pages: usize,
+    allocate: Latency,
+    deallocate: Latency,
+    trace_refills: u64,
+    failed_allocations: u64,
+    unmatched_frees: u64,
+}
+
+impl Replay {
+    fn new() -> Replay {
+        Replay {
+            zones: HashMap::new(),
+            live: HashMap::new(),
+            live_bytes: 0,
+            peak_pages: 0,
+            allocate: Latency::default(),
+            deallocate: Latency::default(),
+            trace_refills: 0,
+            failed_allocations: 0,
+            unmatched_frees: 0,
+        }
+    }
+
+    fn pages(&self) -> usize {
+        self.zones.values().map(|zone| zone.pages()).sum()
+    }
+
+    fn apply(&mut self, event: TraceEvent) {
+        match event.kind {
+            TraceKind::Allocate => {
+                let layout = match Layout::from_size_align(event.size, event.align) {
+                    Ok(layout) if layout.size() <= <ZoneAllocator>::MAX_ALLOC_SIZE => layout,
+                    _ => {
+                        self.failed_allocations += 1;
+                        return;
+                    }
+                };
+                // An object at the same address must have been freed without us seeing it.
+                if self.live.contains_key(&event.addr) {
+                    self.free(event.addr);
+                }
+
+                let zone = self.zones.entry(event.heap_id).or_insert_with(|| {
+                    let mut zone: ZoneAllocator = ZoneAllocator::new();
+                    zone.set_page_source(&PAGES, event.heap_id);
+                    zone
+                });
+                let start = Instant::now();
+                let res = zone.allocate(layout);
+                self.allocate.add(start.elapsed());
+
+                match res {
+                    Ok(ptr) => {
+                        self.live.insert(event.addr, (event.heap_id, ptr, layout));
+                        self.live_bytes += layout.size();
+                    }
+                    Err(_e) => self.failed_allocations += 1,
+                }
+            }
+            TraceKind::Deallocate => {
+                if !self.live.contains_key(&event.addr) {
+                    self.unmatched_frees += 1;
+                    return;
+                }
+                self.free(event.addr);
+            }
+            TraceKind::Refill => self.trace_refills += 1,
+        }
+        self.peak_pages = self.peak_pages.max(self.pages());
+    }
+
+    fn free(&mut self, addr: usize) {
+        let (heap_id, ptr, layout) = self.live.remove(&addr).expect("Object is live");
+        let zone = self.zones.get_mut(&heap_id).expect("Zone of a live object");
+        let start = Instant::now();
+        zone.deallocate(ptr, layout).expect("Can't free a replayed object");
+        self.deallocate.add(start.elapsed());
+        self.live_bytes -= layout.size();
+    }
+
+    /// Prints the fragmentation after `ops` operations.
+    fn sample(&self, ops: usize) {
+        let pages = self.pages();
+        let utilization = if pages == 0 {
+            100.0
+        } else {
+            100.0 * self.live_bytes as f64 / (pages * ObjectPage8k::SIZE) as f64
+        };
+        println!(
+            "{:>10} ops: {:>6} pages, {:>10} live bytes, {:>5.1}% used",
+            ops, pages, self.live_bytes, utilization
+        );
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <trace file> [sample interval]", args[0]);
+        process::exit(1);
+    }
+    let interval: usize = args.get(2).and_then(|arg| arg.parse().ok()).unwrap_or(10_000);
+
+    let trace = fs::read(&args[1]).unwrap_or_else(|e| {
+        eprintln!("Can't read {}: {}", args[1], e);
+        process::exit(1);
+    });
+    let reader = TraceReader::new(&trace).unwrap_or_else(|e| {
+        eprintln!("Can't replay {}: {}", args[1], e);
+        process::exit(1);
+    });
+
+    let mut replay = Replay::new();
+    let mut ops = 0;
+    println!("Fragmentation over time:");
+    for event in reader {
+        replay.apply(event);
+        ops += 1;
+        if interval > 0 && ops % interval == 0 {
+            replay.sample(ops);
+        }
+    }
+    replay.sample(ops);
+
+    println!();
+    println!("Peak pages: {} ({} KiB)", replay.peak_pages, replay.peak_pages * ObjectPage8k::SIZE / 1024);
+    println!("Refills in the trace: {}", replay.trace_refills);
+    println!("Live objects at the end: {}", replay.live.len());
+    println!("Failed allocations: {}", replay.failed_allocations);
+    println!("Frees of unknown objects: {}", replay.unmatched_frees);
+    println!("Latency:");
+    replay.allocate.report("allocate");
+    replay.deallocate.report("deallocate");
+}
@@ -0,0 +1,132 @@
+//! Implementations of the unstable `core::alloc::Allocator` trait.
+//!
+//! This allows using the allocators of this crate with collections
+//! (e.g., `Vec::new_in` or `Box::new_in`).
+//!
+//! `deallocate` can't report errors: if a block can't be freed (e.g., its red zone
+//! or poison pattern is corrupted), the error is logged and the block is leaked.
+
+use crate::*;
+use core::alloc::AllocError;
+
+/// Turns a pointer to a block of `size` bytes into the slice the `Allocator` trait expects.
+fn block(ptr: NonNull<u8>, size: usize) -> NonNull<[u8]> {
+    NonNull::slice_from_raw_parts(ptr, size)
+}
+
+/// The usable size of a block allocated for `layout` by a `ZoneAllocator` of `P`s.
+fn zone_block_size<P: AllocablePage>(layout: Layout) -> usize {
+    ZoneAllocator::<P>::get_max_size(ZoneAllocator::<P>::class_size(layout)).unwrap_or(layout.size())
+}
+
+unsafe impl<'a, L: RawLock, P: AllocablePage> core::alloc::Allocator for LockedZoneAllocator<'a, L, P> {
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        LockedZoneAllocator::allocate(self, layout)
+            .map(|ptr| block(ptr, zone_block_size::<P>(layout)))
+            .map_err(|_e| AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Err(e) = LockedZoneAllocator::deallocate(self, ptr, layout) {
+            error!("Couldn't deallocate {:p} ({:?}): {}, leaking it", ptr, layout, e);
+        }
+    }
+
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+            .map(|ptr| block(ptr, zone_block_size::<P>(new_layout)))
+            .map_err(|_e| AllocError)
+    }
+
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+            .map(|ptr| block(ptr, zone_block_size::<P>(new_layout)))
+            .map_err(|_e| AllocError)
+    }
+}
+
+/// A `ConcurrentSCAllocator` serves a single size class: every block it hands out
+/// is `size()` bytes large, so growing or shrinking within that size never moves
+/// the block (unless it needs a stricter alignment).
+unsafe impl<'a, P: AllocablePage> core::alloc::Allocator for ConcurrentSCAllocator<'a, P> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > self.size() {
+            return Err(AllocError);
+        }
+
+        ConcurrentSCAllocator::allocate(self, layout)
+            .map(|ptr| block(ptr, self.size()))
+            .map_err(|_e| AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Err(e) = ConcurrentSCAllocator::deallocate(self, ptr, layout) {
+            error!("Couldn't deallocate {:p} ({:?}): {}, leaking it", ptr, layout, e);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
+
+impl<'a, P: AllocablePage> ConcurrentSCAllocator<'a, P> {
+    /// Grows or shrinks the block at `ptr` to `new_layout` for `grow` and `shrink`.
+    ///
+    /// The block only moves if it doesn't satisfy the alignment of `new_layout`.
+    /// If the old block can't be freed, the new one is given back and the old one stays allocated.
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.size() > self.size() {
+            return Err(AllocError);
+        }
+        if (ptr.as_ptr() as usize).is_multiple_of(new_layout.align()) {
+            return Ok(block(ptr, self.size()));
+        }
+
+        // Same size class, but we need a block with a stricter alignment.
+        let new_ptr = ConcurrentSCAllocator::allocate(self, new_layout).map_err(|_e| AllocError)?;
+        ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.as_ptr(),
+            core::cmp::min(old_layout.size(), new_layout.size()),
+        );
+        if ConcurrentSCAllocator::deallocate(self, ptr, old_layout).is_err() {
+            // The new block is unused, so freeing it can't fail.
+            let _ = ConcurrentSCAllocator::deallocate(self, new_ptr, new_layout);
+            return Err(AllocError);
+        }
+        Ok(block(new_ptr, self.size()))
+    }
+}
@@ -0,0 +1,279 @@
+//! Typed object caches (similar to `kmem_cache` in the slab allocator).
+
+use crate::*;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+
+/// Runs once for every object slot when the page that holds it is added to the cache.
+pub type Constructor<T> = fn(&mut MaybeUninit<T>);
+
+/// Runs once for every object slot when the page that holds it is reclaimed from the cache.
+pub type Destructor<T> = fn(&mut T);
+
+/// A cache of objects of type `T`, backed by an `SCAllocator<P>`
+/// that is sized and aligned for `T`.
+///
+/// If the cache has a constructor, every object slot is constructed once,
+/// when its page is added to the cache (`refill`). Objects are handed out in
+/// their constructed state by `allocate` and go back into the cache unchanged
+/// when their `SlabBox` is dropped (i.e., users should return them in their
+/// constructed state). The destructor runs on all objects of a page when the
+/// page is taken out of the cache with `retrieve_empty_page`.
+///
+/// Without a constructor the cache behaves like a typed allocator: objects are
+/// moved in with `allocate_with` and dropped together with their `SlabBox`.
+///
+/// With the `poison` feature freed objects are overwritten, so the constructor
+/// runs on every `allocate` and the destructor when the `SlabBox` is dropped instead.
+///
+/// Dropping the cache gives its pages back to the page source (or unmaps them
+/// if there is none), after running the destructor on their objects.
+pub struct ObjectCache<'a, T, L: RawLock = Spinlock, P: AllocablePage = ObjectPage8k<'a>> {
+    allocator: Locked<L, SCAllocator<'a, P>>,
+    constructor: Option<Constructor<T>>,
+    destructor: Option<Destructor<T>>,
+    /// Where we get new pages from (and give them back to), if set.
+    page_source: Option<&'a dyn PageSource>,
+    /// The heap id we stamp pages from `page_source` with.
+    heap_id: usize,
+    /// Objects are created and dropped by whichever CPU uses the cache,
+    /// see the `Send` and `Sync` impls.
+    phantom: PhantomData<*const T>,
+}
+
+unsafe impl<'a, T: Send, L: RawLock + Send, P: AllocablePage + Send> Send for ObjectCache<'a, T, L, P> {}
+// Objects allocated on one thread may be destroyed on another (e.g., by `retrieve_empty_page`).
+unsafe impl<'a, T: Send, L: RawLock + Sync, P: AllocablePage + Send> Sync for ObjectCache<'a, T, L, P> {}
+
+/// Creates an instance of an object cache, we do this in a macro because we
+/// re-use the code in const and non-const functions
+macro_rules! new_object_cache {
+    ($constructor:expr, $destructor:expr) => {
+        ObjectCache {
+            allocator: Locked::new(SCAllocator::new(ObjectCache::<T, L, P>::SLOT_SIZE)),
+            constructor: $constructor,
+            destructor: $destructor,
+            page_source: None,
+            heap_id: 0,
+            phantom: PhantomData,
+        }
+    };
+}
+
+impl<'a, T, L: RawLock, P: AllocablePage> ObjectCache<'a, T, L, P> {
+    /// The size of a slot for a `T` (at least 8 bytes, a multiple of the alignment of `T`).
+    const SLOT_SIZE: usize = {
+        let size = if mem::size_of::<T>() < 8 { 8 } else { mem::size_of::<T>() };
+        (size + mem::align_of::<T>() - 1) & !(mem::align_of::<T>() - 1)
+    };
+
+    /// Creates a cache without constructor and destructor.
+    #[cfg(feature = "unstable")]
+    pub const fn new() -> ObjectCache<'a, T, L, P> {
+        new_object_cache!(None, None)
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn new() -> ObjectCache<'a, T, L, P> {
+        new_object_cache!(None, None)
+    }
+
+    /// Creates a cache whose objects are constructed by `constructor`
+    /// (and destroyed by `destructor`, if given).
+    #[cfg(feature = "unstable")]
+    pub const fn with_constructor(
+        constructor: Constructor<T>,
+        destructor: Option<Destructor<T>>,
+    ) -> ObjectCache<'a, T, L, P> {
+        new_object_cache!(Some(constructor), destructor)
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn with_constructor(
+        constructor: Constructor<T>,
+        destructor: Option<Destructor<T>>,
+    ) -> ObjectCache<'a, T, L, P> {
+        new_object_cache!(Some(constructor), destructor)
+    }
+
+    /// Sets the `PageSource` the cache refills itself from when it runs out of memory.
+    ///
+    /// Pages from the source are stamped with `heap_id`.
+    pub fn set_page_source(&mut self, source: &'a dyn PageSource, heap_id: usize) {
+        self.page_source = Some(source);
+        self.heap_id = heap_id;
+    }
+
+    /// Whether objects keep their constructed state while they are free.
+    const CONSTRUCTED_WHILE_FREE: bool = !cfg!(feature = "poison");
+
+    /// The layout of a `T`.
+    fn layout() -> Layout {
+        Layout::new::<T>()
+    }
+
+    /// Adds a new page to the cache, constructing all its object slots.
+    pub fn refill(&self, mp: MappedPages, heap_id: usize) -> Result<(), &'static str> {
+        if Self::SLOT_SIZE > ZoneAllocator::<'a, P>::MAX_ALLOC_SIZE {
+            return Err(INVALID_LAYOUT);
+        }
+
+        let vaddr = mp.start_address().value();
+        self.allocator.with(|sca| {
+            sca.refill(mp, heap_id)?;
+            if let Some(constructor) = self.constructor.filter(|_| Self::CONSTRUCTED_WHILE_FREE) {
+                for slot in 0..sca.obj_per_page {
+                    let obj = (vaddr + slot * slot_size(Self::SLOT_SIZE)) as *mut MaybeUninit<T>;
+                    constructor(unsafe { &mut *obj });
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Allocates a slot, refilling the cache from the page source if necessary.
+    fn allocate_slot(&self) -> Result<NonNull<T>, &'static str> {
+        let ptr = match self.allocator.with(|sca| sca.allocate(Self::layout())) {
+            Ok(ptr) => ptr,
+            Err(e @ OUT_OF_MEMORY) => {
+                let source = self.page_source.ok_or(e)?;
+                let mp = source.allocate_page().ok_or(OUT_OF_MEMORY)?;
+                self.refill(mp, self.heap_id)?;
+                self.allocator.with(|sca| sca.allocate(Self::layout()))?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(ptr.cast())
+    }
+
+    /// Allocates an already constructed object.
+    ///
+    /// Returns an error if the cache has no constructor, use `allocate_with` then.
+    pub fn allocate(&self) -> Result<SlabBox<'_, 'a, T, L, P>, &'static str> {
+        let constructor = self.constructor.ok_or("The object cache has no constructor")?;
+        let ptr = self.allocate_slot()?;
+        if !Self::CONSTRUCTED_WHILE_FREE {
+            constructor(unsafe { &mut *(ptr.as_ptr() as *mut MaybeUninit<T>) });
+        }
+        Ok(SlabBox { ptr, cache: self })
+    }
+
+    /// Allocates an object and moves `value` into it.
+    ///
+    /// In a cache with a constructor, `value` replaces (and drops)
+    /// the constructed object.
+    pub fn allocate_with(&self, value: T) -> Result<SlabBox<'_, 'a, T, L, P>, &'static str> {
+        let ptr = self.allocate_slot()?;
+        unsafe {
+            if self.constructor.is_some() && Self::CONSTRUCTED_WHILE_FREE {
+                *ptr.as_ptr() = value;
+            } else {
+                ptr.as_ptr().write(value);
+            }
+        }
+        Ok(SlabBox { ptr, cache: self })
+    }
+
+    /// Returns the object at `ptr` to the cache.
+    ///
+    /// This runs when a `SlabBox` is dropped, so it can't fail: if the slot can't
+    /// be freed (e.g., its red zone or poison pattern is corrupted), the error is
+    /// logged and the slot is leaked.
+    fn deallocate(&self, ptr: NonNull<T>) {
+        if self.constructor.is_none() {
+            unsafe { ptr::drop_in_place(ptr.as_ptr()) };
+        } else if !Self::CONSTRUCTED_WHILE_FREE {
+            if let Some(destructor) = self.destructor {
+                destructor(unsafe { &mut *ptr.as_ptr() });
+            }
+        }
+        if let Err(e) = self.allocator.with(|sca| sca.deallocate(ptr.cast(), Self::layout())) {
+            error!("Couldn't free the object at {:p}: {}, leaking its slot", ptr, e);
+        }
+    }
+
+    /// Takes an empty page out of the cache (running the destructor on all its objects)
+    /// and returns its MappedPages.
+    ///
+    /// Fails if the metadata of the empty pages is corrupted.
+    pub fn retrieve_empty_page(&self) -> Result<Option<MappedPages>, &'static str> {
+        self.allocator.with(|sca| {
+            let page = match sca.take_empty_page()? {
+                Some(page) => page,
+                None => return Ok(None),
+            };
+            if let Some(destructor) = self.destructor.filter(|_| Self::CONSTRUCTED_WHILE_FREE) {
+                let vaddr = page.data_addr();
+                for slot in 0..sca.obj_per_page {
+                    let obj = (vaddr + slot * slot_size(Self::SLOT_SIZE)) as *mut T;
+                    destructor(unsafe { &mut *obj });
+                }
+            }
+            Ok(Some(page.retrieve_mapped_pages()))
+        })
+    }
+}
+
+impl<'a, T, L: RawLock, P: AllocablePage> Default for ObjectCache<'a, T, L, P> {
+    fn default() -> ObjectCache<'a, T, L, P> {
+        ObjectCache::new()
+    }
+}
+
+impl<'a, T, L: RawLock, P: AllocablePage> Drop for ObjectCache<'a, T, L, P> {
+    fn drop(&mut self) {
+        // Every `SlabBox` borrows the cache, so only leaked objects keep their pages in use.
+        #[cfg(feature = "quarantine")]
+        self.allocator.get_mut().flush_quarantine();
+        // Pages behind a corrupted page are leaked.
+        while let Ok(Some(mp)) = self.retrieve_empty_page() {
+            match self.page_source {
+                Some(source) => source.release_page(mp),
+                None => drop(mp),
+            }
+        }
+    }
+}
+
+/// An object allocated from an `ObjectCache`, which is returned to the cache when dropped.
+pub struct SlabBox<'c, 'a, T, L: RawLock = Spinlock, P: AllocablePage = ObjectPage8k<'a>> {
+    ptr: NonNull<T>,
+    cache: &'c ObjectCache<'a, T, L, P>,
+}
+
+unsafe impl<'c, 'a, T: Send, L: RawLock, P: AllocablePage> Send for SlabBox<'c, 'a, T, L, P> where ObjectCache<'a, T, L, P>: Sync {}
+unsafe impl<'c, 'a, T: Sync, L: RawLock, P: AllocablePage> Sync for SlabBox<'c, 'a, T, L, P> {}
+
+impl<'c, 'a, T, L: RawLock, P: AllocablePage> SlabBox<'c, 'a, T, L, P> {
+    /// The address of the object.
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<'c, 'a, T, L: RawLock, P: AllocablePage> Deref for SlabBox<'c, 'a, T, L, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<'c, 'a, T, L: RawLock, P: AllocablePage> DerefMut for SlabBox<'c, 'a, T, L, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<'c, 'a, T, L: RawLock, P: AllocablePage> Drop for SlabBox<'c, 'a, T, L, P> {
+    fn drop(&mut self) {
+        self.cache.deallocate(self.ptr);
+    }
+}
+
+impl<'c, 'a, T: fmt::Debug, L: RawLock, P: AllocablePage> fmt::Debug for SlabBox<'c, 'a, T, L, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
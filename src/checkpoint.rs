@@ -0,0 +1,181 @@
+//! Checkpoints of the live objects in a `ZoneAllocator`, to find objects that
+//! an operation allocated but never freed.
+//!
+//! A checkpoint is a copy of the bitfields of all pages that hold objects.
+//! It is stored in a buffer provided by the caller (see `ZoneAllocator::set_checkpoint_buffer`),
+//! so taking one doesn't allocate, and the same buffer can be used for many checkpoints.
+//! Afterwards, `ZoneAllocator::diff` reports the objects that are live now
+//! but weren't at the checkpoint. `ZoneAllocator::clear_checkpoint` gives the buffer back.
+//!
+//! # Note
+//! The zone keeps its checkpoint and removes every object that is freed from it,
+//! so an object whose slot was handed out again after the checkpoint shows up in
+//! the diff (this costs a binary search over the snapshots per deallocation).
+//! Objects that came from another zone through `merge` count as new objects.
+
+use crate::*;
+
+/// Number of bitfield words we copy per page (enough for an `ObjectPage8k` or a `PageDescriptor8k`).
+const SNAPSHOT_WORDS: usize = PageDescriptor8k::MAX_OBJECTS / 64;
+
+/// The allocated slots of one page at the time of a checkpoint.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageSnapshot {
+    /// Start address of the page.
+    page: usize,
+    /// The size class the page belonged to.
+    size_class: usize,
+    /// A copy of the page's bitfield.
+    bitfield: [u64; SNAPSHOT_WORDS],
+}
+
+impl PageSnapshot {
+    fn is_allocated(&self, idx: usize) -> bool {
+        self.bitfield[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn clear(&mut self, idx: usize) {
+        self.bitfield[idx / 64] &= !(1 << (idx % 64));
+    }
+}
+
+/// The live objects of a `ZoneAllocator` at one point in time, see `ZoneAllocator::checkpoint`.
+pub struct HeapCheckpoint<'b> {
+    /// The first `len` snapshots are those of the pages with live objects, sorted by page address.
+    buffer: &'b mut [PageSnapshot],
+    /// Number of snapshots, `None` until a checkpoint is taken.
+    len: Option<usize>,
+}
+
+impl<'b> HeapCheckpoint<'b> {
+    /// Creates a checkpoint that will store its snapshots in `buffer` (nothing is recorded yet).
+    pub(crate) fn new(buffer: &'b mut [PageSnapshot]) -> HeapCheckpoint<'b> {
+        HeapCheckpoint { buffer, len: None }
+    }
+
+    /// The snapshots a checkpoint can be taken into (all of `buffer`).
+    pub(crate) fn snapshots_mut(&mut self) -> &mut [PageSnapshot] {
+        self.buffer
+    }
+
+    /// Takes the checkpoint from the first `len` snapshots (see `snapshots_mut`).
+    pub(crate) fn take(&mut self, len: usize) {
+        self.buffer[..len].sort_unstable_by_key(|snapshot| snapshot.page);
+        self.len = Some(len);
+    }
+
+    /// Has a checkpoint been taken?
+    pub(crate) fn is_taken(&self) -> bool {
+        self.len.is_some()
+    }
+
+    /// Gives back the buffer.
+    pub(crate) fn into_buffer(self) -> &'b mut [PageSnapshot] {
+        self.buffer
+    }
+
+    /// The snapshots of the checkpoint.
+    fn snapshots(&self) -> &[PageSnapshot] {
+        &self.buffer[..self.len.unwrap_or(0)]
+    }
+
+    /// Records the allocated slots of `page`, a page of `size_class`, into `snapshot`.
+    pub(crate) fn snapshot<P: AllocablePage>(snapshot: &mut PageSnapshot, page: &P, size_class: usize) {
+        snapshot.page = page.data_addr();
+        snapshot.size_class = size_class;
+        for (word, bits) in snapshot.bitfield.iter_mut().zip(page.bitfield()) {
+            *word = bits.load(core::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Number of pages with live objects at the time of the checkpoint.
+    pub fn pages(&self) -> usize {
+        self.snapshots().len()
+    }
+
+    /// Was slot `idx` of `page` allocated (for an object of `size_class`) at the time of the checkpoint?
+    pub(crate) fn was_allocated(&self, page: usize, size_class: usize, idx: usize) -> bool {
+        let pages = self.snapshots();
+        match pages.binary_search_by_key(&page, |snapshot| snapshot.page) {
+            Ok(i) => pages[i].size_class == size_class && pages[i].is_allocated(idx),
+            Err(_) => false,
+        }
+    }
+
+    /// Forgets the object in slot `idx` of `page` (it was freed), so the slot counts
+    /// as new if it is allocated again.
+    pub(crate) fn forget(&mut self, page: usize, size_class: usize, idx: usize) {
+        let len = self.len.unwrap_or(0);
+        let pages = &mut self.buffer[..len];
+        if let Ok(i) = pages.binary_search_by_key(&page, |snapshot| snapshot.page) {
+            if pages[i].size_class == size_class {
+                pages[i].clear(idx);
+            }
+        }
+    }
+
+    /// Forgets all objects (e.g., because their pages were taken away).
+    pub(crate) fn clear(&mut self) {
+        let len = self.len.unwrap_or(0);
+        for snapshot in self.buffer[..len].iter_mut() {
+            snapshot.bitfield = [0; SNAPSHOT_WORDS];
+        }
+    }
+}
+
+/// Objects that are live now but weren't at a checkpoint, counted per size class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapDiff {
+    /// The object size of every size class of the zone.
+    class_sizes: [usize; MAX_BASE_SIZE_CLASSES],
+    new_objects: [usize; MAX_BASE_SIZE_CLASSES],
+}
+
+impl Default for HeapDiff {
+    /// An empty diff for a zone of `ObjectPage8k`s.
+    fn default() -> HeapDiff {
+        HeapDiff::new(<ZoneAllocator>::BASE_ALLOC_SIZES)
+    }
+}
+
+impl HeapDiff {
+    /// An empty diff for a zone with the given size classes.
+    pub(crate) fn new(class_sizes: [usize; MAX_BASE_SIZE_CLASSES]) -> HeapDiff {
+        HeapDiff {
+            class_sizes,
+            new_objects: [0; MAX_BASE_SIZE_CLASSES],
+        }
+    }
+
+    pub(crate) fn add(&mut self, class_idx: usize) {
+        self.new_objects[class_idx] += 1;
+    }
+
+    /// Number of new objects in the size class that serves `size`.
+    pub fn new_objects(&self, size: usize) -> usize {
+        match self.class_sizes.iter().position(|class_size| size > 0 && size <= *class_size) {
+            Some(idx) => self.new_objects[idx],
+            None => 0,
+        }
+    }
+
+    /// Number of new objects over all size classes.
+    pub fn total(&self) -> usize {
+        self.new_objects.iter().sum()
+    }
+
+    /// Is the heap free of new objects?
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    /// Iterates over the size classes that have new objects,
+    /// returning the object size of the class and the number of new objects.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.class_sizes
+            .iter()
+            .zip(self.new_objects.iter())
+            .filter(|(_, count)| **count > 0)
+            .map(|(size, count)| (*size, *count))
+    }
+}
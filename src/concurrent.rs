@@ -0,0 +1,262 @@
+//! A SCAllocator variant that can allocate and deallocate from many threads at once.
+
+use crate::*;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// A slab allocator for objects of a fixed size that can be shared between CPUs.
+///
+/// In contrast to `SCAllocator` it does not sort its pages into empty, partial
+/// and full lists (these transitions would need a lock). Instead all pages live
+/// on a single list that is only ever added to while the allocator is shared:
+///
+///  * `allocate` walks the list and claims a free slot in the first page that has
+///    one, using a compare-and-swap on the page's bitfield.
+///  * `deallocate` finds the page by masking the object address and clears the bit.
+///  * `refill` pushes a new page to the front of the list with a compare-and-swap.
+///
+/// None of these need `&mut self`, so the allocator can be used from multiple
+/// CPUs at once without a global mutex. Removing pages from the list (i.e.,
+/// `retrieve_empty_page`) does require exclusive access.
+pub struct ConcurrentSCAllocator<'a, P: AllocablePage> {
+    /// Maximum possible allocation size for this allocator.
+    size: usize,
+    /// max objects per page
+    obj_per_page: usize,
+    /// Keeps track of succeeded allocations.
+    allocation_count: AtomicUsize,
+    /// Number of pages currently in the list.
+    pages: AtomicUsize,
+    /// Head of the list of pages, linked through their `next` pointers.
+    head: AtomicPtr<P>,
+    /// Picks the slots (with the `hardened` feature), the default RNG if `None`.
+    #[cfg(feature = "hardened")]
+    rng: Option<&'a dyn SlotRng>,
+    phantom: PhantomData<&'a mut P>,
+}
+
+/// Creates an instance of a concurrent scallocator, we do this in a macro because we
+/// re-use the code in const and non-const functions
+macro_rules! new_concurrent_sc_allocator {
+    ($size:expr) => {
+        ConcurrentSCAllocator {
+            size: $size,
+            obj_per_page: cmin((P::SIZE - P::METADATA_SIZE) / slot_size($size), P::MAX_OBJECTS),
+            allocation_count: AtomicUsize::new(0),
+            pages: AtomicUsize::new(0),
+            head: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "hardened")]
+            rng: None,
+            phantom: PhantomData,
+        }
+    };
+}
+
+impl<'a, P: AllocablePage> ConcurrentSCAllocator<'a, P> {
+    /// Create a new ConcurrentSCAllocator.
+    #[cfg(feature = "unstable")]
+    pub const fn new(size: usize) -> ConcurrentSCAllocator<'a, P> {
+        new_concurrent_sc_allocator!(size)
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn new(size: usize) -> ConcurrentSCAllocator<'a, P> {
+        new_concurrent_sc_allocator!(size)
+    }
+
+    /// Returns the maximum supported object size of this allocator.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of pages currently owned by this allocator.
+    pub fn pages(&self) -> usize {
+        self.pages.load(Ordering::Relaxed)
+    }
+
+    /// The number of allocations that succeeded so far.
+    pub fn allocation_count(&self) -> usize {
+        self.allocation_count.load(Ordering::Relaxed)
+    }
+
+    /// Sets the RNG used to pick slots.
+    #[cfg(feature = "hardened")]
+    pub fn set_rng(&mut self, rng: &'a dyn SlotRng) {
+        self.rng = Some(rng);
+    }
+
+    /// Returns the heap id of the first page in the list.
+    pub fn heap_id(&self) -> Option<usize> {
+        unsafe { self.head.load(Ordering::Acquire).as_ref() }.map(|page| page.heap_id())
+    }
+
+    /// Refill the allocator with a new page.
+    ///
+    /// The page is published with a compare-and-swap on the list head,
+    /// so this can run concurrently with `allocate` and `deallocate`.
+    pub fn refill(&self, mp: MappedPages, heap_id: usize) -> Result<(), &'static str> {
+        let page = SCAllocator::<'a, P>::create_allocable_page(mp, heap_id)?;
+        page.bitfield_mut().initialize(slot_size(self.size), P::SIZE - P::METADATA_SIZE);
+        #[cfg(feature = "red-zones")]
+        page.fill_red_zones(self.size, self.obj_per_page);
+        #[cfg(feature = "poison")]
+        page.poison_slots(self.size, self.obj_per_page);
+        *page.prev() = Rawlink::none();
+        let page_ptr = page as *mut P;
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // The page is not visible to other threads yet, so we can still modify it.
+            *unsafe { &mut *page_ptr }.next() = Rawlink::from_raw(head);
+            unsafe { &mut *page_ptr }.seal();
+            match self.head.compare_exchange_weak(
+                head,
+                page_ptr,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        self.pages.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Allocates a block of memory described by `layout`.
+    ///
+    /// Returns a pointer to a valid region of memory or an error if all pages are full
+    /// (the allocator then needs to be refilled).
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, &'static str> {
+        assert!(layout.size() <= self.size);
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
+        let new_layout = unsafe { Layout::from_size_align_unchecked(slot_size(self.size), layout.align()) };
+
+        let mut page = self.head.load(Ordering::Acquire);
+        while !page.is_null() {
+            // Pages are never unlinked while the allocator is shared,
+            // so the page and its `next` pointer stay valid. Other CPUs use
+            // the page at the same time, so we only ever borrow it shared.
+            let slab_page = unsafe { &*page };
+            // Don't follow the links of a corrupted page
+            slab_page.check_integrity()?;
+            #[cfg(feature = "hardened")]
+            let ptr = slab_page.allocate_random(new_layout, self.rng.unwrap_or(&DEFAULT_SLOT_RNG));
+            #[cfg(not(feature = "hardened"))]
+            let ptr = slab_page.allocate(new_layout);
+            if let Some(nptr) = NonNull::new(ptr) {
+                // A corrupted object stays allocated so it won't be handed out again.
+                #[cfg(feature = "poison")]
+                slab_page.check_poison(nptr, self.size)?;
+                self.allocation_count.fetch_add(1, Ordering::Relaxed);
+                return Ok(nptr);
+            }
+            page = slab_page.next_ptr();
+        }
+
+        Err(OUT_OF_MEMORY)
+    }
+
+    /// Allocates up to `objects.len()` objects described by `layout` in a single pass
+    /// over the pages, e.g., to refill a magazine.
+    ///
+    /// Returns the number of objects that were allocated (their addresses are at the
+    /// start of `objects`). The slots are always the lowest free ones of a page, even
+    /// with the `hardened` feature. Corrupted objects stay allocated and are left out.
+    pub fn allocate_bulk(
+        &self,
+        layout: Layout,
+        objects: &mut [MaybeUninit<NonNull<u8>>],
+    ) -> Result<usize, &'static str> {
+        assert!(layout.size() <= self.size);
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
+        let new_layout = unsafe { Layout::from_size_align_unchecked(slot_size(self.size), layout.align()) };
+
+        let mut allocated = 0;
+        let mut page = self.head.load(Ordering::Acquire);
+        while !page.is_null() && allocated < objects.len() {
+            // See `allocate`, we only ever borrow the page shared.
+            let slab_page = unsafe { &*page };
+            // Don't follow the links of a corrupted page (but keep what we got so far).
+            if let Err(e) = slab_page.check_integrity() {
+                if allocated == 0 {
+                    return Err(e);
+                }
+                break;
+            }
+            allocated += slab_page.allocate_many(new_layout, &mut objects[allocated..]);
+            page = slab_page.next_ptr();
+        }
+
+        #[cfg(feature = "poison")]
+        {
+            let mut intact = 0;
+            for i in 0..allocated {
+                let ptr = unsafe { objects[i].assume_init() };
+                let page: &P = unsafe { P::from_object(ptr.as_ptr() as usize) }.ok_or("Object is not in a slab page")?;
+                if page.check_poison(ptr, self.size).is_ok() {
+                    objects[intact] = objects[i];
+                    intact += 1;
+                }
+            }
+            if intact == 0 && allocated > 0 {
+                return Err(CORRUPTED);
+            }
+            allocated = intact;
+        }
+
+        self.allocation_count.fetch_add(allocated, Ordering::Relaxed);
+        Ok(allocated)
+    }
+
+    /// Deallocates a previously allocated `ptr` described by `layout`.
+    ///
+    /// This only clears the object's bit in the page; the page stays on the list.
+    pub fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str> {
+        assert!(layout.size() <= self.size);
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
+
+        let slab_page: &P = unsafe { P::from_object(ptr.as_ptr() as usize) }.ok_or("Object is not in a slab page")?;
+        slab_page.check_integrity()?;
+        let new_layout = unsafe { Layout::from_size_align_unchecked(self.size, layout.align()) };
+
+        slab_page.deallocate(ptr, new_layout)
+    }
+
+    /// Returns an empty page from the allocator if available.
+    ///
+    /// Requires exclusive access since it unlinks the page from the list.
+    /// Fails if the metadata of a page is corrupted.
+    pub fn retrieve_empty_page(&mut self) -> Result<Option<MappedPages>, &'static str> {
+        let mut prev: *mut P = ptr::null_mut();
+        let mut page = *self.head.get_mut();
+
+        while !page.is_null() {
+            let slab_page = unsafe { &*page };
+            // Don't follow the links of a corrupted page
+            slab_page.check_integrity()?;
+            let next = slab_page.next_ptr();
+
+            if slab_page.is_empty(self.obj_per_page) {
+                if prev.is_null() {
+                    *self.head.get_mut() = next;
+                } else {
+                    *unsafe { &mut *prev }.next() = Rawlink::from_raw(next);
+                    unsafe { &mut *prev }.seal();
+                }
+                // The page is unlinked, nobody else refers to it anymore.
+                let slab_page = unsafe { &mut *page };
+                *slab_page.next() = Rawlink::none();
+                *self.pages.get_mut() -= 1;
+                return Ok(Some(slab_page.retrieve_mapped_pages()));
+            }
+
+            prev = page;
+            page = next;
+        }
+
+        Ok(None)
+    }
+}
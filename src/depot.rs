@@ -0,0 +1,114 @@
+//! A depot of empty pages shared between heaps.
+
+use crate::*;
+
+/// A stash of empty pages of type `P` shared by many `ZoneAllocator`s,
+/// protected by a lock `L`.
+///
+/// When every heap has its own `ZoneAllocator` (e.g., one per CPU), a heap that
+/// runs out of memory would otherwise have to go to the page source while other
+/// heaps hold on to their empty pages. Instead, heaps push their surplus empty
+/// pages into the depot and pull pages out of it before asking the page source.
+///
+/// See `ZoneAllocator::set_depot`.
+pub struct PageDepot<'a, L: RawLock = Spinlock, P: AllocablePage = ObjectPage8k<'a>> {
+    pages: Locked<L, PageList<'a, P>>,
+    /// Maximum number of pages the depot holds.
+    capacity: usize,
+}
+
+/// Creates an instance of a depot, we do this in a macro because we
+/// re-use the code in const and non-const functions
+macro_rules! new_depot {
+    ($capacity:expr) => {
+        PageDepot {
+            pages: Locked::new(PageList::new()),
+            capacity: $capacity,
+        }
+    };
+}
+
+impl<'a, L: RawLock, P: AllocablePage> PageDepot<'a, L, P> {
+    /// Creates an empty depot that holds at most `capacity` pages.
+    #[cfg(feature = "unstable")]
+    pub const fn new(capacity: usize) -> PageDepot<'a, L, P> {
+        new_depot!(capacity)
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn new(capacity: usize) -> PageDepot<'a, L, P> {
+        new_depot!(capacity)
+    }
+
+    /// The maximum number of pages the depot holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of pages currently in the depot.
+    pub fn len(&self) -> usize {
+        self.pages.with(|pages| pages.elements)
+    }
+
+    /// Is the depot empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds an empty page to the depot.
+    ///
+    /// If the depot is full (or the metadata of `page` or the depot's
+    /// pages is corrupted) the page is handed back as the error.
+    pub fn push(&self, page: &'a mut P) -> Result<(), &'a mut P> {
+        self.pages.with(|pages| {
+            if pages.elements >= self.capacity || pages.check_insert(page).is_err() {
+                return Err(page);
+            }
+            let inserted = pages.insert_front(page);
+            debug_assert!(inserted.is_ok(), "We checked the pages before inserting");
+            Ok(())
+        })
+    }
+
+    /// Takes an empty page out of the depot and stamps it with `heap_id`.
+    ///
+    /// Fails if the metadata of the depot's pages is corrupted.
+    pub fn pull(&self, heap_id: usize) -> Result<Option<&'a mut P>, &'static str> {
+        let page = match self.pages.with(|pages| pages.pop())? {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        page.set_heap_id(heap_id);
+        Ok(Some(page))
+    }
+
+    /// Takes an empty page out of the depot and returns its MappedPages
+    /// (e.g., to give it back to the page source).
+    pub fn retrieve_page(&self) -> Result<Option<MappedPages>, &'static str> {
+        Ok(self
+            .pages
+            .with(|pages| pages.pop())?
+            .map(|page| page.retrieve_mapped_pages()))
+    }
+}
+
+/// The part of a `PageDepot` a `ZoneAllocator` uses.
+///
+/// This hides the lock type of the depot from the zone.
+pub trait Depot<'a, P: AllocablePage = ObjectPage8k<'a>>: Sync {
+    /// Adds an empty page to the depot, see `PageDepot::push`.
+    fn push(&self, page: &'a mut P) -> Result<(), &'a mut P>;
+
+    /// Takes an empty page out of the depot, see `PageDepot::pull`.
+    fn pull(&self, heap_id: usize) -> Result<Option<&'a mut P>, &'static str>;
+}
+
+impl<'a, L: RawLock + Sync, P: AllocablePage + Send> Depot<'a, P> for PageDepot<'a, L, P> {
+    fn push(&self, page: &'a mut P) -> Result<(), &'a mut P> {
+        PageDepot::push(self, page)
+    }
+
+    fn pull(&self, heap_id: usize) -> Result<Option<&'a mut P>, &'static str> {
+        PageDepot::pull(self, heap_id)
+    }
+}
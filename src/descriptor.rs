@@ -0,0 +1,283 @@
+//! An `AllocablePage` whose metadata lives outside of the page.
+//!
+//! `ObjectPage8k` keeps its metadata at the end of the page, which costs
+//! object space and lets an overflow from the last object reach the metadata.
+//! A `PageDescriptor8k` describes a page from a separate descriptor table
+//! instead, so objects can use all 8 KiB of the page (except for one tag byte per slot
+//! at the end of the page with the `tagging` feature, like in an `ObjectPage8k`).
+//!
+//! The descriptor table covers one contiguous range of virtual addresses
+//! (e.g., the kernel heap): the page at `heap_start + i * 8 KiB` is described by
+//! the `i`-th descriptor, so finding the descriptor of an object is a subtraction
+//! and a shift. The table has to be registered once, with
+//! `register_descriptor_table`, before any `PageDescriptor8k` is created.
+
+use crate::*;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Start of the virtual address range covered by the descriptor table.
+static DESCRIPTOR_HEAP_START: AtomicUsize = AtomicUsize::new(0);
+/// Address of the first descriptor (0 if no table was registered).
+static DESCRIPTOR_TABLE: AtomicUsize = AtomicUsize::new(0);
+/// Number of descriptors in the table.
+static DESCRIPTOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers `table` as the descriptor table for the pages starting at `heap_start`.
+///
+/// The table holds `table.size_in_bytes() / size_of::<PageDescriptor8k>()` descriptors,
+/// and covers as many 8 KiB pages. It is never unmapped again.
+/// Only one table can be registered.
+pub fn register_descriptor_table(heap_start: usize, table: MappedPages) -> Result<(), &'static str> {
+    if !heap_start.is_multiple_of(PageDescriptor8k::SIZE) {
+        return Err("The heap for the descriptor table is not aligned at 8k bytes");
+    }
+    if !table.flags().is_writable() {
+        return Err("The MappedPages for the descriptor table are not writable");
+    }
+
+    let count = table.size_in_bytes() / mem::size_of::<PageDescriptor8k>();
+    let addr = table.start_address().value();
+    if count == 0 || !addr.is_multiple_of(mem::align_of::<PageDescriptor8k>()) {
+        return Err("The MappedPages can't hold a descriptor table");
+    }
+
+    if DESCRIPTOR_TABLE
+        .compare_exchange(0, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err("A descriptor table was already registered");
+    }
+
+    // An all-zero descriptor is unused (it doesn't have the magic number).
+    unsafe { ptr::write_bytes(addr as *mut u8, 0, table.size_in_bytes()) };
+    mem::forget(table);
+
+    DESCRIPTOR_HEAP_START.store(heap_start, Ordering::Relaxed);
+    DESCRIPTOR_COUNT.store(count, Ordering::Relaxed);
+    DESCRIPTOR_TABLE.store(addr, Ordering::Release);
+    Ok(())
+}
+
+/// The descriptor slot of the page at `page_addr`, if it is covered by the table.
+fn descriptor_slot(page_addr: usize) -> Option<*mut PageDescriptor8k<'static>> {
+    let table = DESCRIPTOR_TABLE.load(Ordering::Acquire);
+    if table == 0 || table == usize::MAX {
+        return None;
+    }
+
+    let idx = page_addr.checked_sub(DESCRIPTOR_HEAP_START.load(Ordering::Relaxed))? / PageDescriptor8k::SIZE;
+    if idx >= DESCRIPTOR_COUNT.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some((table as *mut PageDescriptor8k).wrapping_add(idx))
+}
+
+/// Describes an 8 KiB page, all of which holds objects.
+///
+/// The descriptor itself is stored in the registered descriptor table.
+#[repr(C)]
+pub struct PageDescriptor8k<'a> {
+    /// Start address of the page.
+    data: usize,
+
+    /// Always `PAGE_MAGIC` for a descriptor that is in use.
+    magic: u32,
+    /// Checksum over the descriptor and page address, `heap_id`, `next` and `prev`.
+    checksum: u32,
+
+    pub mp: MappedPages,
+
+    /// When this page became empty (only meaningful while it is on an empty list).
+    empty_since: usize,
+
+    pub heap_id: usize,
+
+    /// Next element in list (used by `PageList`).
+    next: Rawlink<PageDescriptor8k<'a>>,
+    /// Previous element in  list (used by `PageList`)
+    prev: Rawlink<PageDescriptor8k<'a>>,
+
+    /// A bit-field to track free/allocated memory within the page
+    /// (enough bits for 8 byte objects).
+    bitfield: [AtomicU64; 16],
+}
+
+// These needs some more work to be really safe...
+unsafe impl<'a> Send for PageDescriptor8k<'a> {}
+unsafe impl<'a> Sync for PageDescriptor8k<'a> {}
+
+impl<'a> PageDescriptor8k<'a> {
+    fn compute_checksum(&self) -> u32 {
+        metadata_checksum(&[
+            self as *const PageDescriptor8k as u64,
+            self.data as u64,
+            self.heap_id as u64,
+            self.next.as_ptr() as u64,
+            self.prev.as_ptr() as u64,
+        ])
+    }
+}
+
+impl<'a> AllocablePage for PageDescriptor8k<'a> {
+    const SIZE: usize = 8192;
+    /// No metadata is stored in the page.
+    const METADATA_SIZE: usize = 0;
+    /// The heap id isn't stored in the page, see `heap_id_of`.
+    const HEAP_ID_OFFSET: Option<usize> = None;
+    const MAX_OBJECTS: usize = 16 * 64;
+    /// The tags are kept in the page behind the last slot (not in the descriptor table,
+    /// which would need room for `MAX_OBJECTS` tags per page).
+    #[cfg(feature = "tagging")]
+    const TAG_SIZE: usize = core::mem::size_of::<AllocTag>();
+
+    /// Creates a descriptor for `mp`.
+    /// This function checks that the given mapped pages is aligned at a 8KiB boundary, writable and has a size of 8KiB.
+    fn new(mp: MappedPages, heap_id: usize) -> Result<PageDescriptor8k<'a>, &'static str> {
+        check_mapped_pages(&mp, Self::SIZE)?;
+
+        Ok(PageDescriptor8k {
+            data: mp.start_address().value(),
+            magic: PAGE_MAGIC,
+            // Sealed once the descriptor is in the table
+            checksum: 0,
+            mp,
+            empty_since: 0,
+            heap_id,
+            next: Rawlink::default(),
+            prev: Rawlink::default(),
+            bitfield: Default::default(),
+        })
+    }
+
+    /// Writes the descriptor for `mp` into its slot in the descriptor table.
+    fn create<'b>(mp: MappedPages, heap_id: usize) -> Result<&'b mut Self, &'static str> {
+        let slot = descriptor_slot(mp.start_address().value()).ok_or("Page is not covered by the descriptor table")?;
+        if unsafe { (*slot).magic } == PAGE_MAGIC {
+            return Err("Page already has a descriptor");
+        }
+
+        let page = Self::new(mp, heap_id)?;
+        let page_ref: &'b mut Self = unsafe { &mut *slot.cast::<Self>() };
+        unsafe { (page_ref as *mut Self).write(page); }
+        page_ref.seal();
+
+        Ok(page_ref)
+    }
+
+    unsafe fn from_object<'b>(addr: usize) -> Option<&'b mut Self> {
+        descriptor_slot(addr & !(Self::SIZE - 1)).map(|slot| &mut *slot.cast::<Self>())
+    }
+
+    /// Reads the heap id from the descriptor of the page.
+    ///
+    /// Addresses outside of the descriptor table (or of pages without a descriptor) give `None`.
+    unsafe fn heap_id_of(addr: usize) -> Option<usize> {
+        let page = Self::from_object(addr)?;
+        page.check_integrity().ok()?;
+        Some(page.heap_id)
+    }
+
+    fn data_addr(&self) -> usize {
+        self.data
+    }
+
+    /// Returns the MappedPages object that was stored in the descriptor,
+    /// which frees the descriptor.
+    fn retrieve_mapped_pages(&mut self) -> MappedPages {
+        let mut mp = MappedPages::empty();
+        core::mem::swap(&mut self.mp, &mut mp);
+        self.magic = 0;
+        mp
+    }
+
+    /// clears the metadata of the page
+    fn clear_metadata(&mut self) {
+        self.empty_since = 0;
+        self.heap_id = 0;
+        self.next = Rawlink::default();
+        self.prev = Rawlink::default();
+        for bf in &self.bitfield {
+            bf.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    fn check_integrity(&self) -> Result<(), &'static str> {
+        if self.magic != PAGE_MAGIC {
+            error!(
+                "Descriptor {:p} (page {:#x}) has a bad magic number {:#x}",
+                self, self.data, self.magic
+            );
+            return Err("PageDescriptor8k corrupted");
+        }
+        if self.checksum != self.compute_checksum() {
+            error!(
+                "Descriptor {:p} (page {:#x}) has a bad checksum (heap id {}, next {:p}, prev {:p})",
+                self, self.data, self.heap_id, self.next.as_ptr(), self.prev.as_ptr()
+            );
+            return Err("PageDescriptor8k corrupted");
+        }
+        Ok(())
+    }
+
+    fn set_heap_id(&mut self, heap_id: usize) {
+        self.heap_id = heap_id;
+        self.seal();
+    }
+
+    fn heap_id(&self) -> usize {
+        self.heap_id
+    }
+
+    fn set_empty_since(&mut self, tick: usize) {
+        self.empty_since = tick;
+    }
+
+    fn empty_since(&self) -> usize {
+        self.empty_since
+    }
+
+    fn bitfield(&self) -> &[AtomicU64] {
+        &self.bitfield
+    }
+    fn bitfield_mut(&mut self) -> &mut [AtomicU64] {
+        &mut self.bitfield
+    }
+
+    /// The tags follow the last slot, at the end of the page.
+    #[cfg(feature = "tagging")]
+    fn tags(&self, slots: usize) -> &[AllocTag] {
+        let start = self.data + Self::SIZE - slots * Self::TAG_SIZE;
+        unsafe { core::slice::from_raw_parts(start as *const AllocTag, slots) }
+    }
+    #[cfg(feature = "tagging")]
+    fn tags_mut(&mut self, slots: usize) -> &mut [AllocTag] {
+        let start = self.data + Self::SIZE - slots * Self::TAG_SIZE;
+        unsafe { core::slice::from_raw_parts_mut(start as *mut AllocTag, slots) }
+    }
+
+    fn prev(&mut self) -> &mut Rawlink<Self> {
+        &mut self.prev
+    }
+
+    fn next(&mut self) -> &mut Rawlink<Self> {
+        &mut self.next
+    }
+
+    fn next_ptr(&self) -> *mut Self {
+        self.next.as_ptr()
+    }
+
+    fn buffer_size() -> usize {
+        PageDescriptor8k::SIZE
+    }
+}
+
+impl<'a> fmt::Debug for PageDescriptor8k<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PageDescriptor8k({:#x})", self.data)
+    }
+}
@@ -0,0 +1,56 @@
+//! Hooks that are called on allocator events, for tracing and profiling.
+//!
+//! Hooks are only called with the `hooks` feature, without it the calls
+//! are compiled out entirely.
+//!
+//! Every hook receives the address of the object (or page) it is about,
+//! the size class involved (i.e., its object size) and the heap id of the page.
+//! Hooks about one object also receive the layout it was requested with.
+
+use core::alloc::Layout;
+
+/// Which list of an `SCAllocator` a page is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageState {
+    /// The page holds no objects.
+    Empty,
+    /// Some objects are allocated in the page.
+    Partial,
+    /// All slots of the page are allocated.
+    Full,
+}
+
+/// Receives the events of a `ZoneAllocator` or `SCAllocator`.
+///
+/// All methods do nothing by default, so implementors only override the events
+/// they are interested in. Hooks are called from within the allocator,
+/// so they must not allocate from it.
+pub trait AllocatorHooks: Sync {
+    /// An object at `addr` was allocated for `layout`.
+    fn on_allocate(&self, _addr: usize, _layout: Layout, _size_class: usize, _heap_id: usize) {}
+
+    /// The object at `addr` (allocated for `layout`) was freed (with the `quarantine` feature:
+    /// it left the quarantine, i.e., its slot can be reused).
+    ///
+    /// This isn't called if freeing the object failed.
+    fn on_deallocate(&self, _addr: usize, _layout: Layout, _size_class: usize, _heap_id: usize) {}
+
+    /// A new page at `page` was added to the allocator (e.g., from the page source or a depot).
+    fn on_refill(&self, _page: usize, _size_class: usize, _heap_id: usize) {}
+
+    /// The page at `page` moved from the `from` to the `to` list.
+    fn on_page_transition(&self, _page: usize, _size_class: usize, _heap_id: usize, _from: PageState, _to: PageState) {}
+
+    /// An empty page at `page` was moved from one size class of a zone to another.
+    ///
+    /// This is reported in addition to the page's return from `from_class`
+    /// and the refill of `to_class`.
+    fn on_exchange(&self, _page: usize, _from_class: usize, _to_class: usize, _heap_id: usize) {}
+
+    /// `pages` pages of another allocator were merged (or split) into this one
+    /// (and now belong to `heap_id`).
+    fn on_merge(&self, _pages: usize, _size_class: usize, _heap_id: usize) {}
+
+    /// The page at `page` was taken out of the allocator (e.g., to be unmapped).
+    fn on_page_return(&self, _page: usize, _size_class: usize, _heap_id: usize) {}
+}
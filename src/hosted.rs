@@ -0,0 +1,111 @@
+//! A stand-in for the `memory` crate on hosted targets (for the tests and examples).
+//!
+//! Mappings are plain memory from the system allocator, aligned to their size
+//! (rounded up to a power of two), so an 8 KiB mapping can become an `ObjectPage8k`.
+//! The system allocator is used directly (not the global allocator),
+//! so a program can still use a slab allocator as its global allocator.
+
+use core::alloc::Layout;
+use std::alloc::{GlobalAlloc, System};
+
+/// A virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualAddress(usize);
+
+impl VirtualAddress {
+    pub fn value(&self) -> usize {
+        self.0
+    }
+}
+
+/// The flags of a mapping (only `WRITABLE` has a meaning here).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryFlags(u64);
+
+impl EntryFlags {
+    pub const WRITABLE: EntryFlags = EntryFlags(1 << 1);
+
+    pub fn is_writable(&self) -> bool {
+        self.0 & EntryFlags::WRITABLE.0 != 0
+    }
+}
+
+/// A range of memory that is freed when it is dropped
+/// (unless it was created with `from_raw_parts`).
+#[derive(Debug)]
+pub struct MappedPages {
+    start: usize,
+    size: usize,
+    flags: EntryFlags,
+    owned: bool,
+}
+
+impl MappedPages {
+    /// A mapping of nothing.
+    pub const fn empty() -> MappedPages {
+        MappedPages {
+            start: 0,
+            size: 0,
+            flags: EntryFlags(0),
+            owned: false,
+        }
+    }
+
+    /// Describes `size` bytes at `start` that belong to another mapping
+    /// (e.g., one page of a larger mapping). They aren't freed on drop.
+    ///
+    /// # Safety
+    /// The memory must stay valid as long as the returned object is used.
+    pub unsafe fn from_raw_parts(start: usize, size: usize, flags: EntryFlags) -> MappedPages {
+        MappedPages {
+            start,
+            size,
+            flags,
+            owned: false,
+        }
+    }
+
+    pub fn start_address(&self) -> VirtualAddress {
+        VirtualAddress(self.start)
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.size
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+}
+
+impl Drop for MappedPages {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { System.dealloc(self.start as *mut u8, mapping_layout(self.size)) };
+        }
+    }
+}
+
+/// The layout of the memory for a mapping of `size` bytes.
+fn mapping_layout(size: usize) -> Layout {
+    let align = core::cmp::max(4096, size.next_power_of_two());
+    Layout::from_size_align(size, align).expect("Mapping is too large")
+}
+
+/// Maps `size_in_bytes` bytes of zeroed memory.
+pub fn create_mapping(size_in_bytes: usize, flags: EntryFlags) -> Result<MappedPages, &'static str> {
+    if size_in_bytes == 0 {
+        return Err("Can't map zero bytes");
+    }
+    let start = unsafe { System.alloc_zeroed(mapping_layout(size_in_bytes)) };
+    if start.is_null() {
+        return Err("Out of memory");
+    }
+
+    Ok(MappedPages {
+        start: start as usize,
+        size: size_in_bytes,
+        flags,
+        owned: true,
+    })
+}
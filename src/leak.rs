@@ -0,0 +1,223 @@
+//! Tracking where live objects were allocated, to find leaks when a heap is torn down.
+//!
+//! The tracker keeps one entry per live object in a table that is provided by the
+//! caller (we can't allocate it from the allocator we are tracking). Objects that
+//! are allocated while the table is full are only counted.
+//!
+//! Objects the tracker didn't see being allocated (they were live when it was set,
+//! or came from another zone in `merge`) are counted as well, so freeing them
+//! doesn't make the untracked objects look freed. As we can't tell the two kinds
+//! apart when they are freed, `untracked` may report too many objects (but never
+//! too few) until the older objects are gone.
+//!
+//! # Cost
+//! The table is a hash table keyed by object address (with linear probing), so
+//! recording and forgetting an object takes constant time on average. It slows down
+//! as the table fills up, so it should have some room to spare. `report` walks the
+//! table once for every reported call site.
+
+use core::panic::Location;
+
+/// A live object in a `LeakTracker`.
+#[derive(Debug, Clone, Copy)]
+pub struct LeakEntry {
+    /// Address of the object.
+    addr: usize,
+    /// The size class the object was allocated from.
+    size_class: usize,
+    /// Where the object was allocated.
+    location: &'static Location<'static>,
+    /// The number of allocations the tracker saw before this one.
+    seq: u64,
+}
+
+/// Live objects that were allocated at the same call site from the same size class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeakSite {
+    /// Where the objects were allocated.
+    pub location: &'static Location<'static>,
+    /// The size class the objects were allocated from.
+    pub size_class: usize,
+    /// Number of live objects.
+    pub count: usize,
+    /// Sequence number of the oldest of these objects.
+    pub first_seq: u64,
+}
+
+/// Records the call site and a sequence number of every live object of a `ZoneAllocator`.
+pub struct LeakTracker<'a> {
+    /// A hash table of the recorded objects, see `home`.
+    entries: &'a mut [Option<LeakEntry>],
+    /// Number of recorded objects.
+    recorded: usize,
+    /// Sequence number of the next allocation.
+    next_seq: u64,
+    /// Live objects that didn't fit into `entries`.
+    untracked: usize,
+    /// Live objects that were allocated without the tracker.
+    foreign: usize,
+}
+
+impl<'a> LeakTracker<'a> {
+    /// Creates a tracker that records up to `entries.len()` live objects.
+    pub fn new(entries: &'a mut [Option<LeakEntry>]) -> LeakTracker<'a> {
+        for entry in entries.iter_mut() {
+            *entry = None;
+        }
+        LeakTracker {
+            entries,
+            recorded: 0,
+            next_seq: 0,
+            untracked: 0,
+            foreign: 0,
+        }
+    }
+
+    /// The entry an object at `addr` is looked up from first.
+    fn home(&self, addr: usize) -> usize {
+        // Fibonacci hashing: the upper half of the product depends on all bits of the address,
+        // so objects of any size class spread over the table.
+        (((addr as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize) % self.entries.len()
+    }
+
+    /// The index of the entry of the object at `addr`, if it is recorded.
+    fn find(&self, addr: usize) -> Option<usize> {
+        let len = self.entries.len();
+        if len == 0 {
+            return None;
+        }
+        let home = self.home(addr);
+        for probe in 0..len {
+            let i = (home + probe) % len;
+            match self.entries[i] {
+                Some(entry) if entry.addr == addr => return Some(i),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+        None
+    }
+
+    /// Records the object at `addr`.
+    pub(crate) fn record(&mut self, addr: usize, size_class: usize, location: &'static Location<'static>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let len = self.entries.len();
+        if self.recorded == len {
+            self.untracked += 1;
+            return;
+        }
+
+        let mut i = self.home(addr);
+        while self.entries[i].is_some() {
+            i = (i + 1) % len;
+        }
+        self.entries[i] = Some(LeakEntry {
+            addr,
+            size_class,
+            location,
+            seq,
+        });
+        self.recorded += 1;
+    }
+
+    /// Empties the entry at `hole`, moving back the entries after it that
+    /// would otherwise become unreachable from their home entry.
+    fn remove(&mut self, mut hole: usize) {
+        let len = self.entries.len();
+        self.entries[hole] = None;
+        let mut i = hole;
+        loop {
+            i = (i + 1) % len;
+            let entry = match self.entries[i] {
+                Some(entry) => entry,
+                None => break,
+            };
+            // The entry stays if its home lies cyclically in (hole, i].
+            let home = self.home(entry.addr);
+            let stays = if hole <= i {
+                hole < home && home <= i
+            } else {
+                hole < home || home <= i
+            };
+            if !stays {
+                self.entries[hole] = Some(entry);
+                self.entries[i] = None;
+                hole = i;
+            }
+        }
+        self.recorded -= 1;
+    }
+
+    /// Counts `count` live objects that were allocated without the tracker.
+    pub(crate) fn add_foreign(&mut self, count: usize) {
+        self.foreign += count;
+    }
+
+    /// Forgets the object at `addr` (it was freed).
+    pub(crate) fn forget(&mut self, addr: usize) {
+        match self.find(addr) {
+            Some(i) => self.remove(i),
+            // Either an object we didn't see being allocated or one we couldn't record,
+            // we assume the former so that no untracked object goes unreported.
+            None if self.foreign > 0 => self.foreign -= 1,
+            None => self.untracked = self.untracked.saturating_sub(1),
+        }
+    }
+
+    /// Forgets all objects (e.g., because their pages were taken away).
+    pub(crate) fn clear(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = None;
+        }
+        self.recorded = 0;
+        self.untracked = 0;
+        self.foreign = 0;
+    }
+
+    /// Number of live objects that were allocated while the table was full
+    /// (these don't show up in `report`).
+    ///
+    /// This may be too high while objects that were allocated without the tracker are live.
+    pub fn untracked(&self) -> usize {
+        self.untracked
+    }
+
+    /// Calls `f` once for every combination of call site and size class that has live objects,
+    /// ordered by call site (and then by size class).
+    pub fn report<F: FnMut(LeakSite)>(&self, mut f: F) {
+        let key = |entry: &LeakEntry| (entry.location, entry.size_class);
+        let mut last = None;
+        // Every pass reports the smallest site after the one the previous pass reported.
+        loop {
+            let mut next: Option<LeakSite> = None;
+            for entry in self.entries.iter().flatten() {
+                if last.is_some_and(|last| key(entry) <= last) {
+                    continue;
+                }
+                match next.as_mut() {
+                    Some(site) if key(entry) == (site.location, site.size_class) => {
+                        site.count += 1;
+                        site.first_seq = core::cmp::min(site.first_seq, entry.seq);
+                    }
+                    Some(site) if key(entry) > (site.location, site.size_class) => {}
+                    _ => {
+                        next = Some(LeakSite {
+                            location: entry.location,
+                            size_class: entry.size_class,
+                            count: 1,
+                            first_seq: entry.seq,
+                        })
+                    }
+                }
+            }
+            match next {
+                Some(site) => {
+                    last = Some((site.location, site.size_class));
+                    f(site);
+                }
+                None => return,
+            }
+        }
+    }
+}
@@ -8,6 +8,10 @@
 //!    satisfy requests for different allocation sizes.
 //!  * A `SCAllocator` allocates objects of exactly one size.
 //!    It stores the objects and meta-data in one or multiple `AllocablePage` objects.
+//!  * A `ConcurrentSCAllocator` also allocates objects of exactly one size, but
+//!    allocation and deallocation can happen from multiple CPUs at the same time.
+//!  * A `MagazineCache` caches free objects per CPU in front of a `ConcurrentSCAllocator`.
+//!  * An `ObjectCache<T>` hands out (optionally pre-constructed) objects of type `T`.
 //!  * A trait `AllocablePage` that defines the page-type from which we allocate objects.
 //!
 //! Lastly, it provides two default `AllocablePage` implementations `ObjectPage` and `LargeObjectPage`:
@@ -16,46 +20,105 @@
 //!
 //!
 //! # Implementing GlobalAlloc
-//! See the [global alloc](https://github.com/gz/rust-slabmalloc/tree/master/examples/global_alloc.rs) example.
+//! A `LockedZoneAllocator` wraps a `ZoneAllocator` in a lock and implements `GlobalAlloc`.
+//! It refills itself from a `PageSource`, see the
+//! [global alloc](https://github.com/gz/rust-slabmalloc/tree/master/examples/global_alloc.rs) example.
 //! 
 //! # Theseus 
 //! Some changes made for the Theseus OS heap:
 //!  * A `ObjectPage8k` that is 8 KiB in size and contains allocated objects and associated meta-data.
+//!  * A `PageDescriptor8k` for 8 KiB pages that only contain objects (the meta-data is kept in a separate descriptor table).
 //!  * return_page() function which allow the ZoneAllocator to return empty pages on request.
+//!  * checkpoint() and diff() functions which find the objects an operation allocated but didn't free.
+//!  * drain() and into_pages() functions which take all pages out of a ZoneAllocator when it is torn down.
 #![allow(unused_features)]
-#![cfg_attr(feature = "unstable", feature(const_fn))]
-#![cfg_attr(
-    test,
-    feature(
-        prelude_import,
-        test,
-        raw,
-        c_void_variant,
-        core_intrinsics,
-        vec_remove_item
-    )
-)]
+#![cfg_attr(feature = "unstable", feature(allocator_api))]
+#![cfg_attr(test, feature(test))]
 #![no_std]
 #![crate_name = "slabmalloc"]
 #![crate_type = "lib"]
 
+#[cfg(not(feature = "hosted"))]
 extern crate memory;
+#[cfg(feature = "hosted")]
+use hosted as memory;
 
+/// Calls `$method` of the `AllocatorHooks` of `$alloc`, if it has any.
+///
+/// Without the `hooks` feature this expands to nothing (the arguments aren't evaluated).
+macro_rules! hook {
+    ($alloc:expr, $method:ident($($arg:expr),*)) => {
+        #[cfg(feature = "hooks")]
+        {
+            if let Some(hooks) = $alloc.hooks {
+                hooks.$method($($arg),*);
+            }
+        }
+    };
+}
+
+#[cfg(feature = "unstable")]
+mod allocator_api;
+mod cache;
+mod checkpoint;
+mod concurrent;
+mod depot;
+mod descriptor;
+#[cfg(feature = "hooks")]
+mod hooks;
+#[cfg(feature = "hosted")]
+pub mod hosted;
+#[cfg(feature = "leak-tracking")]
+mod leak;
+mod locked;
+mod magazine;
 mod pages;
+#[cfg(feature = "quarantine")]
+mod quarantine;
+mod reclaim;
+#[cfg(feature = "hardened")]
+mod rng;
 mod sc;
+mod source;
+#[cfg(feature = "tagging")]
+mod tag;
+#[cfg(feature = "hooks")]
+mod trace;
 mod zone;
 
+pub use cache::*;
+pub use checkpoint::*;
+pub use concurrent::*;
+pub use depot::*;
+pub use descriptor::*;
+#[cfg(feature = "hooks")]
+pub use hooks::*;
+#[cfg(feature = "leak-tracking")]
+pub use leak::*;
+pub use locked::*;
+pub use magazine::*;
 pub use pages::*;
+#[cfg(feature = "quarantine")]
+pub use quarantine::*;
+pub use reclaim::*;
+#[cfg(feature = "hardened")]
+pub use rng::*;
 pub use sc::*;
+pub use source::*;
+#[cfg(feature = "tagging")]
+pub use tag::*;
+#[cfg(feature = "hooks")]
+pub use trace::*;
 pub use zone::*;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "hosted"))]
 #[macro_use]
 extern crate std;
 #[cfg(test)]
 extern crate test;
 
-#[cfg(test)]
+// The tests need pages, which only the hosted memory stand-in can provide.
+#[cfg(all(test, feature = "hosted"))]
 mod tests;
 
 use core::alloc::Layout;
@@ -66,9 +129,6 @@ use memory::MappedPages;
 
 use log::{error};
 
-#[cfg(target_arch = "x86_64")]
-const CACHE_LINE_SIZE: usize = 64;
-
 // #[cfg(target_arch = "x86_64")]
 // const BASE_PAGE_SIZE: usize = 4096;
 
@@ -76,9 +136,6 @@ const CACHE_LINE_SIZE: usize = 64;
 #[allow(unused)]
 const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
 
-#[cfg(target_arch = "x86_64")]
-type VAddr = usize;
-
 /// Error that can be returned for `allocation` and `deallocation` requests.
 #[derive(Debug)]
 pub enum AllocationError {
@@ -87,9 +144,39 @@ pub enum AllocationError {
     OutOfMemory,
     /// Allocator can't deal with the provided size of the Layout.
     InvalidLayout,
+    /// The allocator found a corrupted object (e.g., a freed object was written to)
+    /// and took it out of use. Refilling the allocator doesn't help.
+    Corrupted,
+}
+
+/// The error the allocators return for `AllocationError::OutOfMemory`.
+pub const OUT_OF_MEMORY: &str = "AllocationError::OutOfMemory";
+/// The error the allocators return for `AllocationError::InvalidLayout`.
+pub const INVALID_LAYOUT: &str = "AllocationError::InvalidLayout";
+/// The error the allocators return for `AllocationError::Corrupted`.
+pub const CORRUPTED: &str = "AllocationError::Corrupted";
+
+impl AllocationError {
+    /// The error string the allocators return for this error.
+    ///
+    /// Match errors against these constants (rather than string literals)
+    /// to tell them apart, e.g., to decide whether a refill may help.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            AllocationError::OutOfMemory => OUT_OF_MEMORY,
+            AllocationError::InvalidLayout => INVALID_LAYOUT,
+            AllocationError::Corrupted => CORRUPTED,
+        }
+    }
 }
 
+/// Allocate and free objects of different sizes from the pages an allocator was refilled with.
+///
+/// # Safety
+/// Implementations must hand out blocks that fit `layout` (size and alignment) and
+/// don't overlap with any other live block, until they are passed to `deallocate`.
 pub unsafe trait Allocator<'a> {
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
     fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, &'static str>;
     fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str>;
     // unsafe fn refill_large(
@@ -0,0 +1,263 @@
+//! A ZoneAllocator behind a lock, ready to be used as a `GlobalAlloc`.
+
+use crate::*;
+use core::alloc::GlobalAlloc;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A lock that protects an allocator.
+///
+/// This is kept minimal so any kernel lock (spin, irq-safe, ticket locks, ...)
+/// can be plugged in with a few lines of code.
+///
+/// # Safety
+/// Implementors must guarantee mutual exclusion between a `lock` and the
+/// matching `unlock`.
+pub unsafe trait RawLock {
+    /// An unlocked instance of the lock (used by the const constructors).
+    const INIT: Self;
+
+    /// Acquires the lock, blocks until it is available.
+    fn lock(&self);
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    /// Must only be called by the current holder of the lock.
+    unsafe fn unlock(&self);
+}
+
+/// A simple test-and-set spinlock, used if the client doesn't provide its own lock.
+pub struct Spinlock(AtomicBool);
+
+unsafe impl RawLock for Spinlock {
+    const INIT: Spinlock = Spinlock(AtomicBool::new(false));
+
+    fn lock(&self) {
+        while self
+            .0
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.0.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Some data `T` protected by a `RawLock`.
+pub(crate) struct Locked<L: RawLock, T> {
+    lock: L,
+    data: UnsafeCell<T>,
+}
+
+/// Releases the lock when dropped, so we also unlock if `f` panics in `Locked::with`.
+struct LockGuard<'l, L: RawLock>(&'l L);
+
+impl<'l, L: RawLock> Drop for LockGuard<'l, L> {
+    fn drop(&mut self) {
+        unsafe { self.0.unlock() };
+    }
+}
+
+unsafe impl<L: RawLock + Sync, T: Send> Sync for Locked<L, T> {}
+
+impl<L: RawLock, T> Locked<L, T> {
+    #[cfg(feature = "unstable")]
+    pub(crate) const fn new(data: T) -> Locked<L, T> {
+        Locked {
+            lock: L::INIT,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub(crate) fn new(data: T) -> Locked<L, T> {
+        Locked {
+            lock: L::INIT,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Runs `f` with exclusive access to the protected data.
+    pub(crate) fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        self.lock.lock();
+        let _guard = LockGuard(&self.lock);
+        f(unsafe { &mut *self.data.get() })
+    }
+
+    /// Exclusive access without taking the lock (we have `&mut self`).
+    #[allow(dead_code)]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+/// A `ZoneAllocator` protected by a lock `L` that refills itself from a page source.
+///
+/// It implements `GlobalAlloc`, so it can be directly used as a `#[global_allocator]`.
+/// The zone uses its page source (see `ZoneAllocator::set_page_source`) like any other zone:
+///
+///  * If the zone can't satisfy an allocation, it first looks for an empty page
+///    in its other size classes (and its depot, if set), then requests a fresh
+///    page from the page source.
+///  * After a deallocation, empty pages above `Watermarks::high_empty_pages`
+///    are handed back to the page source.
+///
+/// Requests larger than `ZoneAllocator::<P>::MAX_ALLOC_SIZE` can't be handled and
+/// return a null pointer.
+pub struct LockedZoneAllocator<'a, L: RawLock, P: AllocablePage = ObjectPage8k<'a>> {
+    zone: Locked<L, ZoneAllocator<'a, P>>,
+}
+
+impl<'a, L: RawLock, P: AllocablePage> LockedZoneAllocator<'a, L, P> {
+    /// Creates a new, empty allocator that gets its memory from `source`.
+    ///
+    /// All pages added to the zone are stamped with `heap_id`.
+    #[cfg(feature = "unstable")]
+    pub const fn new(
+        source: &'a dyn PageSource,
+        heap_id: usize,
+        watermarks: Watermarks,
+    ) -> LockedZoneAllocator<'a, L, P> {
+        LockedZoneAllocator {
+            zone: Locked::new(ZoneAllocator::with_page_source(source, heap_id, watermarks)),
+        }
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn new(
+        source: &'a dyn PageSource,
+        heap_id: usize,
+        watermarks: Watermarks,
+    ) -> LockedZoneAllocator<'a, L, P> {
+        LockedZoneAllocator {
+            zone: Locked::new(ZoneAllocator::with_page_source(source, heap_id, watermarks)),
+        }
+    }
+
+    /// Runs `f` with exclusive access to the underlying `ZoneAllocator`.
+    pub fn with_zone<R, F: FnOnce(&mut ZoneAllocator<'a, P>) -> R>(&self, f: F) -> R {
+        self.zone.with(f)
+    }
+
+    /// Allocates a block of memory described by `layout`,
+    /// refilling the zone from the page source if necessary.
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, &'static str> {
+        let caller = call_site();
+        self.zone.with(|zone| zone.allocate_from(layout, caller))
+    }
+
+    /// Allocates a block of memory described by `layout` (like `allocate`)
+    /// and records `location` as its call site, see `ZoneAllocator::allocate_at`.
+    #[cfg(feature = "leak-tracking")]
+    pub fn allocate_at(
+        &self,
+        layout: Layout,
+        location: &'static core::panic::Location<'static>,
+    ) -> Result<NonNull<u8>, &'static str> {
+        self.zone.with(|zone| zone.allocate_from(layout, location))
+    }
+
+    /// Resizes the block at `ptr` from `old_layout` to `new_layout`.
+    ///
+    /// The block stays where it is if both layouts are served by the same size class,
+    /// otherwise its contents are moved to a new block (the zone is refilled if needed)
+    /// and the old block is freed. If the old block can't be freed (e.g., it is corrupted),
+    /// the new block is given back and the old one stays allocated.
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    pub fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<u8>, &'static str> {
+        if ZoneAllocator::<P>::fits_in_place(ptr, old_layout, new_layout) {
+            return Ok(ptr);
+        }
+
+        let caller = call_site();
+        self.zone.with(|zone| {
+            let new_ptr = zone.allocate_from(new_layout, caller)?;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    ptr.as_ptr(),
+                    new_ptr.as_ptr(),
+                    core::cmp::min(old_layout.size(), new_layout.size()),
+                );
+            }
+            match zone.deallocate(ptr, old_layout) {
+                Ok(()) => Ok(new_ptr),
+                Err(e) => {
+                    // The new block is unused, so freeing it can't fail.
+                    let _ = zone.deallocate(new_ptr, new_layout);
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Deallocates a block of memory previously returned by `allocate`.
+    pub fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str> {
+        self.zone.with(|zone| zone.deallocate(ptr, layout))
+    }
+}
+
+/// With `leak-tracking`, `alloc` and `realloc` record their caller. Note that this
+/// only works if they are called directly: behind `#[global_allocator]` they are called
+/// from the `__rust_alloc` shims, so every object is recorded at the same location in
+/// the standard library. A wrapper that knows the real call site can pass it to
+/// `allocate_at` instead.
+///
+/// None of the methods may panic: if a block can't be freed (e.g., its red zone
+/// or poison pattern is corrupted), the error is logged and the block is leaked.
+unsafe impl<'a, L: RawLock, P: AllocablePage> GlobalAlloc for LockedZoneAllocator<'a, L, P> {
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() > ZoneAllocator::<P>::MAX_ALLOC_SIZE {
+            return ptr::null_mut();
+        }
+
+        match self.allocate(layout) {
+            Ok(nptr) => nptr.as_ptr(),
+            Err(_e) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.size() > ZoneAllocator::<P>::MAX_ALLOC_SIZE {
+            // We never handed out such a pointer.
+            return;
+        }
+
+        if let Some(nptr) = NonNull::new(ptr) {
+            if let Err(e) = self.deallocate(nptr, layout) {
+                error!("Couldn't deallocate {:p} ({:?}): {}, leaking it", ptr, layout, e);
+            }
+        } else {
+            // Nothing to do (don't dealloc null pointers).
+        }
+    }
+
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > ZoneAllocator::<P>::MAX_ALLOC_SIZE {
+            return ptr::null_mut();
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match NonNull::new(ptr) {
+            Some(nptr) => match self.reallocate(nptr, layout, new_layout) {
+                Ok(new_ptr) => new_ptr.as_ptr(),
+                Err(_e) => ptr::null_mut(),
+            },
+            None => self.alloc(new_layout),
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! A magazine layer that caches free objects per CPU in front of a `ConcurrentSCAllocator`.
+//!
+//! This follows the design of Bonwick's magazines (see "Magazines and Vmem:
+//! Extending the Slab Allocator to Many CPUs and Arbitrary Resources"):
+//!
+//!  * Every CPU has a `CpuMagazines` per size class: a `loaded` and a `previous`
+//!    magazine, each a small stack of free objects. Allocating and freeing
+//!    usually just pops or pushes an object on the loaded magazine.
+//!    When a `CpuMagazines` is dropped its objects go back to the allocator.
+//!  * A `MagazineCache` (shared between CPUs) holds a depot with a stack of full
+//!    and a stack of empty magazines. If both magazines of a CPU are empty, it
+//!    exchanges one for a full magazine from the depot; if both are full, it
+//!    exchanges one for an empty magazine.
+//!  * Only if the depot can't help, objects are allocated from (or freed to)
+//!    the underlying `ConcurrentSCAllocator` in batches.
+//!
+//! The depot and the underlying allocator are protected by the lock of the cache,
+//! which is only taken if the magazines of a CPU can't serve a request.
+
+use crate::*;
+use core::mem::MaybeUninit;
+
+/// A small stack of free objects.
+pub struct Magazine {
+    rounds: [*mut u8; Magazine::CAPACITY],
+    count: usize,
+}
+
+// The objects in a magazine are free memory, they can be handed to any CPU.
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    /// The number of objects a magazine holds.
+    pub const CAPACITY: usize = 32;
+
+    #[cfg(feature = "unstable")]
+    const fn empty() -> Magazine {
+        Magazine {
+            rounds: [ptr::null_mut(); Magazine::CAPACITY],
+            count: 0,
+        }
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    fn empty() -> Magazine {
+        Magazine {
+            rounds: [ptr::null_mut(); Magazine::CAPACITY],
+            count: 0,
+        }
+    }
+
+    /// Number of objects in the magazine.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count == Magazine::CAPACITY
+    }
+
+    fn push(&mut self, ptr: NonNull<u8>) {
+        debug_assert!(!self.is_full());
+        self.rounds[self.count] = ptr.as_ptr();
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        NonNull::new(self.rounds[self.count])
+    }
+}
+
+/// The magazines of one CPU for one size class of the `MagazineCache` `cache`.
+///
+/// These are owned by the CPU (e.g., in its per-CPU data) and handed to the
+/// `MagazineCache` on every operation, so the fast path doesn't need a lock.
+/// Dropping them gives the cached objects back to the underlying allocator.
+pub struct CpuMagazines<'c, 'a, L: RawLock = Spinlock, P: AllocablePage = ObjectPage8k<'a>> {
+    cache: &'c MagazineCache<'a, L, P>,
+    loaded: Magazine,
+    previous: Magazine,
+}
+
+impl<'c, 'a, L: RawLock, P: AllocablePage> CpuMagazines<'c, 'a, L, P> {
+    /// Creates empty magazines for a CPU that uses `cache`.
+    #[cfg(feature = "unstable")]
+    pub const fn new(cache: &'c MagazineCache<'a, L, P>) -> CpuMagazines<'c, 'a, L, P> {
+        CpuMagazines {
+            cache,
+            loaded: Magazine::empty(),
+            previous: Magazine::empty(),
+        }
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn new(cache: &'c MagazineCache<'a, L, P>) -> CpuMagazines<'c, 'a, L, P> {
+        CpuMagazines {
+            cache,
+            loaded: Magazine::empty(),
+            previous: Magazine::empty(),
+        }
+    }
+
+    /// The number of free objects cached by this CPU.
+    pub fn len(&self) -> usize {
+        self.loaded.len() + self.previous.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'c, 'a, L: RawLock, P: AllocablePage> Drop for CpuMagazines<'c, 'a, L, P> {
+    fn drop(&mut self) {
+        // Objects that can't be freed (e.g., because they are corrupted) are leaked.
+        let cache = self.cache;
+        let _ = cache.flush_cpu(self);
+    }
+}
+
+/// How many objects we allocate from (or free to) the underlying allocator at once.
+const BATCH: usize = Magazine::CAPACITY / 2;
+
+/// The depot of a `MagazineCache` and the allocator behind it.
+///
+/// The depot holds `DEPOT_CAPACITY` magazines on two stacks that share one array:
+/// the full magazines from the start up to `full`, the empty ones from the end down
+/// to `full` (so the top of both stacks is next to `full`). A CPU always exchanges
+/// one of its magazines for the top of a stack, so magazines are never copied or lost.
+struct MagazineDepot<'a, P: AllocablePage> {
+    magazines: [Magazine; <MagazineCache>::DEPOT_CAPACITY],
+    /// The number of full magazines.
+    full: usize,
+    allocator: ConcurrentSCAllocator<'a, P>,
+}
+
+impl<'a, P: AllocablePage> MagazineDepot<'a, P> {
+    /// Exchanges the full `magazine` for an empty one from the depot.
+    ///
+    /// Returns `false` if there is no empty magazine left (`magazine` is left untouched).
+    fn exchange_full(&mut self, magazine: &mut Magazine) -> bool {
+        debug_assert!(magazine.is_full());
+        if self.full == <MagazineCache>::DEPOT_CAPACITY {
+            return false;
+        }
+        core::mem::swap(&mut self.magazines[self.full], magazine);
+        self.full += 1;
+        true
+    }
+
+    /// Exchanges the empty `magazine` for a full one from the depot.
+    ///
+    /// Returns `false` if there is no full magazine left (`magazine` is left untouched).
+    fn exchange_empty(&mut self, magazine: &mut Magazine) -> bool {
+        debug_assert!(magazine.is_empty());
+        if self.full == 0 {
+            return false;
+        }
+        self.full -= 1;
+        core::mem::swap(&mut self.magazines[self.full], magazine);
+        true
+    }
+
+    /// Gives the objects of all full magazines back to the allocator,
+    /// the magazines move to the empty stack.
+    fn flush(&mut self, layout: Layout) -> Result<(), &'static str> {
+        let mut res = Ok(());
+        for magazine in self.magazines[..self.full].iter_mut() {
+            res = res.and(free_magazine(&self.allocator, magazine, layout));
+        }
+        self.full = 0;
+        res
+    }
+}
+
+/// Gives all objects of `magazine` back to `allocator`.
+///
+/// Objects that can't be freed (e.g., because they are corrupted) are leaked,
+/// the first error is returned once all other objects are freed.
+fn free_magazine<P: AllocablePage>(
+    allocator: &ConcurrentSCAllocator<'_, P>,
+    magazine: &mut Magazine,
+    layout: Layout,
+) -> Result<(), &'static str> {
+    let mut res = Ok(());
+    while let Some(obj) = magazine.pop() {
+        res = res.and(allocator.deallocate(obj, layout));
+    }
+    res
+}
+
+/// Creates an instance of a magazine cache, we do this in a macro because we
+/// re-use the code in const and non-const functions
+macro_rules! new_magazine_cache {
+    ($layout:expr) => {
+        MagazineCache {
+            layout: $layout,
+            depot: Locked::new(MagazineDepot {
+                magazines: [
+                    Magazine::empty(), Magazine::empty(), Magazine::empty(), Magazine::empty(),
+                    Magazine::empty(), Magazine::empty(), Magazine::empty(), Magazine::empty(),
+                    Magazine::empty(), Magazine::empty(), Magazine::empty(), Magazine::empty(),
+                    Magazine::empty(), Magazine::empty(), Magazine::empty(), Magazine::empty(),
+                ],
+                full: 0,
+                allocator: ConcurrentSCAllocator::new($layout.size()),
+            }),
+        }
+    };
+}
+
+/// Caches free objects of one size class for many CPUs.
+///
+/// The cache allocates objects of a fixed `layout`. It owns the underlying
+/// `ConcurrentSCAllocator`, which has to be refilled through `refill`.
+/// Its depot and the allocator are protected by a lock `L`, so all operations
+/// (including flushing and reclaiming pages) work while CPUs use the cache.
+pub struct MagazineCache<'a, L: RawLock = Spinlock, P: AllocablePage = ObjectPage8k<'a>> {
+    layout: Layout,
+    depot: Locked<L, MagazineDepot<'a, P>>,
+}
+
+impl<'a, L: RawLock, P: AllocablePage> MagazineCache<'a, L, P> {
+    /// The number of magazines the depot holds (full and empty ones).
+    pub const DEPOT_CAPACITY: usize = 16;
+
+    /// Creates a cache for objects described by `layout`.
+    #[cfg(feature = "unstable")]
+    pub const fn new(layout: Layout) -> MagazineCache<'a, L, P> {
+        new_magazine_cache!(layout)
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn new(layout: Layout) -> MagazineCache<'a, L, P> {
+        new_magazine_cache!(layout)
+    }
+
+    /// The layout of the objects in this cache.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Runs `f` with exclusive access to the underlying allocator.
+    pub fn with_allocator<R, F: FnOnce(&mut ConcurrentSCAllocator<'a, P>) -> R>(&self, f: F) -> R {
+        self.depot.with(|depot| f(&mut depot.allocator))
+    }
+
+    /// The number of full magazines in the depot.
+    pub fn full_magazines(&self) -> usize {
+        self.depot.with(|depot| depot.full)
+    }
+
+    /// Sets the RNG the underlying allocator uses to pick slots.
+    #[cfg(feature = "hardened")]
+    pub fn set_rng(&mut self, rng: &'a dyn SlotRng) {
+        self.depot.get_mut().allocator.set_rng(rng);
+    }
+
+    /// Refill the underlying allocator with a new page.
+    pub fn refill(&self, mp: MappedPages, heap_id: usize) -> Result<(), &'static str> {
+        self.depot.with(|depot| depot.allocator.refill(mp, heap_id))
+    }
+
+    /// Allocates an object, using the magazines of the current CPU `cpu` if possible.
+    pub fn allocate(&self, cpu: &mut CpuMagazines<'_, 'a, L, P>) -> Result<NonNull<u8>, &'static str> {
+        debug_assert!(ptr::eq(cpu.cache, self), "The magazines belong to another cache");
+        if let Some(ptr) = cpu.loaded.pop() {
+            return Ok(ptr);
+        }
+
+        if !cpu.previous.is_empty() {
+            core::mem::swap(&mut cpu.loaded, &mut cpu.previous);
+            return cpu.loaded.pop().ok_or(OUT_OF_MEMORY);
+        }
+
+        self.depot.with(|depot| {
+            // Both magazines are empty, exchange one for a full one from the depot.
+            if depot.exchange_empty(&mut cpu.previous) {
+                core::mem::swap(&mut cpu.loaded, &mut cpu.previous);
+                return cpu.loaded.pop().ok_or(OUT_OF_MEMORY);
+            }
+
+            // The depot has no full magazine, get a batch of objects from the allocator.
+            let mut objects = [MaybeUninit::uninit(); BATCH];
+            let allocated = depot.allocator.allocate_bulk(self.layout, &mut objects)?;
+            if allocated == 0 {
+                return Err(OUT_OF_MEMORY);
+            }
+            for obj in &objects[1..allocated] {
+                cpu.loaded.push(unsafe { obj.assume_init() });
+            }
+            Ok(unsafe { objects[0].assume_init() })
+        })
+    }
+
+    /// Frees an object, caching it in the magazines of the current CPU `cpu` if possible.
+    ///
+    /// The object is always cached: if objects have to go back to the underlying
+    /// allocator and one of them can't be freed (e.g., because it is corrupted),
+    /// it is leaked and the error is returned after `ptr` was cached.
+    pub fn deallocate(&self, cpu: &mut CpuMagazines<'_, 'a, L, P>, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        debug_assert!(ptr::eq(cpu.cache, self), "The magazines belong to another cache");
+        if !cpu.loaded.is_full() {
+            cpu.loaded.push(ptr);
+            return Ok(());
+        }
+
+        if !cpu.previous.is_full() {
+            core::mem::swap(&mut cpu.loaded, &mut cpu.previous);
+            cpu.loaded.push(ptr);
+            return Ok(());
+        }
+
+        self.depot.with(|depot| {
+            // Both magazines are full, exchange one for an empty one from the depot.
+            if depot.exchange_full(&mut cpu.previous) {
+                core::mem::swap(&mut cpu.loaded, &mut cpu.previous);
+                cpu.loaded.push(ptr);
+                return Ok(());
+            }
+
+            // The depot has no empty magazine, give a batch of objects back to the allocator.
+            let mut batch = Magazine::empty();
+            for _ in 0..BATCH {
+                if let Some(obj) = cpu.loaded.pop() {
+                    batch.push(obj);
+                }
+            }
+            cpu.loaded.push(ptr);
+            free_magazine(&depot.allocator, &mut batch, self.layout)
+        })
+    }
+
+    /// Gives all objects of the magazines of `cpu` back to the underlying allocator.
+    ///
+    /// Objects that can't be freed are leaked, the first error is returned.
+    pub fn flush_cpu(&self, cpu: &mut CpuMagazines<'_, 'a, L, P>) -> Result<(), &'static str> {
+        self.depot.with(|depot| {
+            let loaded = free_magazine(&depot.allocator, &mut cpu.loaded, self.layout);
+            let previous = free_magazine(&depot.allocator, &mut cpu.previous, self.layout);
+            loaded.and(previous)
+        })
+    }
+
+    /// Gives all objects of the magazines in the depot back to the underlying allocator.
+    ///
+    /// Objects that can't be freed are leaked, the first error is returned.
+    pub fn flush_depot(&self) -> Result<(), &'static str> {
+        self.depot.with(|depot| depot.flush(self.layout))
+    }
+
+    /// Flushes the depot and returns an empty page of the underlying allocator, if any.
+    ///
+    /// Objects cached by the CPUs keep their pages in use, flush (or drop)
+    /// their `CpuMagazines` first to reclaim as much as possible.
+    ///
+    /// Fails if an object in the depot or the metadata of a page is corrupted.
+    pub fn retrieve_empty_page(&self) -> Result<Option<MappedPages>, &'static str> {
+        self.depot.with(|depot| {
+            depot.flush(self.layout)?;
+            depot.allocator.retrieve_empty_page()
+        })
+    }
+}
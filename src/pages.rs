@@ -1,6 +1,82 @@
 use crate::*;
+use core::mem::MaybeUninit;
 use core::sync::atomic::{AtomicU64, Ordering};
 
+/// Minimum number of guard bytes reserved after every object in a page.
+///
+/// Objects start at the beginning of their slot and the red zone fills the
+/// rest of it, see `slot_size`.
+#[cfg(feature = "red-zones")]
+pub const RED_ZONE_SIZE: usize = 16;
+/// Minimum number of guard bytes reserved after every object in a page.
+#[cfg(not(feature = "red-zones"))]
+pub const RED_ZONE_SIZE: usize = 0;
+
+/// The pattern red zones are filled with.
+#[cfg(feature = "red-zones")]
+pub const RED_ZONE_PATTERN: u8 = 0xbb;
+
+/// The pattern freed objects are filled with.
+#[cfg(feature = "poison")]
+pub const POISON_PATTERN: u8 = 0x6b;
+
+/// Marks the metadata of a page that is in use.
+pub(crate) const PAGE_MAGIC: u32 = 0x51ab_9a6e;
+
+/// A checksum over the `words` of page metadata (FNV-1a over the words).
+pub(crate) fn metadata_checksum(words: &[u64]) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for word in words.iter() {
+        hash = (hash ^ word).wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash ^ (hash >> 32)) as u32
+}
+
+/// Checks that `mp` can be turned into an allocable page of `size` bytes:
+/// it must be aligned to `size`, writable and exactly `size` bytes long.
+pub(crate) fn check_mapped_pages(mp: &MappedPages, size: usize) -> Result<(), &'static str> {
+    let vaddr = mp.start_address().value();
+
+    if !vaddr.is_multiple_of(size) {
+        error!("The mapped pages for the heap are not aligned at {} bytes", size);
+        return Err("The mapped pages for the heap are not aligned to the page size");
+    }
+
+    // check that the mapped pages is writable
+    if !mp.flags().is_writable() {
+        error!("Tried to convert to an allocable page but MappedPages weren't writable (flags: {:?})",  mp.flags());
+        return Err("Trying to create an allocable page but MappedPages were not writable");
+    }
+
+    // check that the mapped pages size is equal in size to the page
+    if size != mp.size_in_bytes() {
+        error!("MappedPages of size {} cannot be converted to an allocable page", mp.size_in_bytes());
+        return Err("MappedPages size does not equal allocable page size");
+    }
+
+    Ok(())
+}
+
+/// The size of a slot (an object of `size` bytes followed by its red zone) within a page.
+///
+/// Slots are rounded up to the largest power of two that divides `size`, so the objects
+/// of the power-of-two size classes stay aligned to their size with red zones (which
+/// doubles their slots). Slots larger than half a page are not rounded up: a page only
+/// holds one of them, at its (aligned) start.
+///
+/// With red zones the 2048 and 4096 byte classes therefore only fit one object into
+/// an `ObjectPage8k` (the 4096 byte slot of a 2048 byte object leaves no room for a
+/// second one next to the page metadata).
+pub(crate) const fn slot_size(size: usize) -> usize {
+    let align = size & size.wrapping_neg();
+    let rounded = (size + RED_ZONE_SIZE + align - 1) & !(align - 1);
+    if rounded > ObjectPage8k::SIZE / 2 {
+        size + RED_ZONE_SIZE
+    } else {
+        rounded
+    }
+}
+
 /// A trait defining bitfield operations we need for tracking allocated objects within a page.
 pub(crate) trait Bitfield {
     fn initialize(&mut self, for_size: usize, capacity: usize);
@@ -11,11 +87,37 @@ pub(crate) trait Bitfield {
         page_size: usize,
         metadata_size: usize,
     ) -> Option<(usize, usize)>;
+    fn claim_first_fit(
+        &self,
+        base_addr: usize,
+        layout: Layout,
+        page_size: usize,
+        metadata_size: usize,
+    ) -> Option<(usize, usize)>;
+    #[cfg(feature = "hardened")]
+    fn claim_random_fit(
+        &self,
+        base_addr: usize,
+        layout: Layout,
+        page_size: usize,
+        metadata_size: usize,
+        random: u64,
+    ) -> Option<(usize, usize)>;
+    fn claim_many(
+        &self,
+        base_addr: usize,
+        layout: Layout,
+        page_size: usize,
+        metadata_size: usize,
+        objects: &mut [MaybeUninit<NonNull<u8>>],
+    ) -> usize;
     fn is_allocated(&self, idx: usize) -> bool;
+    #[allow(dead_code)]
     fn set_bit(&self, idx: usize);
     fn clear_bit(&self, idx: usize);
     fn is_full(&self) -> bool;
     fn all_free(&self, relevant_bits: usize) -> bool;
+    fn free_count(&self) -> usize;
 }
 
 /// Implementation of bit operations on u64 slices.
@@ -34,7 +136,7 @@ impl Bitfield for [AtomicU64] {
     fn initialize(&mut self, for_size: usize, capacity: usize) {
         // Set everything to allocated
         for bitmap in self.iter_mut() {
-            *bitmap = AtomicU64::new(u64::max_value());
+            *bitmap = AtomicU64::new(u64::MAX);
         }
 
         // Mark actual slots as free
@@ -58,7 +160,7 @@ impl Bitfield for [AtomicU64] {
     ) -> Option<(usize, usize)> {
         for (base_idx, b) in self.iter().enumerate() {
             let bitval = b.load(Ordering::Relaxed);
-            if bitval == u64::max_value() {
+            if bitval == u64::MAX {
                 continue;
             } else {
                 let negated = !bitval;
@@ -73,7 +175,7 @@ impl Bitfield for [AtomicU64] {
                 }
 
                 let addr: usize = base_addr + offset;
-                let alignment_ok = addr % layout.align() == 0;
+                let alignment_ok = addr.is_multiple_of(layout.align());
                 let block_is_free = bitval & (1 << first_free) == 0;
                 if alignment_ok && block_is_free {
                     return Some((idx, addr));
@@ -83,6 +185,198 @@ impl Bitfield for [AtomicU64] {
         None
     }
 
+    /// Tries to find and claim a free block of memory that satisfies `layout`.
+    ///
+    /// Unlike `first_fit` followed by `set_bit`, this is safe to call from many
+    /// threads at once: a block is only handed out if the compare-and-swap on its
+    /// bitmap word succeeds. If another thread modified the word in the meantime
+    /// we retry with the updated value.
+    ///
+    /// Returns the index and address of the claimed block.
+    #[inline(always)]
+    fn claim_first_fit(
+        &self,
+        base_addr: usize,
+        layout: Layout,
+        page_size: usize,
+        metadata_size: usize,
+    ) -> Option<(usize, usize)> {
+        for (base_idx, b) in self.iter().enumerate() {
+            let mut bitval = b.load(Ordering::Relaxed);
+            'retry: while bitval != u64::MAX {
+                let mut free = !bitval;
+                while free != 0 {
+                    let first_free = free.trailing_zeros() as usize;
+                    let idx: usize = base_idx * 64 + first_free;
+                    let offset = idx * layout.size();
+
+                    let offset_inside_data_area = offset <= (page_size - metadata_size - layout.size());
+                    if !offset_inside_data_area {
+                        return None;
+                    }
+
+                    let addr: usize = base_addr + offset;
+                    if addr.is_multiple_of(layout.align()) {
+                        match b.compare_exchange_weak(
+                            bitval,
+                            bitval | (1 << first_free),
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => return Some((idx, addr)),
+                            Err(current) => {
+                                bitval = current;
+                                continue 'retry;
+                            }
+                        }
+                    }
+
+                    // Clear the lowest free bit and look at the next one.
+                    free &= free - 1;
+                }
+                break;
+            }
+        }
+        None
+    }
+
+    /// Like `claim_first_fit`, but the search starts at a random block
+    /// (picked with `random`) instead of the lowest one.
+    ///
+    /// We look at the blocks after the start block first and then wrap around,
+    /// so any free block that satisfies `layout` is found.
+    #[cfg(feature = "hardened")]
+    fn claim_random_fit(
+        &self,
+        base_addr: usize,
+        layout: Layout,
+        page_size: usize,
+        metadata_size: usize,
+        random: u64,
+    ) -> Option<(usize, usize)> {
+        let words = self.len();
+        let blocks = core::cmp::min((page_size - metadata_size) / layout.size(), words * 64);
+        if blocks == 0 {
+            return None;
+        }
+        let start = (random % blocks as u64) as usize;
+        let (start_word, start_bit) = (start / 64, start % 64);
+
+        // The start word is visited twice: first its bits from `start_bit` up,
+        // after wrapping around the bits below `start_bit`.
+        for i in 0..=words {
+            let base_idx = (start_word + i) % words;
+            let b = &self[base_idx];
+            let candidates = if i == 0 {
+                u64::MAX << start_bit
+            } else if i == words {
+                !(u64::MAX << start_bit)
+            } else {
+                u64::MAX
+            };
+
+            let mut bitval = b.load(Ordering::Relaxed);
+            'retry: loop {
+                let mut free = !bitval & candidates;
+                while free != 0 {
+                    let bit = free.trailing_zeros() as usize;
+                    free &= free - 1;
+
+                    let idx = base_idx * 64 + bit;
+                    let offset = idx * layout.size();
+                    let offset_inside_data_area = offset <= (page_size - metadata_size - layout.size());
+                    if !offset_inside_data_area {
+                        break;
+                    }
+
+                    let addr = base_addr + offset;
+                    if addr.is_multiple_of(layout.align()) {
+                        match b.compare_exchange_weak(
+                            bitval,
+                            bitval | (1 << bit),
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => return Some((idx, addr)),
+                            Err(current) => {
+                                bitval = current;
+                                continue 'retry;
+                            }
+                        }
+                    }
+                }
+                break;
+            }
+        }
+        None
+    }
+
+    /// Claims as many free blocks that satisfy `layout` as fit into `objects`,
+    /// and writes their addresses to `objects`.
+    ///
+    /// All blocks we want from one bitmap word are claimed with a single
+    /// compare-and-swap (retried if the word changed in the meantime).
+    ///
+    /// Returns the number of blocks that were claimed.
+    fn claim_many(
+        &self,
+        base_addr: usize,
+        layout: Layout,
+        page_size: usize,
+        metadata_size: usize,
+        objects: &mut [MaybeUninit<NonNull<u8>>],
+    ) -> usize {
+        let mut claimed = 0;
+
+        for (base_idx, b) in self.iter().enumerate() {
+            if claimed == objects.len() {
+                break;
+            }
+
+            let mut bitval = b.load(Ordering::Relaxed);
+            loop {
+                // Collect the free and suitably aligned blocks we want from this word.
+                let mut mask: u64 = 0;
+                let mut wanted = objects.len() - claimed;
+                let mut free = !bitval;
+                while free != 0 && wanted > 0 {
+                    let bit = free.trailing_zeros() as usize;
+                    free &= free - 1;
+
+                    let offset = (base_idx * 64 + bit) * layout.size();
+                    let offset_inside_data_area = offset <= (page_size - metadata_size - layout.size());
+                    if !offset_inside_data_area {
+                        break;
+                    }
+                    if (base_addr + offset).is_multiple_of(layout.align()) {
+                        mask |= 1 << bit;
+                        wanted -= 1;
+                    }
+                }
+
+                if mask == 0 {
+                    break;
+                }
+
+                match b.compare_exchange_weak(bitval, bitval | mask, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => {
+                        while mask != 0 {
+                            let bit = mask.trailing_zeros() as usize;
+                            mask &= mask - 1;
+                            let addr = base_addr + (base_idx * 64 + bit) * layout.size();
+                            objects[claimed] = MaybeUninit::new(unsafe { NonNull::new_unchecked(addr as *mut u8) });
+                            claimed += 1;
+                        }
+                        break;
+                    }
+                    Err(current) => bitval = current,
+                }
+            }
+        }
+
+        claimed
+    }
+
     /// Check if the bit `idx` is set.
     #[inline(always)]
     fn is_allocated(&self, idx: usize) -> bool {
@@ -100,11 +394,14 @@ impl Bitfield for [AtomicU64] {
     }
 
     /// Clears bit number `idx` in the bit-field.
+    ///
+    /// Uses release ordering so that all writes to the freed object are
+    /// visible to whoever claims the block next.
     #[inline(always)]
     fn clear_bit(&self, idx: usize) {
         let base_idx = idx / 64;
         let bit_idx = idx % 64;
-        self[base_idx].fetch_and(!(1 << bit_idx), Ordering::Relaxed);
+        self[base_idx].fetch_and(!(1 << bit_idx), Ordering::Release);
     }
 
     /// Checks if we could allocate more objects of a given `alloc_size` within the
@@ -118,7 +415,7 @@ impl Bitfield for [AtomicU64] {
     #[inline(always)]
     fn is_full(&self) -> bool {
         self.iter()
-            .filter(|&x| x.load(Ordering::Relaxed) != u64::max_value())
+            .filter(|&x| x.load(Ordering::Relaxed) != u64::MAX)
             .count()
             == 0
     }
@@ -148,6 +445,17 @@ impl Bitfield for [AtomicU64] {
 
         true
     }
+
+    /// Counts the free slots in the bitfield.
+    ///
+    /// Bits beyond the relevant ones are marked allocated by `initialize`,
+    /// so we can just count all zeroes.
+    #[inline(always)]
+    fn free_count(&self) -> usize {
+        self.iter()
+            .map(|bitmap| bitmap.load(Ordering::Relaxed).count_zeros() as usize)
+            .sum()
+    }
 }
 
 /// This trait is used to define a page from which objects are allocated
@@ -166,44 +474,147 @@ pub trait AllocablePage {
 
     const METADATA_SIZE: usize;
 
-    const HEAP_ID_OFFSET: usize;
+    /// Offset of the heap id from the start of the page, if the page stores it
+    /// (`None` for pages whose metadata lives elsewhere).
+    ///
+    /// Use `heap_id_of` to find the heap id of an object for any page type.
+    const HEAP_ID_OFFSET: Option<usize>;
+
+    /// The maximum number of objects in a page (bits in the bitfield).
+    const MAX_OBJECTS: usize = 8 * 64;
+
+    /// Bytes every slot needs at the end of the data area for its tag
+    /// (0 if the tags are kept outside of the page).
+    const TAG_SIZE: usize = 0;
 
     fn new(mp: MappedPages, heap_id: usize) -> Result<Self, &'static str>
     where
         Self: core::marker::Sized;
+
+    /// Creates the page for `mp` and places it where it belongs.
+    ///
+    /// By default the page (metadata included) is written to the start of `mp`.
+    fn create<'b>(mp: MappedPages, heap_id: usize) -> Result<&'b mut Self, &'static str>
+    where
+        Self: core::marker::Sized,
+    {
+        let vaddr = mp.start_address().value();
+
+        // create page and store the MappedPages object
+        let page = Self::new(mp, heap_id)?;
+        let page_ref: &'b mut Self = unsafe { &mut *(vaddr as *mut Self) };
+        unsafe { (page_ref as *mut Self).write(page); }
+        page_ref.seal();
+
+        Ok(page_ref)
+    }
+
+    /// Finds the page that holds the object at `addr`.
+    ///
+    /// By default the page starts at the `SIZE`-aligned address below `addr`.
+    ///
+    /// # Safety
+    /// The object must have been allocated from a page of this type.
+    unsafe fn from_object<'b>(addr: usize) -> Option<&'b mut Self>
+    where
+        Self: core::marker::Sized,
+    {
+        Some(&mut *((addr & !(Self::SIZE - 1)) as *mut Self))
+    }
+
+    /// The heap id of the page that holds the object at `addr`,
+    /// or `None` if there is no such page or its metadata is corrupted.
+    ///
+    /// # Safety
+    /// The object must have been allocated from a page of this type.
+    unsafe fn heap_id_of(addr: usize) -> Option<usize>;
+
+    /// The start address of the memory objects are allocated from.
+    fn data_addr(&self) -> usize {
+        (self as *const Self as *const u8) as usize
+    }
     fn retrieve_mapped_pages(&mut self) -> MappedPages;
     fn clear_metadata(&mut self);
+    /// Updates the checksum of the metadata (after the list links changed).
+    fn seal(&mut self);
+    /// Checks that the metadata of the page wasn't corrupted
+    /// (e.g., by an overflow from the last object in the page).
+    fn check_integrity(&self) -> Result<(), &'static str>;
     fn set_heap_id(&mut self, heap_id: usize);
     fn heap_id(&self) -> usize;
-    fn bitfield(&self) -> &[AtomicU64; 8];
-    fn bitfield_mut(&mut self) -> &mut [AtomicU64; 8];
+    /// Records when the page (last) became empty, see `SCAllocator::empty_page_clock`.
+    fn set_empty_since(&mut self, tick: usize);
+    fn empty_since(&self) -> usize;
+    fn bitfield(&self) -> &[AtomicU64];
+    fn bitfield_mut(&mut self) -> &mut [AtomicU64];
+    /// The tags of the objects in this page (which has `slots` slots), indexed by slot.
+    #[cfg(feature = "tagging")]
+    fn tags(&self, slots: usize) -> &[AllocTag];
+    #[cfg(feature = "tagging")]
+    fn tags_mut(&mut self, slots: usize) -> &mut [AllocTag];
     fn prev(&mut self) -> &mut Rawlink<Self>
     where
         Self: core::marker::Sized;
     fn next(&mut self) -> &mut Rawlink<Self>
+    where
+        Self: core::marker::Sized;
+    /// The next page in the list (without borrowing the page mutably).
+    fn next_ptr(&self) -> *mut Self
     where
         Self: core::marker::Sized;
     fn buffer_size() -> usize;
 
     /// Tries to find a free block within `data` that satisfies `alignment` requirement.
     fn first_fit(&self, layout: Layout) -> Option<(usize, usize)> {
-        let base_addr = (&*self as *const Self as *const u8) as usize;
+        let base_addr = self.data_addr();
         self.bitfield().first_fit(base_addr, layout, Self::SIZE, Self::METADATA_SIZE)
     }
 
     /// Tries to allocate an object within this page.
     ///
+    /// The slot is claimed atomically, so this may be called concurrently
+    /// from multiple threads on the same page.
+    ///
     /// In case the slab is full, returns a null ptr.
-    fn allocate(&mut self, layout: Layout) -> *mut u8 {
-        match self.first_fit(layout) {
-            Some((idx, addr)) => {
-                self.bitfield().set_bit(idx);
-                addr as *mut u8
-            }
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        let base_addr = self.data_addr();
+        match self
+            .bitfield()
+            .claim_first_fit(base_addr, layout, Self::SIZE, Self::METADATA_SIZE)
+        {
+            Some((_idx, addr)) => addr as *mut u8,
             None => ptr::null_mut(),
         }
     }
 
+    /// Tries to allocate an object in a randomly chosen free slot within this page.
+    ///
+    /// In case the slab is full, returns a null ptr.
+    #[cfg(feature = "hardened")]
+    fn allocate_random(&self, layout: Layout, rng: &dyn SlotRng) -> *mut u8 {
+        let base_addr = self.data_addr();
+        match self.bitfield().claim_random_fit(
+            base_addr,
+            layout,
+            Self::SIZE,
+            Self::METADATA_SIZE,
+            rng.next_u64(),
+        ) {
+            Some((_idx, addr)) => addr as *mut u8,
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Tries to allocate as many objects as fit into `objects` within this page.
+    ///
+    /// Returns the number of objects that were allocated
+    /// (their addresses are at the start of `objects`).
+    fn allocate_many(&self, layout: Layout, objects: &mut [MaybeUninit<NonNull<u8>>]) -> usize {
+        let base_addr = self.data_addr();
+        self.bitfield()
+            .claim_many(base_addr, layout, Self::SIZE, Self::METADATA_SIZE, objects)
+    }
+
     /// Checks if we can still allocate more objects of a given layout within the page.
     fn is_full(&self) -> bool {
         self.bitfield().is_full()
@@ -214,24 +625,139 @@ pub trait AllocablePage {
         self.bitfield().all_free(relevant_bits)
     }
 
-    /// Deallocates a memory object within this page.
+    /// The number of objects that can still be allocated within the page.
+    fn free_slots(&self) -> usize {
+        self.bitfield().free_count()
+    }
+
+    /// Deallocates a memory object within this page
+    /// (`layout.size()` is the size of the size class, not of the slot).
     fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str> {
         // trace!(
         //     "AllocablePage deallocating ptr = {:p} with {:?}",
         //     ptr,
         //     layout
         // );
+        let idx = self.retire(ptr, layout)?;
+        self.bitfield().clear_bit(idx);
+        Ok(())
+    }
+
+    /// The index of the slot (of `slot_size` bytes) of the object at `ptr`.
+    fn slot_index(ptr: NonNull<u8>, slot_size: usize) -> usize {
         let page_offset = (ptr.as_ptr() as usize) & (Self::SIZE - 1);
-        assert!(page_offset % layout.size() == 0);
-        let idx = page_offset / layout.size();
+        assert!(page_offset.is_multiple_of(slot_size));
+        page_offset / slot_size
+    }
+
+    /// Does all the checks (and poisoning) for freeing the object at `ptr`,
+    /// but leaves its slot marked allocated.
+    ///
+    /// Returns the index of the slot, which can be marked free later on.
+    /// `layout.size()` is the size of the size class.
+    fn retire(&self, ptr: NonNull<u8>, layout: Layout) -> Result<usize, &'static str> {
+        let idx = Self::slot_index(ptr, slot_size(layout.size()));
         assert!(
             self.bitfield().is_allocated(idx),
             "{:p} not marked allocated?",
             ptr
         );
 
-        self.bitfield().clear_bit(idx);
-        Ok(())
+        #[cfg(feature = "red-zones")]
+        self.check_red_zones(idx, layout.size())?;
+
+        #[cfg(feature = "poison")]
+        unsafe {
+            ptr::write_bytes(ptr.as_ptr(), POISON_PATTERN, layout.size())
+        };
+
+        Ok(idx)
+    }
+
+    /// Fills the objects (of `size` bytes) in the first `obj_per_page` slots with `POISON_PATTERN`.
+    #[cfg(feature = "poison")]
+    fn poison_slots(&mut self, size: usize, obj_per_page: usize) {
+        let base_addr = self.data_addr() as *mut u8;
+        for idx in 0..obj_per_page {
+            unsafe {
+                let obj = base_addr.add(idx * slot_size(size));
+                ptr::write_bytes(obj, POISON_PATTERN, size);
+            }
+        }
+    }
+
+    /// Checks that the free object at `ptr` (of `size` bytes) still holds `POISON_PATTERN`.
+    ///
+    /// Reports the object, its size class and the heap id of the page
+    /// if it was written to after it was freed.
+    #[cfg(feature = "poison")]
+    fn check_poison(&self, ptr: NonNull<u8>, size: usize) -> Result<(), &'static str> {
+        let obj = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), size) };
+        match obj.iter().position(|b| *b != POISON_PATTERN) {
+            None => Ok(()),
+            Some(offset) => {
+                error!(
+                    "Object {:p} was written at offset {} after it was freed (size class {}, heap id {})",
+                    ptr,
+                    offset,
+                    size,
+                    self.heap_id()
+                );
+                Err(CORRUPTED)
+            }
+        }
+    }
+
+    /// Fills the red zones after the objects (of `size` bytes) in the first `obj_per_page` slots
+    /// with `RED_ZONE_PATTERN`.
+    ///
+    /// The red zone is the rest of the slot, i.e. everything up to the next object.
+    #[cfg(feature = "red-zones")]
+    fn fill_red_zones(&mut self, size: usize, obj_per_page: usize) {
+        let base_addr = self.data_addr() as *mut u8;
+        for idx in 0..obj_per_page {
+            unsafe {
+                let slot = base_addr.add(idx * slot_size(size));
+                ptr::write_bytes(slot.add(size), RED_ZONE_PATTERN, slot_size(size) - size);
+            }
+        }
+    }
+
+    /// Checks that the red zone after the object (of `size` bytes) in slot `idx`
+    /// still holds `RED_ZONE_PATTERN`.
+    ///
+    /// Reports the object, its size class and the heap id of the page
+    /// if it was overwritten.
+    #[cfg(feature = "red-zones")]
+    fn check_red_zones(&self, idx: usize, size: usize) -> Result<(), &'static str> {
+        let obj = self.data_addr() + idx * slot_size(size);
+        let red_zone = unsafe { core::slice::from_raw_parts((obj + size) as *const u8, slot_size(size) - size) };
+
+        if red_zone.iter().all(|b| *b == RED_ZONE_PATTERN) {
+            Ok(())
+        } else {
+            error!(
+                "Red zone after object {:#x} was overwritten (size class {}, heap id {})",
+                obj,
+                size,
+                self.heap_id()
+            );
+            Err("Red zone corrupted")
+        }
+    }
+
+    /// Checks the red zones of the first `obj_per_page` objects (of `size` bytes each).
+    ///
+    /// Every corrupted object is reported, not just the first one.
+    #[cfg(feature = "red-zones")]
+    fn verify_red_zones(&self, size: usize, obj_per_page: usize) -> Result<(), &'static str> {
+        let mut res = Ok(());
+        for idx in 0..obj_per_page {
+            if let Err(e) = self.check_red_zones(idx, size) {
+                res = Err(e);
+            }
+        }
+        res
     }
 }
 
@@ -251,9 +777,17 @@ pub struct ObjectPage8k<'a> {
     /// Holds memory objects.
     #[allow(dead_code)]
     data: [u8; ObjectPage8k::SIZE -ObjectPage8k::METADATA_SIZE],
-    
+
+    /// Always `PAGE_MAGIC` for a page that is in use.
+    magic: u32,
+    /// Checksum over the page address, `heap_id`, `next` and `prev`.
+    checksum: u32,
+
     pub mp: MappedPages,
 
+    /// When this page became empty (only meaningful while it is on an empty list).
+    empty_since: usize,
+
     pub heap_id: usize,
 
     /// Next element in list (used by `PageList`).
@@ -272,51 +806,52 @@ unsafe impl<'a> Sync for ObjectPage8k<'a> {}
 
 impl<'a> AllocablePage for ObjectPage8k<'a> {
     const SIZE: usize = 8192;
-    const METADATA_SIZE: usize = core::mem::size_of::<MappedPages>() + core::mem::size_of::<usize>() + (2*core::mem::size_of::<Rawlink<ObjectPage8k<'a>>>()) + (8*8);
-    const HEAP_ID_OFFSET: usize = Self::SIZE - (core::mem::size_of::<usize>() + (2*core::mem::size_of::<Rawlink<ObjectPage8k<'a>>>()) + (8*8));
+    const METADATA_SIZE: usize = (2*core::mem::size_of::<u32>()) + core::mem::size_of::<MappedPages>() + (2*core::mem::size_of::<usize>()) + (2*core::mem::size_of::<Rawlink<ObjectPage8k<'a>>>()) + (8*8);
+    const HEAP_ID_OFFSET: Option<usize> = Some(Self::SIZE - (core::mem::size_of::<usize>() + (2*core::mem::size_of::<Rawlink<ObjectPage8k<'a>>>()) + (8*8)));
+    /// One tag per slot is kept behind the last slot, so pages of small objects
+    /// use space that is left over anyway, and the largest object loses just one byte.
+    #[cfg(feature = "tagging")]
+    const TAG_SIZE: usize = core::mem::size_of::<AllocTag>();
 
     /// Creates a new 8KiB allocable page and stores the MappedPages object in the metadata portion.
     /// This function checks that the given mapped pages is aligned at a 8KiB boundary, writable and has a size of 8KiB.
     fn new(mp: MappedPages, heap_id: usize) -> Result<ObjectPage8k<'a>, &'static str> {
-        let vaddr = mp.start_address().value();
-        
-        if vaddr % Self::SIZE != 0 {
-            error!("The mapped pages for the heap are not aligned at 8k bytes");
-            return Err("The mapped pages for the heap are not aligned at 8k bytes");
-        }
-
-        // check that the mapped pages is writable
-        if !mp.flags().is_writable() {
-            error!("Tried to convert to an allocable page but MappedPages weren't writable (flags: {:?})",  mp.flags());
-            return Err("Trying to create an allocable page but MappedPages were not writable");
-        }
-        
-        // check that the mapped pages size is equal in size to the page
-        if Self::SIZE != mp.size_in_bytes() {
-            error!("MappedPages of size {} cannot be converted to an allocable page", mp.size_in_bytes());
-            return Err("MappedPages size does not equal allocable page size");
-        }
+        check_mapped_pages(&mp, Self::SIZE)?;
 
         Ok( ObjectPage8k {
             data: [0; ObjectPage8k::SIZE -ObjectPage8k::METADATA_SIZE],
-            mp: mp,
-            heap_id: heap_id,
+            magic: PAGE_MAGIC,
+            // Sealed once the page is at its final address
+            checksum: 0,
+            mp,
+            empty_since: 0,
+            heap_id,
             next: Rawlink::default(),
             prev: Rawlink::default(),
             bitfield: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),AtomicU64::new(0) ],
         })
     }
 
+    /// Reads the heap id from the metadata at the end of the page (see `HEAP_ID_OFFSET`).
+    unsafe fn heap_id_of(addr: usize) -> Option<usize> {
+        let page = Self::from_object(addr)?;
+        page.check_integrity().ok()?;
+        Some(page.heap_id)
+    }
+
     /// Returns the MappedPages object that was stored in the metadata portion of the page,
     /// by swapping with an empty MappedPages object.
     fn retrieve_mapped_pages(&mut self) -> MappedPages {
         let mut mp = MappedPages::empty();
         core::mem::swap(&mut self.mp, &mut mp);
+        // Stale pointers into this page won't pass `check_integrity` anymore.
+        self.magic = 0;
         mp
     }
 
     /// clears the metadata section of the page
     fn clear_metadata(&mut self) {
+        self.empty_since = 0;
         self.heap_id = 0;
         self.next = Rawlink::default();
         self.prev = Rawlink::default();
@@ -325,21 +860,64 @@ impl<'a> AllocablePage for ObjectPage8k<'a> {
         }
     }
 
+    fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    fn check_integrity(&self) -> Result<(), &'static str> {
+        if self.magic != PAGE_MAGIC {
+            error!(
+                "Page {:p} has a bad magic number {:#x} (heap id {})",
+                self, self.magic, self.heap_id
+            );
+            return Err("ObjectPage8k metadata corrupted");
+        }
+        if self.checksum != self.compute_checksum() {
+            error!(
+                "Page {:p} has a bad checksum (heap id {}, next {:p}, prev {:p})",
+                self, self.heap_id, self.next.as_ptr(), self.prev.as_ptr()
+            );
+            return Err("ObjectPage8k metadata corrupted");
+        }
+        Ok(())
+    }
+
     fn set_heap_id(&mut self, heap_id: usize){
         self.heap_id = heap_id;
+        self.seal();
     }
 
     fn heap_id(&self) -> usize {
         self.heap_id
     }
 
-    fn bitfield(&self) -> &[AtomicU64; 8] {
+    fn set_empty_since(&mut self, tick: usize) {
+        self.empty_since = tick;
+    }
+
+    fn empty_since(&self) -> usize {
+        self.empty_since
+    }
+
+    fn bitfield(&self) -> &[AtomicU64] {
         &self.bitfield
     }
-    fn bitfield_mut(&mut self) -> &mut [AtomicU64; 8] {
+    fn bitfield_mut(&mut self) -> &mut [AtomicU64] {
         &mut self.bitfield
     }
 
+    /// The tags follow the last slot, at the end of the data area.
+    #[cfg(feature = "tagging")]
+    fn tags(&self, slots: usize) -> &[AllocTag] {
+        let end = Self::SIZE - Self::METADATA_SIZE;
+        &self.data[end - slots * Self::TAG_SIZE..end]
+    }
+    #[cfg(feature = "tagging")]
+    fn tags_mut(&mut self, slots: usize) -> &mut [AllocTag] {
+        let end = Self::SIZE - Self::METADATA_SIZE;
+        &mut self.data[end - slots * Self::TAG_SIZE..end]
+    }
+
     fn prev(&mut self) -> &mut Rawlink<Self> {
         &mut self.prev
     }
@@ -348,11 +926,27 @@ impl<'a> AllocablePage for ObjectPage8k<'a> {
         &mut self.next
     }
 
+    fn next_ptr(&self) -> *mut Self {
+        self.next.as_ptr()
+    }
+
     fn buffer_size() -> usize {
         ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE
     }
 }
 
+impl<'a> ObjectPage8k<'a> {
+    /// A checksum over the page address, `heap_id`, `next` and `prev`.
+    fn compute_checksum(&self) -> u32 {
+        metadata_checksum(&[
+            self as *const ObjectPage8k as u64,
+            self.heap_id as u64,
+            self.next.as_ptr() as u64,
+            self.prev.as_ptr() as u64,
+        ])
+    }
+}
+
 impl<'a> Default for ObjectPage8k<'a> {
     fn default() -> ObjectPage8k<'a> {
         unsafe { mem::MaybeUninit::zeroed().assume_init() }
@@ -403,26 +997,55 @@ impl<'a, T: AllocablePage> PageList<'a, T> {
     }
 
     /// Inserts `new_head` at the front of the list.
-    pub(crate) fn insert_front<'b>(&'b mut self, mut new_head: &'a mut T) {
+    ///
+    /// Fails (without changing the list) if the metadata of `new_head`
+    /// or the current head is corrupted.
+    pub(crate) fn insert_front<'b>(&'b mut self, mut new_head: &'a mut T) -> Result<(), &'static str> {
+        self.check_insert(new_head)?;
         match self.head {
             None => {
                 *new_head.prev() = Rawlink::none();
+                new_head.seal();
                 self.head = Some(new_head);
             }
             Some(ref mut head) => {
                 *new_head.prev() = Rawlink::none();
                 *head.prev() = Rawlink::some(new_head);
+                head.seal();
                 mem::swap(head, &mut new_head);
                 *head.next() = Rawlink::some(new_head);
+                head.seal();
             }
         }
 
         self.elements += 1;
+        Ok(())
+    }
+
+    /// Checks that the metadata of `new_head` and the current head is intact,
+    /// i.e., that `insert_front` won't fail.
+    pub(crate) fn check_insert(&self, new_head: &T) -> Result<(), &'static str> {
+        new_head.check_integrity()?;
+        match self.head {
+            None => Ok(()),
+            Some(ref head) => head.check_integrity(),
+        }
     }
 
     /// Removes `slab_page` from the list.
-    pub(crate) fn remove_from_list(&mut self, slab_page: &mut T) {
+    ///
+    /// Fails (without changing the list) if the metadata of `slab_page`
+    /// or one of its neighbours is corrupted, instead of following a broken link.
+    pub(crate) fn remove_from_list(&mut self, slab_page: &mut T) -> Result<(), &'static str> {
+        slab_page.check_integrity()?;
         unsafe {
+            if let Some(prev) = slab_page.prev().resolve_mut() {
+                prev.check_integrity()?;
+            }
+            if let Some(next) = slab_page.next().resolve_mut() {
+                next.check_integrity()?;
+            }
+
             match slab_page.prev().resolve_mut() {
                 None => {
                     self.head = slab_page.next().resolve_mut();
@@ -432,6 +1055,7 @@ impl<'a, T: AllocablePage> PageList<'a, T> {
                         None => Rawlink::none(),
                         Some(next) => Rawlink::some(next),
                     };
+                    prev.seal();
                 }
             }
 
@@ -442,33 +1066,45 @@ impl<'a, T: AllocablePage> PageList<'a, T> {
                         None => Rawlink::none(),
                         Some(prev) => Rawlink::some(prev),
                     };
+                    next.seal();
                 }
             }
         }
 
         *slab_page.prev() = Rawlink::none();
         *slab_page.next() = Rawlink::none();
+        slab_page.seal();
         self.elements -= 1;
+        Ok(())
     }
 
-    /// Removes `slab_page` from the list.
-    pub(crate) fn pop<'b>(&'b mut self) -> Option<&'a mut T> {
+    /// Removes the head of the list.
+    ///
+    /// Fails (without changing the list) if the metadata of the head
+    /// or the page after it is corrupted.
+    pub(crate) fn pop<'b>(&'b mut self) -> Result<Option<&'a mut T>, &'static str> {
         match self.head {
-            None => None,
+            None => Ok(None),
             Some(ref mut head) => {
+                head.check_integrity()?;
+                if let Some(next) = unsafe { head.next().resolve_mut() } {
+                    next.check_integrity()?;
+                }
                 let head_next = head.next();
                 let mut new_head = unsafe { head_next.resolve_mut() };
                 mem::swap(&mut self.head, &mut new_head);
-                let _ = self.head.as_mut().map(|n| {
+                if let Some(n) = self.head.as_mut() {
                     *n.prev() = Rawlink::none();
-                });
+                    n.seal();
+                }
 
                 self.elements -= 1;
-                new_head.map(|node| {
+                if let Some(node) = new_head.as_mut() {
                     *node.prev() = Rawlink::none();
                     *node.next() = Rawlink::none();
-                    node
-                })
+                    node.seal();
+                }
+                Ok(new_head)
             }
         }
     }
@@ -476,7 +1112,7 @@ impl<'a, T: AllocablePage> PageList<'a, T> {
     /// Does the list contain `s`?
     pub(crate) fn contains(&mut self, s: *const T) -> bool {
         for slab_page in self.iter_mut() {
-            if slab_page as *const T == s as *const T {
+            if core::ptr::eq(slab_page, s) {
                 return true;
             }
         }
@@ -501,13 +1137,12 @@ impl<'a, P: AllocablePage + 'a> Iterator for ObjectPageIterMut<'a, P> {
     #[inline]
     fn next(&mut self) -> Option<&'a mut P> {
         unsafe {
-            self.head.resolve_mut().map(|next| {
-                self.head = match next.next().resolve_mut() {
-                    None => Rawlink::none(),
-                    Some(ref mut sp) => Rawlink::some(*sp),
-                };
-                next
-            })
+            let next = self.head.resolve_mut()?;
+            self.head = match next.next().resolve_mut() {
+                None => Rawlink::none(),
+                Some(ref mut sp) => Rawlink::some(*sp),
+            };
+            Some(next)
         }
     }
 }
@@ -559,6 +1194,16 @@ impl<T> Rawlink<T> {
         self.p.as_mut()
     }
 
+    /// Create a `Rawlink` from a raw pointer (which may be null).
+    pub(crate) fn from_raw(p: *mut T) -> Rawlink<T> {
+        Rawlink { p }
+    }
+
+    /// Returns the raw pointer held by this `Rawlink`.
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        self.p
+    }
+
     /// Return the `Rawlink` and replace with `Rawlink::none()`
     #[allow(dead_code)]
     pub(crate) fn take(&mut self) -> Rawlink<T> {
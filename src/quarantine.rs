@@ -0,0 +1,119 @@
+//! A quarantine for freed objects, which delays the reuse of their slots.
+
+use core::alloc::Layout;
+
+/// Maximum number of objects a `Quarantine` can hold.
+pub const QUARANTINE_CAPACITY: usize = 64;
+
+/// How many freed objects are kept in a `Quarantine`.
+///
+/// Both limits are capped at `QUARANTINE_CAPACITY` objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarantineLimit {
+    /// Keep at most this many objects (0 disables the quarantine).
+    Objects(usize),
+    /// Keep at most as many objects as fit into this many bytes.
+    Bytes(usize),
+}
+
+/// A FIFO of recently freed objects (of one size class).
+///
+/// The slots of quarantined objects stay marked allocated in their page,
+/// they only become free once the object leaves the quarantine.
+pub struct Quarantine {
+    /// Addresses and layouts of the quarantined objects (a ring buffer).
+    objects: [(usize, Layout); QUARANTINE_CAPACITY],
+    /// Index of the oldest object in `objects`.
+    head: usize,
+    /// Number of objects in the quarantine.
+    len: usize,
+    limit: QuarantineLimit,
+}
+
+impl Quarantine {
+    /// Creates an empty quarantine that holds up to `QUARANTINE_CAPACITY` objects.
+    pub const fn new() -> Quarantine {
+        Quarantine {
+            objects: [(0, Layout::new::<u8>()); QUARANTINE_CAPACITY],
+            head: 0,
+            len: 0,
+            limit: QuarantineLimit::Objects(QUARANTINE_CAPACITY),
+        }
+    }
+
+    /// The current limit.
+    pub fn limit(&self) -> QuarantineLimit {
+        self.limit
+    }
+
+    /// Changes the limit (objects above it are evicted with the next `push`).
+    pub fn set_limit(&mut self, limit: QuarantineLimit) {
+        self.limit = limit;
+    }
+
+    /// The number of quarantined objects.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the quarantine holds no objects.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many objects of `size` bytes we can hold.
+    fn max_objects(&self, size: usize) -> usize {
+        let n = match self.limit {
+            QuarantineLimit::Objects(n) => n,
+            QuarantineLimit::Bytes(bytes) => bytes / size,
+        };
+        core::cmp::min(n, QUARANTINE_CAPACITY)
+    }
+
+    /// Checks if we can't take another object of `size` bytes without exceeding the limit.
+    pub fn is_full(&self, size: usize) -> bool {
+        self.len >= self.max_objects(size)
+    }
+
+    /// Checks if we hold more objects of `size` bytes than the limit allows
+    /// (e.g., after the limit was lowered).
+    pub fn over_limit(&self, size: usize) -> bool {
+        self.len > self.max_objects(size)
+    }
+
+    /// Adds the object at `addr` (freed with `layout`) as the newest object.
+    ///
+    /// Callers make room with `pop` first (see `is_full`).
+    pub fn push(&mut self, addr: usize, layout: Layout) {
+        assert!(self.len < QUARANTINE_CAPACITY, "Quarantine is full");
+        self.objects[(self.head + self.len) % QUARANTINE_CAPACITY] = (addr, layout);
+        self.len += 1;
+    }
+
+    /// Checks if the object at `addr` is in the quarantine.
+    pub fn contains(&self, addr: usize) -> bool {
+        self.iter().any(|object| object == addr)
+    }
+
+    /// Iterates over the addresses of the quarantined objects, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).map(move |i| self.objects[(self.head + i) % QUARANTINE_CAPACITY].0)
+    }
+
+    /// Removes the oldest object, returns its address and layout.
+    pub fn pop(&mut self) -> Option<(usize, Layout)> {
+        if self.len == 0 {
+            return None;
+        }
+        let object = self.objects[self.head];
+        self.head = (self.head + 1) % QUARANTINE_CAPACITY;
+        self.len -= 1;
+        Some(object)
+    }
+}
+
+impl Default for Quarantine {
+    fn default() -> Quarantine {
+        Quarantine::new()
+    }
+}
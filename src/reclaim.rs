@@ -0,0 +1,97 @@
+//! Giving pages back from the allocators.
+
+use crate::*;
+
+/// Decides from which size classes (and in which order) `ZoneAllocator::reclaim`
+/// takes empty pages.
+///
+/// All policies respect the empty pages threshold of every size class
+/// (see `ZoneAllocator::set_empty_pages_threshold`), that many empty pages
+/// are always kept as a reserve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReclaimPolicy {
+    /// Go through the size classes (smallest first) and take every empty page
+    /// above the class's reserve.
+    InClassOrder,
+    /// Always take the next page from the size class that currently has the most
+    /// empty pages above its reserve.
+    LargestPool,
+    /// Take the pages that have been empty the longest first, across all size classes.
+    OldestEmpty,
+}
+
+/// A batch of pages that were taken out of an allocator.
+///
+/// The pages are still linked together through their meta-data, so collecting
+/// them doesn't need any memory. Iterating over the batch returns the
+/// `MappedPages` of every page, any pages that are left in the batch when it
+/// is dropped are unmapped (i.e., their `MappedPages` are dropped).
+pub struct ReclaimedPages<'a, P: AllocablePage> {
+    pages: PageList<'a, P>,
+}
+
+impl<'a, P: AllocablePage> ReclaimedPages<'a, P> {
+    /// Creates an empty batch.
+    pub(crate) fn new() -> ReclaimedPages<'a, P> {
+        ReclaimedPages {
+            pages: PageList::new(),
+        }
+    }
+
+    /// Adds `page` to the batch.
+    pub(crate) fn push(&mut self, page: &'a mut P) -> Result<(), &'static str> {
+        self.pages.insert_front(page)
+    }
+
+    /// Number of pages in the batch.
+    pub fn len(&self) -> usize {
+        self.pages.elements
+    }
+
+    /// Is the batch empty?
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+impl<'a, P: AllocablePage> Iterator for ReclaimedPages<'a, P> {
+    type Item = MappedPages;
+
+    fn next(&mut self) -> Option<MappedPages> {
+        // The pages after a corrupted one are leaked (we can't follow its links).
+        self.pages.pop().ok()?.map(|page| page.retrieve_mapped_pages())
+    }
+}
+
+impl<'a, P: AllocablePage> Drop for ReclaimedPages<'a, P> {
+    fn drop(&mut self) {
+        for mp in self {
+            drop(mp);
+        }
+    }
+}
+
+/// What `ZoneAllocator::drain` does if objects are still allocated in the zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainMode {
+    /// Leave all pages in the zone and return an error.
+    Refuse,
+    /// Take all pages out anyway and return the number of objects that were still allocated.
+    Report,
+}
+
+/// What happens to the pages of a `ZoneAllocator` when it is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Leave the pages alone, their mappings are leaked (the default).
+    ///
+    /// This is right for zones that live as long as the system does.
+    #[default]
+    Leak,
+    /// Give all pages back if no objects are allocated from the zone any more.
+    /// Otherwise, the pages are leaked (and an error is logged).
+    ReleaseIfUnused,
+    /// Always give all pages back, even if objects are still allocated in them
+    /// (an error is logged in that case).
+    Release,
+}
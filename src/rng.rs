@@ -0,0 +1,83 @@
+//! Random numbers for randomized slot and page selection (the `hardened` feature).
+//!
+//! `SCAllocator::allocate` picks a random free slot and starts its search for
+//! a partial page at a random one, `ConcurrentSCAllocator::allocate` picks a
+//! random free slot. Not randomized are:
+//!
+//!  * `SCAllocator::allocate_bulk` and `ConcurrentSCAllocator::allocate_bulk`
+//!    (and thus the `MagazineCache` when it refills its magazines), which claim
+//!    the lowest free slots of a page so that they can fill a batch with a single
+//!    pass over the bitfield.
+//!  * The objects cached in a `MagazineCache`, which are handed out in the reverse
+//!    order they were freed (as any per-CPU free list does).
+//!
+//! The default generator starts from a fixed seed, which makes the slot choice
+//! predictable to an attacker that knows the seed. Call `seed_slot_rng` with
+//! real entropy (e.g., from `rdrand` or the boot time stamp counter) early during
+//! boot, or give the allocators a `SlotRng` of your own.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A source of random numbers for picking slots and pages.
+///
+/// Implementations must be usable from multiple CPUs at once
+/// (e.g., backed by a hardware RNG or an atomic state).
+pub trait SlotRng: Sync {
+    /// Returns the next random number.
+    fn next_u64(&self) -> u64;
+}
+
+/// A xorshift64* generator, the default `SlotRng`.
+///
+/// It is cheap and lock-free, but not cryptographically secure: deployments
+/// that care should seed it with real entropy or plug in their own `SlotRng`.
+pub struct XorShiftRng {
+    state: AtomicU64,
+}
+
+impl XorShiftRng {
+    /// Creates a generator from `seed` (a zero seed is replaced since
+    /// xorshift would only ever produce zeros).
+    pub const fn new(seed: u64) -> XorShiftRng {
+        XorShiftRng {
+            state: AtomicU64::new(seed | 1),
+        }
+    }
+
+    /// Restarts the generator from `seed`.
+    pub fn reseed(&self, seed: u64) {
+        self.state.store(seed | 1, Ordering::Relaxed);
+    }
+}
+
+impl SlotRng for XorShiftRng {
+    fn next_u64(&self) -> u64 {
+        let mut x = self.state.load(Ordering::Relaxed);
+        loop {
+            let mut next = x;
+            next ^= next >> 12;
+            next ^= next << 25;
+            next ^= next >> 27;
+            match self
+                .state
+                .compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return next.wrapping_mul(0x2545_f491_4f6c_dd1d),
+                Err(current) => x = current,
+            }
+        }
+    }
+}
+
+/// The `SlotRng` used by allocators that weren't given one.
+///
+/// Its seed is fixed and therefore public, see `seed_slot_rng`.
+pub(crate) static DEFAULT_SLOT_RNG: XorShiftRng = XorShiftRng::new(0x9e37_79b9_7f4a_7c15);
+
+/// Seeds the `SlotRng` used by allocators that weren't given one.
+///
+/// Until this is called the default generator runs from a fixed seed, so
+/// its choices can be predicted and it doesn't make heap grooming any harder.
+pub fn seed_slot_rng(seed: u64) {
+    DEFAULT_SLOT_RNG.reseed(seed);
+}
@@ -1,6 +1,14 @@
 //! A SCAllocator that can allocate fixed size objects.
 
 use crate::*;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A logical clock to record when pages become empty.
+///
+/// It's shared by all allocators so we can compare the age of
+/// empty pages from different size classes.
+static EMPTY_PAGE_CLOCK: AtomicUsize = AtomicUsize::new(0);
 
 /// A genius(?) const min()
 ///
@@ -13,16 +21,25 @@ use crate::*;
 /// # Source
 /// https://stackoverflow.com/questions/53619695/calculating-maximum-value-of-a-set-of-constant-expressions-at-compile-time
 #[cfg(feature = "unstable")]
-const fn cmin(a: usize, b: usize) -> usize {
+pub(crate) const fn cmin(a: usize, b: usize) -> usize {
     [a, b][(a > b) as usize]
 }
 
 /// The boring variant of min (not const).
 #[cfg(not(feature = "unstable"))]
-fn cmin(a: usize, b: usize) -> usize {
+pub(crate) fn cmin(a: usize, b: usize) -> usize {
     core::cmp::min(a, b)
 }
 
+/// What a bulk allocation does if it can't allocate all requested objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkMode {
+    /// Allocate as many objects as possible.
+    Partial,
+    /// Free everything allocated so far and return an error.
+    AllOrNothing,
+}
+
 /// A slab allocator allocates elements of a fixed size.
 ///
 /// It maintains three internal lists of objects that implement `AllocablePage`
@@ -57,6 +74,20 @@ pub struct SCAllocator<'a, P: AllocablePage> {
     pub(crate) slabs: PageList<'a, P>,
     /// List of full ObjectPages (everything allocated in these don't need to search them).
     pub(crate) full_slabs: PageList<'a, P>,
+    /// Number of empty pages that are kept when pages are reclaimed from this allocator.
+    pub(crate) empty_pages_threshold: usize,
+    /// Number of free slots in `empty_slabs` and `slabs`, kept up to date on every
+    /// allocation, deallocation and page movement (so we don't have to walk the pages).
+    pub(crate) free_slot_count: usize,
+    /// Recently freed objects whose slots can't be reused yet.
+    #[cfg(feature = "quarantine")]
+    pub(crate) quarantine: Quarantine,
+    /// Picks slots and partial pages (the default RNG is used if `None`).
+    #[cfg(feature = "hardened")]
+    pub(crate) rng: Option<&'a dyn SlotRng>,
+    /// Called on allocator events, if set.
+    #[cfg(feature = "hooks")]
+    pub(crate) hooks: Option<&'a dyn AllocatorHooks>,
 }
 
 /// Creates an instance of a scallocator, we do this in a macro because we
@@ -66,10 +97,18 @@ macro_rules! new_sc_allocator {
         SCAllocator {
             size: $size,
             allocation_count: 0,
-            obj_per_page: cmin((P::SIZE - P::METADATA_SIZE) / $size, 8 * 64),
+            obj_per_page: cmin((P::SIZE - P::METADATA_SIZE) / (slot_size($size) + P::TAG_SIZE), P::MAX_OBJECTS),
             empty_slabs: PageList::new(),
             slabs: PageList::new(),
             full_slabs: PageList::new(),
+            empty_pages_threshold: 0,
+            free_slot_count: 0,
+            #[cfg(feature = "quarantine")]
+            quarantine: Quarantine::new(),
+            #[cfg(feature = "hardened")]
+            rng: None,
+            #[cfg(feature = "hooks")]
+            hooks: None,
         }
     };
 }
@@ -93,30 +132,186 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
         self.size
     }
 
+    /// The layout of a slot (object and red zone) in our pages for an allocation of `layout`.
+    fn slot_layout(&self, layout: Layout) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(slot_size(self.size), layout.align()) }
+    }
+
+    /// The layout of an object of our size class for an allocation of `layout`
+    /// (this is what pages expect when objects are freed).
+    fn object_layout(&self, layout: Layout) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.size, layout.align()) }
+    }
+
+    /// Checks that the newly allocated object at `ptr` wasn't written to while it was free.
+    #[cfg(feature = "poison")]
+    fn check_poison(&self, ptr: NonNull<u8>) -> Result<(), &'static str> {
+        let slab_page = Self::page_of(ptr)?;
+        slab_page.check_poison(ptr, self.size)
+    }
+
+    /// Finds the page that holds the object at `ptr`,
+    /// making sure its metadata is intact before we use it.
+    fn page_of(ptr: NonNull<u8>) -> Result<&'a mut P, &'static str> {
+        let slab_page = unsafe { P::from_object(ptr.as_ptr() as usize) }.ok_or("Object is not in a slab page")?;
+        slab_page.check_integrity()?;
+        Ok(slab_page)
+    }
+
+    /// Checks all pages of the allocator for corruption.
+    ///
+    /// This checks the metadata (magic number and checksum) of every page,
+    /// and with the `red-zones` feature the red zone after every object.
+    /// All corruptions are reported, not just the first one.
+    pub fn verify(&mut self) -> Result<(), &'static str> {
+        let mut res = Ok(());
+
+        for list in [&mut self.empty_slabs, &mut self.slabs, &mut self.full_slabs].iter_mut() {
+            for page in list.iter_mut() {
+                if let Err(e) = page.check_integrity() {
+                    // Don't follow the links of a corrupted page
+                    res = Err(e);
+                    break;
+                }
+
+                #[cfg(feature = "red-zones")]
+                {
+                    if let Err(e) = page.verify_red_zones(self.size, self.obj_per_page) {
+                        res = Err(e);
+                    }
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Sets the RNG used to pick slots and partial pages.
+    #[cfg(feature = "hardened")]
+    pub fn set_rng(&mut self, rng: &'a dyn SlotRng) {
+        self.rng = Some(rng);
+    }
+
+    /// The RNG used to pick slots and partial pages.
+    #[cfg(feature = "hardened")]
+    fn rng(&self) -> &'a dyn SlotRng {
+        self.rng.unwrap_or(&DEFAULT_SLOT_RNG)
+    }
+
+    /// Sets the hooks that are called on the events of this allocator.
+    #[cfg(feature = "hooks")]
+    pub fn set_hooks(&mut self, hooks: &'a dyn AllocatorHooks) {
+        self.hooks = Some(hooks);
+    }
+
+    /// The heap id of the page that holds the object at `ptr` (for the hooks).
+    #[cfg(feature = "hooks")]
+    fn heap_id_of(ptr: NonNull<u8>) -> usize {
+        Self::page_of(ptr).map_or(0, |page| page.heap_id())
+    }
+
+    /// Allocates an object in `page` (in a random slot with the `hardened` feature).
+    fn allocate_in_page(&self, page: &P, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "hardened")]
+        return page.allocate_random(layout, self.rng());
+        #[cfg(not(feature = "hardened"))]
+        return page.allocate(layout);
+    }
+
     /// Add a new ObjectPage.
-    fn insert_partial_slab(&mut self, new_head: &'a mut P) {
-        self.slabs.insert_front(new_head);
+    fn insert_partial_slab(&mut self, new_head: &'a mut P) -> Result<(), &'static str> {
+        self.slabs.insert_front(new_head)
     }
 
     /// Add page to empty list.
-    fn insert_empty(&mut self, new_head: &'a mut P) {
+    fn insert_empty(&mut self, new_head: &'a mut P) -> Result<(), &'static str> {
         assert_eq!(
-            new_head as *const P as usize % P::SIZE,
+            new_head.data_addr() % P::SIZE,
             0,
             "Inserted page is not aligned to page-size."
         );
-        self.empty_slabs.insert_front(new_head);
+        new_head.set_empty_since(Self::empty_page_clock());
+        self.empty_slabs.insert_front(new_head)
+    }
+
+    /// Advances the clock used to stamp pages when they become empty.
+    fn empty_page_clock() -> usize {
+        EMPTY_PAGE_CLOCK.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Sets the number of empty pages that are kept when pages are reclaimed.
+    pub fn set_empty_pages_threshold(&mut self, threshold: usize) {
+        self.empty_pages_threshold = threshold;
     }
 
-    fn remove_empty(&mut self) -> Option<&'a mut P> {
-        self.empty_slabs.pop()
+    /// Returns the number of empty pages that are kept when pages are reclaimed.
+    pub fn empty_pages_threshold(&self) -> usize {
+        self.empty_pages_threshold
     }
 
-    fn remove_partial(&mut self) -> Option<&'a mut P> {
-        self.slabs.pop()
+    /// The number of empty pages above the threshold (these can be reclaimed).
+    pub fn surplus_empty_pages(&self) -> usize {
+        self.empty_slabs.elements.saturating_sub(self.empty_pages_threshold)
     }
 
-    fn remove_full(&mut self) -> Option<&'a mut P> {
+    /// The number of objects that can still be allocated without a refill.
+    ///
+    /// Slots of quarantined objects are not free until they leave the quarantine.
+    pub fn free_slots(&self) -> usize {
+        self.free_slot_count
+    }
+
+    /// Removes the most recently emptied page from the empty list.
+    pub(crate) fn take_empty_page(&mut self) -> Result<Option<&'a mut P>, &'static str> {
+        let page = match self.remove_empty()? {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        hook!(self, on_page_return(page.data_addr(), self.size, page.heap_id()));
+        Ok(Some(page))
+    }
+
+    /// Finds the page that has been on the empty list the longest.
+    ///
+    /// Pages are inserted at the front, so this is the last page in the list.
+    fn oldest_empty_page(&mut self) -> Option<&'a mut P> {
+        self.empty_slabs.iter_mut().last()
+    }
+
+    /// When the oldest page in the empty list became empty.
+    pub(crate) fn oldest_empty_since(&mut self) -> Option<usize> {
+        self.oldest_empty_page().map(|page| page.empty_since())
+    }
+
+    /// Removes the page that has been on the empty list the longest.
+    pub(crate) fn take_oldest_empty_page(&mut self) -> Result<Option<&'a mut P>, &'static str> {
+        let page = match self.oldest_empty_page() {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        self.empty_slabs.remove_from_list(page)?;
+        self.free_slot_count -= self.obj_per_page;
+        hook!(self, on_page_return(page.data_addr(), self.size, page.heap_id()));
+        Ok(Some(page))
+    }
+
+    fn remove_empty(&mut self) -> Result<Option<&'a mut P>, &'static str> {
+        let page = self.empty_slabs.pop()?;
+        if page.is_some() {
+            self.free_slot_count -= self.obj_per_page;
+        }
+        Ok(page)
+    }
+
+    fn remove_partial(&mut self) -> Result<Option<&'a mut P>, &'static str> {
+        let page = self.slabs.pop()?;
+        if let Some(ref page) = page {
+            self.free_slot_count -= page.free_slots();
+        }
+        Ok(page)
+    }
+
+    fn remove_full(&mut self) -> Result<Option<&'a mut P>, &'static str> {
         self.full_slabs.pop()
     }
     
@@ -142,7 +337,7 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
     // }
 
     /// Move a page from `slabs` to `empty_slabs`.
-    fn move_to_empty(&mut self, page: &'a mut P) {
+    fn move_to_empty(&mut self, page: &'a mut P) -> Result<(), &'static str> {
         let page_ptr = page as *const P;
 
         debug_assert!(self.slabs.contains(page_ptr));
@@ -152,39 +347,64 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
             page_ptr
         );
 
-        self.slabs.remove_from_list(page);
-        self.empty_slabs.insert_front(page);
+        self.slabs.remove_from_list(page)?;
+        page.set_empty_since(Self::empty_page_clock());
+        hook!(self, on_page_transition(page.data_addr(), self.size, page.heap_id(), PageState::Partial, PageState::Empty));
+        self.empty_slabs.insert_front(page)?;
 
         debug_assert!(!self.slabs.contains(page_ptr));
         debug_assert!(self.empty_slabs.contains(page_ptr));
+        Ok(())
+    }
+
+    /// Move a page from `full_slabs` to `empty_slabs`
+    /// (this happens if a page only holds a single object).
+    fn move_full_to_empty(&mut self, page: &'a mut P) -> Result<(), &'static str> {
+        let page_ptr = page as *const P;
+
+        debug_assert!(self.full_slabs.contains(page_ptr));
+        debug_assert!(!self.empty_slabs.contains(page_ptr));
+
+        self.full_slabs.remove_from_list(page)?;
+        page.set_empty_since(Self::empty_page_clock());
+        hook!(self, on_page_transition(page.data_addr(), self.size, page.heap_id(), PageState::Full, PageState::Empty));
+        self.empty_slabs.insert_front(page)?;
+
+        debug_assert!(!self.full_slabs.contains(page_ptr));
+        debug_assert!(self.empty_slabs.contains(page_ptr));
+        Ok(())
     }
 
     /// Move a page from `full_slabs` to `slab`.
-    fn move_partial_to_full(&mut self, page: &'a mut P) {
+    fn move_partial_to_full(&mut self, page: &'a mut P) -> Result<(), &'static str> {
         let page_ptr = page as *const P;
 
         debug_assert!(self.slabs.contains(page_ptr));
         debug_assert!(!self.full_slabs.contains(page_ptr));
 
-        self.slabs.remove_from_list(page);
-        self.full_slabs.insert_front(page);
+        self.slabs.remove_from_list(page)?;
+        hook!(self, on_page_transition(page.data_addr(), self.size, page.heap_id(), PageState::Partial, PageState::Full));
+        self.full_slabs.insert_front(page)?;
 
         debug_assert!(!self.slabs.contains(page_ptr));
         debug_assert!(self.full_slabs.contains(page_ptr));
+        Ok(())
     }
 
     /// Move a page from `full_slabs` to `slab`.
-    fn move_full_to_partial(&mut self, page: &'a mut P) {
+    fn move_full_to_partial(&mut self, page: &'a mut P) -> Result<(), &'static str> {
         let page_ptr = page as *const P;
 
         debug_assert!(!self.slabs.contains(page_ptr));
         debug_assert!(self.full_slabs.contains(page_ptr));
 
-        self.full_slabs.remove_from_list(page);
-        self.slabs.insert_front(page);
+        self.full_slabs.remove_from_list(page)?;
+        hook!(self, on_page_transition(page.data_addr(), self.size, page.heap_id(), PageState::Full, PageState::Partial));
+        self.slabs.insert_front(page)?;
 
         debug_assert!(self.slabs.contains(page_ptr));
         debug_assert!(!self.full_slabs.contains(page_ptr));
+        Ok(())
     }
 
     /// Tries to allocate a block of memory with respect to the `layout`.
@@ -193,21 +413,33 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
     ///
     /// # Arguments
     ///  * `sc_layout`: This is not the original layout but adjusted for the
-    ///     SCAllocator size (>= original).
-    fn try_allocate_from_pagelist(&mut self, sc_layout: Layout) -> *mut u8 {
+    ///    SCAllocator size (>= original).
+    ///
+    /// Fails if the metadata of a partial page is corrupted
+    /// (we don't follow its links).
+    fn try_allocate_from_pagelist(&mut self, sc_layout: Layout) -> Result<*mut u8, &'static str> {
         // TODO: Do we really need to check multiple slab pages (due to alignment)
         // If not we can get away with a singly-linked list and have 8 more bytes
         // for the bitfield in an ObjectPage.
 
-        for slab_page in self.slabs.iter_mut() {
-            let ptr = slab_page.allocate(sc_layout);
+        // Start at a random partial page (to make heap grooming harder),
+        // and wrap around to the pages before it.
+        #[cfg(feature = "hardened")]
+        let start = (self.rng().next_u64() % core::cmp::max(self.slabs.elements, 1) as u64) as usize;
+        #[cfg(not(feature = "hardened"))]
+        let start = 0;
+
+        for slab_page in self.slabs.iter_mut().skip(start).chain(self.slabs.iter_mut().take(start)) {
+            // Don't follow the links of a corrupted page
+            slab_page.check_integrity()?;
+            let ptr = self.allocate_in_page(slab_page, sc_layout);
             if !ptr.is_null() {
                 if slab_page.is_full() {
-                    // trace!("move {:p} partial -> full", slab_page);
-                    self.move_partial_to_full(slab_page);
+                    self.move_partial_to_full(slab_page)?;
                 }
                 self.allocation_count += 1;
-                return ptr;
+                self.free_slot_count -= 1;
+                return Ok(ptr);
             } else {
                 continue;
             }
@@ -218,7 +450,7 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
         //     self.check_page_assignments();
         // }
 
-        ptr::null_mut()
+        Ok(ptr::null_mut())
     }
 
     pub fn heap_id(&self) -> Option<usize> {
@@ -237,11 +469,19 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
 
     /// removes all of the pages from the lists of `allocator` and adds them to this allocator.
     pub fn merge(&mut self, allocator: &mut SCAllocator<'a, P>, heap_id: usize) -> Result<(), &'static str> {
+        // Quarantined objects must be released before their page moves to this allocator.
+        #[cfg(feature = "quarantine")]
+        allocator.flush_quarantine();
+        #[cfg(feature = "hooks")]
+        let pages = allocator.pages();
+
         while !allocator.empty_slabs.is_empty() {
-            match allocator.remove_empty() {
+            match allocator.remove_empty()? {
                 Some(new_head) =>{
+                    hook!(allocator, on_page_return(new_head.data_addr(), allocator.size, new_head.heap_id()));
                     new_head.set_heap_id(heap_id);
-                    self.empty_slabs.insert_front(new_head)
+                    self.free_slot_count += self.obj_per_page;
+                    self.empty_slabs.insert_front(new_head)?
                 }
                 None => {
                     break;
@@ -250,10 +490,12 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
         }
 
         while !allocator.slabs.is_empty() {
-            match allocator.remove_partial() {
+            match allocator.remove_partial()? {
                 Some(new_head) =>{
+                    hook!(allocator, on_page_return(new_head.data_addr(), allocator.size, new_head.heap_id()));
                     new_head.set_heap_id(heap_id);
-                    self.slabs.insert_front(new_head)
+                    self.free_slot_count += new_head.free_slots();
+                    self.slabs.insert_front(new_head)?
                 }
                 None => {
                     break;
@@ -262,10 +504,11 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
         }
 
         while !allocator.full_slabs.is_empty() {
-            match allocator.remove_full() {
+            match allocator.remove_full()? {
                 Some(new_head) =>{
+                    hook!(allocator, on_page_return(new_head.data_addr(), allocator.size, new_head.heap_id()));
                     new_head.set_heap_id(heap_id);
-                    self.full_slabs.insert_front(new_head)
+                    self.full_slabs.insert_front(new_head)?
                 }
                 None => {
                     break;
@@ -273,44 +516,203 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
             }
         }
 
+        #[cfg(feature = "hooks")]
+        {
+            if pages > 0 {
+                hook!(self, on_merge(pages, self.size, heap_id));
+            }
+        }
+
         Ok(())
 
     }
 
-    /// Creates an allocable page given a MappedPages object and returns a reference to the allocable page.
-    /// The MappedPages object is stored within the metadata of the allocable page.
-    fn create_allocable_page(mp: MappedPages, heap_id: usize) -> Result<&'a mut P, &'static str> {
-        let vaddr = mp.start_address().value();
+    /// Moves up to `empty_pages` empty pages and up to `partial_pages` partially used pages
+    /// of this allocator into `allocator`, stamping them with `heap_id`.
+    ///
+    /// This is the opposite of `merge`. Returns the number of pages that were moved.
+    pub fn split_into(
+        &mut self,
+        allocator: &mut SCAllocator<'a, P>,
+        heap_id: usize,
+        empty_pages: usize,
+        partial_pages: usize,
+    ) -> Result<usize, &'static str> {
+        debug_assert_eq!(self.size, allocator.size, "Splitting into a different size class");
+        // Quarantined objects must be released before their page moves to `allocator`.
+        #[cfg(feature = "quarantine")]
+        self.flush_quarantine();
+        let mut moved = 0;
+
+        for _ in 0..empty_pages {
+            match self.remove_empty()? {
+                Some(page) => {
+                    hook!(self, on_page_return(page.data_addr(), self.size, page.heap_id()));
+                    page.set_heap_id(heap_id);
+                    allocator.free_slot_count += allocator.obj_per_page;
+                    allocator.empty_slabs.insert_front(page)?;
+                    moved += 1;
+                }
+                None => break,
+            }
+        }
+
+        for _ in 0..partial_pages {
+            match self.remove_partial()? {
+                Some(page) => {
+                    hook!(self, on_page_return(page.data_addr(), self.size, page.heap_id()));
+                    page.set_heap_id(heap_id);
+                    allocator.free_slot_count += page.free_slots();
+                    allocator.slabs.insert_front(page)?;
+                    moved += 1;
+                }
+                None => break,
+            }
+        }
+
+        #[cfg(feature = "hooks")]
+        {
+            if moved > 0 {
+                hook!(allocator, on_merge(moved, allocator.size, heap_id));
+            }
+        }
 
-        // create page and store the MappedPages object
-        let page = P::new(mp, heap_id)?;
-        let page_ref: &'a mut P = unsafe { core::mem::transmute(vaddr) } ; // not unsafe because the allocable page was only create by a mapped page that fit the criteria
-        unsafe { (page_ref as *mut P).write(page); }
+        Ok(moved)
+    }
 
-        Ok(page_ref) 
+    /// Creates an allocable page given a MappedPages object and returns a reference to the allocable page.
+    /// The MappedPages object is stored within the metadata of the allocable page.
+    pub(crate) fn create_allocable_page(mp: MappedPages, heap_id: usize) -> Result<&'a mut P, &'static str> {
+        P::create(mp, heap_id)
     }
 
     /// Refill the SCAllocator
     pub fn refill(&mut self, mp: MappedPages, heap_id: usize) -> Result<(), &'static str> {
         let page = Self::create_allocable_page(mp, heap_id)?;
-        page.bitfield_mut().initialize(self.size, P::SIZE - P::METADATA_SIZE);
+        hook!(self, on_refill(page.data_addr(), self.size, heap_id));
+        self.insert_empty_page(page)
+    }
+
+    /// Adds an empty page (e.g., one that was used by another size class before)
+    /// to this allocator.
+    ///
+    /// The bitfield of the page is re-initialized for our object size.
+    pub(crate) fn insert_empty_page(&mut self, page: &'a mut P) -> Result<(), &'static str> {
+        // The tags (if any) follow the last slot.
+        page.bitfield_mut().initialize(slot_size(self.size), self.obj_per_page * slot_size(self.size));
+        #[cfg(feature = "red-zones")]
+        page.fill_red_zones(self.size, self.obj_per_page);
+        #[cfg(feature = "poison")]
+        page.poison_slots(self.size, self.obj_per_page);
         *page.prev() = Rawlink::none();
         *page.next() = Rawlink::none();
+        page.seal();
         // trace!("adding page to SCAllocator {:p}", page);
-        self.insert_empty(page);
+        self.insert_empty(page)?;
+        self.free_slot_count += self.obj_per_page;
+        Ok(())
+    }
+
+    /// The number of pages in this allocator (empty, partial and full).
+    pub fn pages(&self) -> usize {
+        self.empty_slabs.elements + self.slabs.elements + self.full_slabs.elements
+    }
 
+    /// The number of objects that are currently allocated from this allocator.
+    ///
+    /// This walks the list of partially used pages. Quarantined objects
+    /// were freed, so they don't count (even though they still hold their slots).
+    pub fn live_objects(&mut self) -> usize {
+        let mut live = self.full_slabs.elements * self.obj_per_page;
+        for slab_page in self.slabs.iter_mut() {
+            live += self.obj_per_page - slab_page.free_slots();
+        }
+        #[cfg(feature = "quarantine")]
+        {
+            live -= self.quarantine.len();
+        }
+        live
+    }
+
+    /// Records `tag` for the object at `ptr` (which was allocated from this allocator).
+    #[cfg(feature = "tagging")]
+    pub(crate) fn set_tag(&self, ptr: NonNull<u8>, tag: AllocTag) -> Result<(), &'static str> {
+        let slab_page = Self::page_of(ptr)?;
+        let idx = (ptr.as_ptr() as usize - slab_page.data_addr()) / slot_size(self.size);
+        slab_page.tags_mut(self.obj_per_page)[idx] = tag;
         Ok(())
     }
 
+    /// Calls `f` for every page that holds objects (i.e., partial and full pages).
+    ///
+    /// The slots of quarantined objects are still marked allocated in these pages
+    /// (see `quarantined`).
+    pub(crate) fn for_each_used_page<F: FnMut(&P)>(&mut self, mut f: F) {
+        for slab_page in self.slabs.iter_mut().chain(self.full_slabs.iter_mut()) {
+            f(slab_page);
+        }
+    }
+
+    /// Calls `f` with the page and slot index of every live object,
+    /// skipping the slots of quarantined objects.
+    pub(crate) fn for_each_live_object<F: FnMut(&P, usize)>(&mut self, mut f: F) {
+        let obj_per_page = self.obj_per_page;
+        #[cfg(feature = "quarantine")]
+        let (quarantine, slot_size) = (&self.quarantine, slot_size(self.size));
+        for slab_page in self.slabs.iter_mut().chain(self.full_slabs.iter_mut()) {
+            for slot in 0..obj_per_page {
+                if !slab_page.bitfield().is_allocated(slot) {
+                    continue;
+                }
+                #[cfg(feature = "quarantine")]
+                if quarantine.contains(slab_page.data_addr() + slot * slot_size) {
+                    continue;
+                }
+                f(slab_page, slot);
+            }
+        }
+    }
+
+    /// Iterates over the addresses of the objects in the quarantine.
+    #[cfg(feature = "quarantine")]
+    pub(crate) fn quarantined(&self) -> impl Iterator<Item = usize> + '_ {
+        self.quarantine.iter()
+    }
+
+    /// Takes every page (empty, partial and full) out of this allocator and adds it to `pages`.
+    ///
+    /// Objects that are still allocated in these pages become invalid
+    /// once the pages are unmapped. The quarantine is emptied first.
+    pub(crate) fn drain_into(&mut self, pages: &mut ReclaimedPages<'a, P>) -> Result<(), &'static str> {
+        #[cfg(feature = "quarantine")]
+        self.flush_quarantine();
+        loop {
+            let page = match self.remove_empty()? {
+                Some(page) => page,
+                None => match self.remove_partial()? {
+                    Some(page) => page,
+                    None => match self.remove_full()? {
+                        Some(page) => page,
+                        None => return Ok(()),
+                    },
+                },
+            };
+            hook!(self, on_page_return(page.data_addr(), self.size, page.heap_id()));
+            pages.push(page)?;
+        }
+    }
+
     /// Returns an empty page from the allocator if available.
     /// It removes the MappedPages object from the heap pages where it is stored.
-    pub fn retrieve_empty_page(&mut self) -> Option<MappedPages> {
-        match self.remove_empty(){
+    ///
+    /// Fails if the metadata of the empty pages is corrupted.
+    pub fn retrieve_empty_page(&mut self) -> Result<Option<MappedPages>, &'static str> {
+        match self.take_empty_page()? {
             Some(page) => {
-                Some(page.retrieve_mapped_pages())
+                Ok(Some(page.retrieve_mapped_pages()))
             }
             None => {
-                None
+                Ok(None)
             }
         }
     }
@@ -323,51 +725,49 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
     /// The function may also move around pages between lists
     /// (empty -> partial or partial -> full).
     pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, &'static str> {
-        // trace!(
-        //     "SCAllocator({}) is trying to allocate {:?}, {}",
-        //     self.size,
-        //     layout, 
-        //     P::SIZE - CACHE_LINE_SIZE
-        // );
         assert!(layout.size() <= self.size);
-        assert!(self.size <= (P::SIZE - CACHE_LINE_SIZE));
-        let new_layout = unsafe { Layout::from_size_align_unchecked(self.size, layout.align()) };
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
+        let new_layout = self.slot_layout(layout);
         assert!(new_layout.size() >= layout.size());
 
         let ptr = {
             // Try to allocate from partial slabs,
             // if we fail check if we have empty pages and allocate from there
-            let ptr = self.try_allocate_from_pagelist(new_layout);
+            let ptr = self.try_allocate_from_pagelist(new_layout)?;
             if ptr.is_null() && self.empty_slabs.head.is_some() {
                 // Re-try allocation in empty page
-                let empty_page = self.empty_slabs.pop().expect("We checked head.is_some()");
+                let empty_page = self.empty_slabs.pop()?.ok_or(OUT_OF_MEMORY)?;
                 debug_assert!(!self.empty_slabs.contains(empty_page));
 
-                let ptr = empty_page.allocate(layout);
+                let ptr = self.allocate_in_page(empty_page, new_layout);
                 debug_assert!(!ptr.is_null(), "Allocation must have succeeded here.");
+                self.free_slot_count -= 1;
 
-                // trace!(
-                //     "move {:p} empty -> partial empty count {}",
-                //     empty_page,
-                //     self.empty_slabs.elements
-                // );
                 // Move empty page to partial pages
-                self.insert_partial_slab(empty_page);
+                // (or full pages, if a page only holds one object).
+                if empty_page.is_full() {
+                    hook!(self, on_page_transition(empty_page.data_addr(), self.size, empty_page.heap_id(), PageState::Empty, PageState::Full));
+                    self.full_slabs.insert_front(empty_page)?;
+                } else {
+                    hook!(self, on_page_transition(empty_page.data_addr(), self.size, empty_page.heap_id(), PageState::Empty, PageState::Partial));
+                    self.insert_partial_slab(empty_page)?;
+                }
                 ptr
             } else {
                 ptr
             }
         };
 
-        let res = NonNull::new(ptr).ok_or("AllocationError::OutOfMemory");
+        let res = NonNull::new(ptr).ok_or(OUT_OF_MEMORY);
 
-        // if !ptr.is_null() {
-        //     trace!(
-        //         "SCAllocator({}) allocated ptr=0x{:x}",
-        //         self.size,
-        //         ptr as usize
-        //     );
-        // }
+        // A corrupted object stays allocated so it won't be handed out again.
+        #[cfg(feature = "poison")]
+        let res = res.and_then(|ptr| self.check_poison(ptr).map(|_| ptr));
+
+        #[cfg(feature = "hooks")]
+        if let Ok(ptr) = res {
+            hook!(self, on_allocate(ptr.as_ptr() as usize, layout, self.size, Self::heap_id_of(ptr)));
+        }
 
         res
     }
@@ -379,36 +779,256 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
     /// or full -> partial lists.
     pub fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str> {
         assert!(layout.size() <= self.size);
-        assert!(self.size <= (P::SIZE - CACHE_LINE_SIZE));
-        // trace!(
-        //     "SCAllocator({}) is trying to deallocate ptr = {:p} layout={:?} P.size= {}",
-        //     self.size,
-        //     ptr,
-        //     layout,
-        //     P::SIZE
-        // );
-
-        let page = (ptr.as_ptr() as usize) & !(P::SIZE - 1) as usize;
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
 
         // Figure out which page we are on and construct a reference to it
         // TODO: The linked list will have another &mut reference
-        let slab_page = unsafe { mem::transmute::<VAddr, &'a mut P>(page) };
-        let new_layout = unsafe { Layout::from_size_align_unchecked(self.size, layout.align()) };
+        let slab_page = Self::page_of(ptr)?;
+        let new_layout = self.object_layout(layout);
+
+        // On error (e.g., a corrupted red zone) the object stays allocated.
+        // The hook is called once the object leaves the quarantine.
+        #[cfg(feature = "quarantine")]
+        {
+            slab_page.retire(ptr, new_layout)?;
+            self.quarantine_object(ptr, layout);
+        }
+
+        #[cfg(not(feature = "quarantine"))]
+        {
+            let slab_page_was_full = slab_page.is_full();
+            slab_page.deallocate(ptr, new_layout)?;
+            self.free_slot_count += 1;
+            hook!(self, on_deallocate(ptr.as_ptr() as usize, layout, self.size, slab_page.heap_id()));
+            self.update_page_list_after_free(slab_page, slab_page_was_full)?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts the (retired) object at `ptr` into the quarantine,
+    /// releasing the oldest quarantined objects if there isn't enough room.
+    #[cfg(feature = "quarantine")]
+    fn quarantine_object(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        while self.quarantine.is_full(self.size) {
+            match self.quarantine.pop() {
+                Some((addr, old_layout)) => self.release_retired(addr, old_layout),
+                None => {
+                    // The quarantine is disabled
+                    self.release_retired(ptr.as_ptr() as usize, layout);
+                    return;
+                }
+            }
+        }
+        self.quarantine.push(ptr.as_ptr() as usize, layout);
+    }
+
+    /// Marks the slot of a retired object at `addr` (freed with `layout`) free.
+    #[cfg(feature = "quarantine")]
+    #[cfg_attr(not(feature = "hooks"), allow(unused_variables))]
+    fn release_retired(&mut self, addr: usize, layout: Layout) {
+        let ptr = unsafe { NonNull::new_unchecked(addr as *mut u8) };
+        let slab_page = match Self::page_of(ptr) {
+            Ok(page) => page,
+            // The page can't be trusted anymore, so the object is leaked.
+            Err(_) => return,
+        };
 
         let slab_page_was_full = slab_page.is_full();
-        let ret = slab_page.deallocate(ptr, new_layout);
-        debug_assert!(ret.is_ok(), "Slab page deallocate won't fail at the moment");
+        let idx = P::slot_index(ptr, slot_size(self.size));
+        slab_page.bitfield().clear_bit(idx);
+        self.free_slot_count += 1;
+        hook!(self, on_deallocate(addr, layout, self.size, slab_page.heap_id()));
+        // If a neighbour of the page is corrupted the page stays on its list,
+        // the next operation on that list reports the corruption.
+        let _ = self.update_page_list_after_free(slab_page, slab_page_was_full);
+    }
+
+    /// Changes how many freed objects are kept in the quarantine.
+    #[cfg(feature = "quarantine")]
+    pub fn set_quarantine_limit(&mut self, limit: QuarantineLimit) {
+        self.quarantine.set_limit(limit);
+        while self.quarantine.over_limit(self.size) {
+            let (addr, layout) = self.quarantine.pop().expect("Quarantine is over its limit");
+            self.release_retired(addr, layout);
+        }
+    }
+
+    /// Releases all objects in the quarantine (their slots can be reused again).
+    #[cfg(feature = "quarantine")]
+    pub fn flush_quarantine(&mut self) {
+        while let Some((addr, layout)) = self.quarantine.pop() {
+            self.release_retired(addr, layout);
+        }
+    }
 
+    /// Moves `slab_page` to the right list after objects were freed in it.
+    fn update_page_list_after_free(&mut self, slab_page: &'a mut P, slab_page_was_full: bool) -> Result<(), &'static str> {
         if slab_page.is_empty(self.obj_per_page) {
-            // We need to move it from self.slabs -> self.empty_slabs
-            // trace!("move {:p} partial -> empty", slab_page);
-            self.move_to_empty(slab_page);
+            if slab_page_was_full {
+                // We need to move it from self.full_slabs -> self.empty_slabs
+                self.move_full_to_empty(slab_page)
+            } else {
+                // We need to move it from self.slabs -> self.empty_slabs
+                self.move_to_empty(slab_page)
+            }
         } else if slab_page_was_full {
             // We need to move it from self.full_slabs -> self.slabs
-            // trace!("move {:p} full -> partial", slab_page);
-            self.move_full_to_partial(slab_page);
+            self.move_full_to_partial(slab_page)
+        } else {
+            Ok(())
         }
+    }
+
+    /// Allocates up to `objects.len()` blocks of memory described by `layout`
+    /// and writes their addresses to `objects`.
+    ///
+    /// Pages are filled one after the other (claiming many bits of a page's bitfield
+    /// at once), and each page is moved between lists at most once.
+    ///
+    /// Returns the number of objects allocated (these are at the start of `objects`).
+    /// If fewer objects than requested could be allocated, `BulkMode::AllOrNothing`
+    /// frees all of them again (without reporting them to the hooks or quarantining them)
+    /// and returns an out-of-memory error.
+    ///
+    /// The slots are always the lowest free ones of a page, even with the
+    /// `hardened` feature.
+    pub fn allocate_bulk(
+        &mut self,
+        layout: Layout,
+        objects: &mut [MaybeUninit<NonNull<u8>>],
+        mode: BulkMode,
+    ) -> Result<usize, &'static str> {
+        assert!(layout.size() <= self.size);
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
+        let new_layout = self.slot_layout(layout);
+
+        let mut allocated = 0;
 
-        ret
+        // Fill up partial slabs first
+        for slab_page in self.slabs.iter_mut() {
+            if allocated == objects.len() {
+                break;
+            }
+            // Don't follow the links of a corrupted page
+            slab_page.check_integrity()?;
+            let claimed = slab_page.allocate_many(new_layout, &mut objects[allocated..]);
+            allocated += claimed;
+            self.allocation_count += claimed;
+            self.free_slot_count -= claimed;
+            if slab_page.is_full() {
+                self.move_partial_to_full(slab_page)?;
+            }
+        }
+
+        // Then take empty pages
+        while allocated < objects.len() {
+            let empty_page = match self.empty_slabs.pop()? {
+                Some(page) => page,
+                None => break,
+            };
+            let claimed = empty_page.allocate_many(new_layout, &mut objects[allocated..]);
+            allocated += claimed;
+            self.allocation_count += claimed;
+            self.free_slot_count -= claimed;
+            if empty_page.is_full() {
+                hook!(self, on_page_transition(empty_page.data_addr(), self.size, empty_page.heap_id(), PageState::Empty, PageState::Full));
+                self.full_slabs.insert_front(empty_page)?;
+            } else if empty_page.is_empty(self.obj_per_page) {
+                // Nothing fit (e.g., due to alignment)
+                self.insert_empty(empty_page)?;
+                break;
+            } else {
+                hook!(self, on_page_transition(empty_page.data_addr(), self.size, empty_page.heap_id(), PageState::Empty, PageState::Partial));
+                self.insert_partial_slab(empty_page)?;
+            }
+        }
+
+        // Corrupted objects stay allocated, we give back the others and fail.
+        #[cfg(feature = "poison")]
+        {
+            let mut intact = 0;
+            for i in 0..allocated {
+                let ptr = unsafe { objects[i].assume_init() };
+                if self.check_poison(ptr).is_ok() {
+                    objects.swap(intact, i);
+                    intact += 1;
+                }
+            }
+            if intact < allocated {
+                let intact_objects = unsafe {
+                    &*(&objects[..intact] as *const [MaybeUninit<NonNull<u8>>] as *const [NonNull<u8>])
+                };
+                self.release_bulk(intact_objects, layout, false)?;
+                return Err(CORRUPTED);
+            }
+        }
+
+        if allocated < objects.len() && mode == BulkMode::AllOrNothing {
+            let allocated_objects = unsafe {
+                &*(&objects[..allocated] as *const [MaybeUninit<NonNull<u8>>] as *const [NonNull<u8>])
+            };
+            self.release_bulk(allocated_objects, layout, false)?;
+            return Err(OUT_OF_MEMORY);
+        }
+
+        #[cfg(feature = "hooks")]
+        for obj in &objects[..allocated] {
+            let ptr = unsafe { obj.assume_init() };
+            hook!(self, on_allocate(ptr.as_ptr() as usize, layout, self.size, Self::heap_id_of(ptr)));
+        }
+
+        Ok(allocated)
+    }
+
+    /// Deallocates many objects described by `layout` at once.
+    ///
+    /// Objects that lie on the same page should be next to each other in `objects`
+    /// (e.g., as returned by `allocate_bulk`), every such run of objects only
+    /// moves its page between lists once.
+    pub fn deallocate_bulk(&mut self, objects: &[NonNull<u8>], layout: Layout) -> Result<(), &'static str> {
+        assert!(layout.size() <= self.size);
+        assert!(slot_size(self.size) <= (P::SIZE - P::METADATA_SIZE));
+        if cfg!(feature = "quarantine") {
+            // Every object goes through the quarantine on its own.
+            return objects.iter().try_for_each(|obj| self.deallocate(*obj, layout));
+        }
+        self.release_bulk(objects, layout, true)
+    }
+
+    /// Marks the slots of `objects` free right away (bypassing the quarantine), moving
+    /// every page once per run of objects on it.
+    ///
+    /// The `on_deallocate` hook is only called if `notify` is set, objects that
+    /// were never handed out (e.g., when `allocate_bulk` rolls back) aren't reported.
+    #[cfg_attr(not(feature = "hooks"), allow(unused_variables))]
+    fn release_bulk(&mut self, objects: &[NonNull<u8>], layout: Layout, notify: bool) -> Result<(), &'static str> {
+        let new_layout = self.object_layout(layout);
+
+        let mut i = 0;
+        while i < objects.len() {
+            let page = (objects[i].as_ptr() as usize) & !(P::SIZE - 1);
+            let slab_page = Self::page_of(objects[i])?;
+            let slab_page_was_full = slab_page.is_full();
+
+            let mut freed = Ok(());
+            while i < objects.len() && (objects[i].as_ptr() as usize) & !(P::SIZE - 1) == page {
+                freed = slab_page.deallocate(objects[i], new_layout);
+                if freed.is_err() {
+                    break;
+                }
+                self.free_slot_count += 1;
+                if notify {
+                    hook!(self, on_deallocate(objects[i].as_ptr() as usize, layout, self.size, slab_page.heap_id()));
+                }
+                i += 1;
+            }
+
+            // The objects freed before an error still move the page to the right list.
+            self.update_page_list_after_free(slab_page, slab_page_was_full)?;
+            freed?;
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,19 @@
+//! The interface between the allocators and whatever provides their backing memory.
+
+use crate::*;
+
+/// A source of backing memory for the allocators in this crate.
+///
+/// This is typically implemented by the kernel's frame allocator (or a wrapper
+/// around it) and used by the allocators to refill themselves, and to hand
+/// pages back once they are no longer needed.
+///
+/// Pages handed out by `allocate_page` must be `ObjectPage8k::SIZE` bytes large,
+/// aligned to `ObjectPage8k::SIZE` and writable (this is checked by `ObjectPage8k::new`).
+pub trait PageSource: Sync {
+    /// Allocates a new page, returns `None` if the source is out of memory.
+    fn allocate_page(&self) -> Option<MappedPages>;
+
+    /// Gives a page (previously returned by `allocate_page`) back to the source.
+    fn release_page(&self, mp: MappedPages);
+}
@@ -0,0 +1,22 @@
+//! Tagging objects with the subsystem (or task) that owns them, to account memory per tag.
+//!
+//! Every page keeps one tag per slot, so tagging doesn't need any memory besides
+//! the pages themselves. Both `ObjectPage8k` and `PageDescriptor8k` keep the tags
+//! behind their last slot (see `AllocablePage::TAG_SIZE`): a size class gets as many
+//! slots as fit together with their tags, which costs small objects space that is
+//! left over anyway and makes `ZoneAllocator::MAX_ALLOC_SIZE` one byte smaller.
+
+/// A tag recorded for an object, e.g., the id of the subsystem that allocated it.
+pub type AllocTag = u8;
+
+/// The tag of objects that were allocated without one.
+pub const UNTAGGED: AllocTag = 0;
+
+/// The live objects with one tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TagUsage {
+    /// Number of live objects.
+    pub objects: usize,
+    /// Bytes used by the live objects (the size of their size classes).
+    pub bytes: usize,
+}
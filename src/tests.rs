@@ -1,156 +1,70 @@
-use env_logger;
-use rand;
 use std::alloc;
 use std::alloc::Layout;
+use std::boxed::Box;
 use std::collections::HashSet;
-use std::mem::{size_of, transmute};
-use std::prelude::v1::*;
+use std::mem::size_of;
+use std::vec::Vec;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::hosted::{create_mapping, EntryFlags};
 use crate::*;
 use test::Bencher;
 
-/// A simple page allocator based on GlobalAlloc (for testing purposes).
+/// A page source backed by the hosted memory stand-in (for testing purposes)
+/// that counts the pages which weren't given back yet.
 struct Pager {
-    base_pages: HashSet<*mut u8>, // probably should be hash-tables
-    large_pages: HashSet<*mut u8>,
+    allocated: AtomicUsize,
 }
 
-unsafe impl Send for Pager {}
-unsafe impl Sync for Pager {}
-
 impl Pager {
-    pub fn new() -> Pager {
+    pub const fn new() -> Pager {
         Pager {
-            base_pages: HashSet::with_capacity(1024),
-            large_pages: HashSet::with_capacity(128),
+            allocated: AtomicUsize::new(0),
         }
     }
-}
 
-impl Pager {
     pub fn currently_allocated(&self) -> usize {
-        self.base_pages.len() + self.large_pages.len()
-    }
-
-    fn alloc_page(&mut self, page_size: usize) -> Option<*mut u8> {
-        let r =
-            unsafe { std::alloc::alloc(Layout::from_size_align(page_size, page_size).unwrap()) };
-
-        if !r.is_null() {
-            match page_size {
-                BASE_PAGE_SIZE => self.base_pages.insert(r),
-                LARGE_PAGE_SIZE => self.large_pages.insert(r),
-                _ => unreachable!("invalid page-size supplied"),
-            };
-            Some(r)
-        } else {
-            None
-        }
-    }
-
-    fn dealloc_page(&mut self, ptr: *mut u8, page_size: usize) {
-        let layout = match page_size {
-            BASE_PAGE_SIZE => {
-                assert!(
-                    self.base_pages.contains(&ptr),
-                    "Trying to deallocate invalid base-page"
-                );
-                self.base_pages.remove(&ptr);
-                Layout::from_size_align(BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap()
-            }
-            LARGE_PAGE_SIZE => {
-                assert!(
-                    self.large_pages.contains(&ptr),
-                    "Trying to deallocate invalid large-page"
-                );
-                self.large_pages.remove(&ptr);
-                Layout::from_size_align(LARGE_PAGE_SIZE, LARGE_PAGE_SIZE).unwrap()
-            }
-            _ => unreachable!("invalid page-size supplied"),
-        };
-
-        unsafe { std::alloc::dealloc(ptr, layout) };
+        self.allocated.load(Ordering::SeqCst)
     }
 }
 
-trait PageProvider<'a>: Send {
-    fn allocate_page(&mut self) -> Option<&'a mut ObjectPage<'a>>;
-    fn release_page(&mut self, page: &'a mut ObjectPage<'a>);
-
-    fn allocate_large_page(&mut self) -> Option<&'a mut LargeObjectPage<'a>>;
-    fn release_large_page(&mut self, page: &'a mut LargeObjectPage<'a>);
-}
-
-impl<'a> PageProvider<'a> for Pager {
-    /// Allocates a new ObjectPage from the system.
-    ///
-    /// Uses `mmap` to map a page and casts it to a ObjectPage.
-    fn allocate_page(&mut self) -> Option<&'a mut ObjectPage<'a>> {
-        self.alloc_page(BASE_PAGE_SIZE)
-            .map(|r| unsafe { transmute(r as usize) })
-    }
-
-    /// Release a ObjectPage back to the system.slab_page
-    ///
-    /// Uses `munmap` to release the page back to the OS.
-    fn release_page(&mut self, p: &'a mut ObjectPage<'a>) {
-        self.dealloc_page(p as *const ObjectPage as *mut u8, BASE_PAGE_SIZE);
+impl PageSource for Pager {
+    fn allocate_page(&self) -> Option<MappedPages> {
+        let mp = create_mapping(ObjectPage8k::SIZE, EntryFlags::WRITABLE).ok()?;
+        self.allocated.fetch_add(1, Ordering::SeqCst);
+        Some(mp)
     }
 
-    /// Allocates a new ObjectPage from the system.
-    ///
-    /// Uses `mmap` to map a page and casts it to a ObjectPage.
-    fn allocate_large_page(&mut self) -> Option<&'a mut LargeObjectPage<'a>> {
-        self.alloc_page(LARGE_PAGE_SIZE)
-            .map(|r| unsafe { transmute(r as usize) })
-    }
-
-    /// Release a LargeObjectPage back to the system.slab_page
-    ///
-    /// Uses `munmap` to release the page back to the OS.
-    fn release_large_page(&mut self, p: &'a mut LargeObjectPage<'a>) {
-        self.dealloc_page(p as *const LargeObjectPage as *mut u8, LARGE_PAGE_SIZE);
+    fn release_page(&self, mp: MappedPages) {
+        self.allocated.fetch_sub(1, Ordering::SeqCst);
+        drop(mp);
     }
 }
 
 #[test]
 fn check_size() {
     assert_eq!(
-        BASE_PAGE_SIZE as usize,
-        size_of::<ObjectPage>(),
-        "ObjectPage should be exactly the size of a single page."
-    );
-
-    assert_eq!(
-        LARGE_PAGE_SIZE as usize,
-        size_of::<LargeObjectPage>(),
-        "LargeObjectPage should be exactly the size of a large-page."
+        ObjectPage8k::SIZE,
+        size_of::<ObjectPage8k>(),
+        "ObjectPage8k should be exactly the size of a single page."
     );
 }
 
 #[test]
 fn test_mmap_allocator() {
-    let mut mmap = Pager::new();
+    let mmap = Pager::new();
 
     match mmap.allocate_page() {
-        Some(sp) => {
-            sp.bitfield.initialize(8, BASE_PAGE_SIZE - 80);
+        Some(mp) => {
+            let sp = ObjectPage8k::create(mp, 0).expect("Can't create an ObjectPage8k");
+            sp.bitfield_mut().initialize(8, ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE);
             assert!(!sp.is_full(), "Got empty slab");
-            assert!(sp.is_empty(6 * 64), "Got empty slab");
-            mmap.release_page(sp)
-        }
-        None => panic!("failed to allocate ObjectPage"),
-    }
-
-    match mmap.allocate_large_page() {
-        Some(lp) => {
-            lp.bitfield.initialize(8, LARGE_PAGE_SIZE - 80);
-            assert!(!lp.is_full(), "Got empty slab");
-            assert!(lp.is_empty(8 * 64), "Got empty slab");
-            mmap.release_large_page(lp)
+            assert!(sp.is_empty(8 * 64), "Got empty slab");
+            mmap.release_page(sp.retrieve_mapped_pages())
         }
-        None => panic!("failed to allocate LargeObjectPage"),
+        None => panic!("failed to allocate ObjectPage8k"),
     }
+    assert_eq!(mmap.currently_allocated(), 0);
 }
 
 macro_rules! test_sc_allocation {
@@ -158,151 +72,13 @@ macro_rules! test_sc_allocation {
         #[test]
         fn $test() {
             let _ = env_logger::try_init();
-            let mut mmap = Pager::new();
-            {
-                let mut sa: SCAllocator<$type> = SCAllocator::new($size);
-                let alignment = $alignment;
-
-                let mut objects: Vec<NonNull<u8>> = Vec::new();
-                let mut vec: Vec<(usize, &mut [usize; $size / 8])> = Vec::new();
-                let layout = Layout::from_size_align($size, alignment).unwrap();
-
-                for _ in 0..$allocations {
-                    loop {
-                        match sa.allocate(layout) {
-                            // Allocation was successful
-                            Ok(nptr) => {
-                                unsafe {
-                                    vec.push((rand::random::<usize>(), transmute(nptr.as_ptr())))
-                                };
-                                objects.push(nptr);
-                                break;
-                            }
-                            // Couldn't allocate need to refill first
-                            Err(AllocationError::OutOfMemory) => {
-                                let page = mmap.allocate_page().unwrap();
-                                unsafe {
-                                    sa.refill(page);
-                                }
-                            }
-                            // Unexpected errors
-                            Err(AllocationError::InvalidLayout) => unreachable!("Unexpected error"),
-                        }
-                    }
-                }
-
-                // Write the objects with a random pattern
-                for item in vec.iter_mut() {
-                    let (pattern, ref mut obj) = *item;
-                    assert!(obj.len() == $size / 8);
-                    for i in 0..obj.len() {
-                        obj[i] = pattern;
-                    }
-                }
-
-                for item in vec.iter() {
-                    let (pattern, ref obj) = *item;
-                    for i in 0..obj.len() {
-                        assert_eq!(
-                            obj[i], pattern,
-                            "No two allocations point to the same memory."
-                        );
-                    }
-                }
-
-                // Make sure we can correctly deallocate:
-                let pages_allocated = sa.slabs.elements;
-
-                // Deallocate all the objects
-                for item in objects.iter_mut() {
-                    sa.deallocate(*item, layout).expect("Can't deallocate");
-                }
-
-                objects.clear();
-                sa.check_page_assignments();
-
-                // then allocate everything again,
-                for _ in 0..$allocations {
-                    loop {
-                        match sa.allocate(layout) {
-                            // Allocation was successful
-                            Ok(nptr) => {
-                                unsafe {
-                                    vec.push((rand::random::<usize>(), transmute(nptr.as_ptr())))
-                                };
-                                objects.push(nptr);
-                                break;
-                            }
-                            // Couldn't allocate need to refill first
-                            Err(AllocationError::OutOfMemory) => {
-                                let page = mmap.allocate_page().unwrap();
-                                unsafe {
-                                    sa.refill(page);
-                                }
-                            }
-                            // Unexpected errors
-                            Err(AllocationError::InvalidLayout) => unreachable!("Unexpected error"),
-                        }
-                    }
-                }
-
-                // and make sure we do not request more pages than what we had previously
-                // println!("{} {}", pages_allocated, sa.slabs.elements);
-                assert_eq!(
-                    pages_allocated, sa.slabs.elements,
-                    "Did not use more memory for 2nd allocation run."
-                );
-
-                // Deallocate everything once more
-                for item in objects.iter_mut() {
-                    sa.deallocate(*item, layout).expect("Can't deallocate");
-                }
-
-                // Drain the slab-allocator and give unused pages back to the OS
-                sa.check_page_assignments();
-                while let Some(page) = sa.empty_slabs.pop() {
-                    mmap.release_page(page);
-                }
-            }
-
-            // Check that we released everything to our page allocator:
-            assert_eq!(
-                mmap.currently_allocated(),
-                0,
-                "Released all pages to the underlying memory manager."
-            );
-        }
-    };
-}
-
-test_sc_allocation!(op_512_size8_alignment1, 8, 1, 512, ObjectPage);
-test_sc_allocation!(op_4096_size8_alignment8, 8, 8, 4096, ObjectPage);
-test_sc_allocation!(op_500_size8_alignment64, 8, 64, 500, ObjectPage);
-test_sc_allocation!(op_4096_size12_alignment1, 12, 1, 4096, ObjectPage);
-test_sc_allocation!(op_4096_size13_alignment1, 13, 1, 4096, ObjectPage);
-test_sc_allocation!(op_2000_size14_alignment1, 14, 1, 2000, ObjectPage);
-test_sc_allocation!(op_4096_size15_alignment1, 15, 1, 4096, ObjectPage);
-test_sc_allocation!(op_8000_size16_alignment1, 16, 1, 8000, ObjectPage);
-test_sc_allocation!(op_1024_size24_alignment1, 24, 1, 1024, ObjectPage);
-test_sc_allocation!(op_3090_size32_alignment1, 32, 1, 3090, ObjectPage);
-test_sc_allocation!(op_4096_size64_alignment1, 64, 1, 4096, ObjectPage);
-test_sc_allocation!(op_1000_size512_alignment1, 512, 1, 1000, ObjectPage);
-test_sc_allocation!(op_4096_size1024_alignment1, 1024, 1, 4096, ObjectPage);
-test_sc_allocation!(op_10_size2048_alignment1, 2048, 1, 10, ObjectPage);
-test_sc_allocation!(op_10000_size512_alignment1, 512, 1, 10000, ObjectPage);
-
-macro_rules! lop_allocation {
-    ($test:ident, $size:expr, $alignment:expr, $allocations:expr, $type:ty) => {
-        #[test]
-        fn $test() {
-            let _ = env_logger::try_init();
-            let mut mmap = Pager::new();
+            let mmap = Pager::new();
             {
                 let mut sa: SCAllocator<$type> = SCAllocator::new($size);
                 let alignment = $alignment;
 
                 let mut objects: Vec<NonNull<u8>> = Vec::new();
-                let mut vec: Vec<(usize, &mut [usize; $size / 8])> = Vec::new();
+                let mut vec: Vec<(u8, &mut [u8; $size])> = Vec::new();
                 let layout = Layout::from_size_align($size, alignment).unwrap();
 
                 for _ in 0..$allocations {
@@ -310,21 +86,18 @@ macro_rules! lop_allocation {
                         match sa.allocate(layout) {
                             // Allocation was successful
                             Ok(nptr) => {
-                                unsafe {
-                                    vec.push((rand::random::<usize>(), transmute(nptr.as_ptr())))
-                                };
+                                let obj = unsafe { &mut *(nptr.as_ptr() as *mut [u8; $size]) };
+                                vec.push((rand::random::<u8>(), obj));
                                 objects.push(nptr);
                                 break;
                             }
                             // Couldn't allocate need to refill first
-                            Err(AllocationError::OutOfMemory) => {
-                                let page = mmap.allocate_large_page().unwrap();
-                                unsafe {
-                                    sa.refill(page);
-                                }
+                            Err(OUT_OF_MEMORY) => {
+                                let mp = mmap.allocate_page().unwrap();
+                                sa.refill(mp, 0).expect("Can't refill");
                             }
                             // Unexpected errors
-                            Err(AllocationError::InvalidLayout) => unreachable!("Unexpected error"),
+                            Err(e) => unreachable!("Unexpected error: {}", e),
                         }
                     }
                 }
@@ -332,7 +105,7 @@ macro_rules! lop_allocation {
                 // Write the objects with a random pattern
                 for item in vec.iter_mut() {
                     let (pattern, ref mut obj) = *item;
-                    assert!(obj.len() == $size / 8);
+                    assert!(obj.len() == $size);
                     for i in 0..obj.len() {
                         obj[i] = pattern;
                     }
@@ -347,17 +120,20 @@ macro_rules! lop_allocation {
                         );
                     }
                 }
+                vec.clear();
 
                 // Make sure we can correctly deallocate:
-                let pages_allocated = sa.slabs.elements;
+                let pages_allocated = sa.pages();
 
                 // Deallocate all the objects
                 for item in objects.iter_mut() {
                     sa.deallocate(*item, layout).expect("Can't deallocate");
                 }
+                #[cfg(feature = "quarantine")]
+                sa.flush_quarantine();
 
                 objects.clear();
-                sa.check_page_assignments();
+                sa.verify().expect("Page lists are inconsistent");
 
                 // then allocate everything again,
                 for _ in 0..$allocations {
@@ -365,29 +141,23 @@ macro_rules! lop_allocation {
                         match sa.allocate(layout) {
                             // Allocation was successful
                             Ok(nptr) => {
-                                unsafe {
-                                    vec.push((rand::random::<usize>(), transmute(nptr.as_ptr())))
-                                };
                                 objects.push(nptr);
                                 break;
                             }
                             // Couldn't allocate need to refill first
-                            Err(AllocationError::OutOfMemory) => {
-                                let page = mmap.allocate_large_page().unwrap();
-                                unsafe {
-                                    sa.refill(page);
-                                }
+                            Err(OUT_OF_MEMORY) => {
+                                let mp = mmap.allocate_page().unwrap();
+                                sa.refill(mp, 0).expect("Can't refill");
                             }
                             // Unexpected errors
-                            Err(AllocationError::InvalidLayout) => unreachable!("Unexpected error"),
+                            Err(e) => unreachable!("Unexpected error: {}", e),
                         }
                     }
                 }
 
                 // and make sure we do not request more pages than what we had previously
-                // println!("{} {}", pages_allocated, sa.slabs.elements);
                 assert_eq!(
-                    pages_allocated, sa.slabs.elements,
+                    pages_allocated, sa.pages(),
                     "Did not use more memory for 2nd allocation run."
                 );
 
@@ -395,12 +165,15 @@ macro_rules! lop_allocation {
                 for item in objects.iter_mut() {
                     sa.deallocate(*item, layout).expect("Can't deallocate");
                 }
+                #[cfg(feature = "quarantine")]
+                sa.flush_quarantine();
 
                 // Drain the slab-allocator and give unused pages back to the OS
-                sa.check_page_assignments();
-                while let Some(page) = sa.empty_slabs.pop() {
-                    mmap.release_large_page(page);
+                sa.verify().expect("Page lists are inconsistent");
+                while let Some(mp) = sa.retrieve_empty_page().unwrap() {
+                    mmap.release_page(mp);
                 }
+                assert_eq!(sa.pages(), 0);
             }
 
             // Check that we released everything to our page allocator:
@@ -413,13 +186,22 @@ macro_rules! lop_allocation {
     };
 }
 
-lop_allocation!(lop_4096_3, 8, 1, 1024, LargeObjectPage);
-lop_allocation!(lop_4096_12, 4096, 4096, 2048, LargeObjectPage);
-lop_allocation!(lop_4096_13, 1 << 13, 4096, 4096, LargeObjectPage);
-lop_allocation!(lop_4096_14, 1 << 14, 4096, 4096, LargeObjectPage);
-lop_allocation!(lop_4096_15, 1 << 15, 4096, 4096, LargeObjectPage);
-lop_allocation!(lop_4096_16, 1 << 16, 4096, 4096, LargeObjectPage);
-lop_allocation!(lop_4096_17, 1 << 17, 4096, 4096, LargeObjectPage);
+test_sc_allocation!(op_512_size8_alignment1, 8, 1, 512, ObjectPage8k);
+test_sc_allocation!(op_4096_size8_alignment8, 8, 8, 4096, ObjectPage8k);
+test_sc_allocation!(op_500_size8_alignment64, 8, 64, 500, ObjectPage8k);
+test_sc_allocation!(op_4096_size12_alignment1, 12, 1, 4096, ObjectPage8k);
+test_sc_allocation!(op_4096_size13_alignment1, 13, 1, 4096, ObjectPage8k);
+test_sc_allocation!(op_2000_size14_alignment1, 14, 1, 2000, ObjectPage8k);
+test_sc_allocation!(op_4096_size15_alignment1, 15, 1, 4096, ObjectPage8k);
+test_sc_allocation!(op_8000_size16_alignment1, 16, 1, 8000, ObjectPage8k);
+test_sc_allocation!(op_1024_size24_alignment1, 24, 1, 1024, ObjectPage8k);
+test_sc_allocation!(op_3090_size32_alignment1, 32, 1, 3090, ObjectPage8k);
+test_sc_allocation!(op_4096_size64_alignment1, 64, 1, 4096, ObjectPage8k);
+test_sc_allocation!(op_1000_size512_alignment1, 512, 1, 1000, ObjectPage8k);
+test_sc_allocation!(op_4096_size1024_alignment1, 1024, 1, 4096, ObjectPage8k);
+test_sc_allocation!(op_10_size2048_alignment1, 2048, 1, 10, ObjectPage8k);
+test_sc_allocation!(op_10000_size512_alignment1, 512, 1, 10000, ObjectPage8k);
+test_sc_allocation!(op_2048_size4096_alignment4096, 4096, 4096, 2048, ObjectPage8k);
 
 #[test]
 #[should_panic]
@@ -428,22 +210,22 @@ fn invalid_alignment() {
 }
 
 #[test]
-fn test_readme() -> Result<(), AllocationError> {
+fn test_readme() -> Result<(), &'static str> {
     let object_size = 12;
     let alignment = 4;
     let layout = Layout::from_size_align(object_size, alignment).unwrap();
 
     // We need something that can provide backing memory
-    // (4 KiB and 2 MiB pages) to our ZoneAllocator
+    // (8 KiB pages) to our ZoneAllocator
     // (see tests.rs for a dummy implementation).
-    let mut pager = Pager::new();
+    let pager = Pager::new();
     let page = pager.allocate_page().expect("Can't allocate a page");
 
     let mut zone: ZoneAllocator = Default::default();
     // Prematurely fill the ZoneAllocator with memory.
     // Alternatively, the allocate call would return an
     // error which we can capture to refill on-demand.
-    unsafe { zone.refill(layout, page)? };
+    zone.refill(layout, page, 0)?;
 
     let allocated = zone.allocate(layout)?;
     zone.deallocate(allocated, layout)?;
@@ -452,38 +234,36 @@ fn test_readme() -> Result<(), AllocationError> {
 }
 
 #[test]
-fn test_readme2() -> Result<(), AllocationError> {
+fn test_readme2() -> Result<(), &'static str> {
     let object_size = 10;
     let alignment = 8;
     let layout = Layout::from_size_align(object_size, alignment).unwrap();
 
     // We need something that can provide backing memory
-    // (4 KiB and 2 MiB pages) to our ZoneAllocator
+    // (8 KiB pages) to our SCAllocator
     // (see tests.rs for a dummy implementation).
-    let mut pager = Pager::new();
+    let pager = Pager::new();
     let page = pager.allocate_page().expect("Can't allocate a page");
 
-    let mut sa: SCAllocator<ObjectPage> = SCAllocator::new(object_size);
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(object_size);
     // Prematurely fill the SCAllocator with memory.
     // Alternatively, the allocate call would return an
     // error which we can capture to refill on-demand.
-    unsafe { sa.refill(page) };
+    sa.refill(page, 0)?;
 
     sa.allocate(layout)?;
     Ok(())
 }
 
 #[test]
-fn test_bug1() -> Result<(), AllocationError> {
+fn test_bug1() -> Result<(), &'static str> {
     let _ = env_logger::try_init();
 
-    let mut mmap = Pager::new();
+    let mmap = Pager::new();
     let page = mmap.allocate_page();
 
-    let mut sa: SCAllocator<ObjectPage> = SCAllocator::new(8);
-    unsafe {
-        sa.refill(page.unwrap());
-    }
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(8);
+    sa.refill(page.unwrap(), 0)?;
 
     let ptr1 = sa.allocate(Layout::from_size_align(1, 1).unwrap())?;
     let ptr2 = sa.allocate(Layout::from_size_align(2, 1).unwrap())?;
@@ -496,14 +276,12 @@ fn test_bug1() -> Result<(), AllocationError> {
 fn slabmalloc_allocate_deallocate(b: &mut Bencher) {
     let _ = env_logger::try_init();
 
-    let mut mmap = Pager::new();
-    let mut sa: SCAllocator<ObjectPage> = SCAllocator::new(8);
+    let mmap = Pager::new();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(8);
     let layout = Layout::from_size_align(8, 1).unwrap();
 
     let page = mmap.allocate_page();
-    unsafe {
-        sa.refill(page.unwrap());
-    }
+    sa.refill(page.unwrap(), 0).expect("Can't refill");
 
     let ptr = sa.allocate(layout).expect("Can't allocate");
     test::black_box(ptr);
@@ -518,13 +296,11 @@ fn slabmalloc_allocate_deallocate(b: &mut Bencher) {
 fn slabmalloc_allocate_deallocate_big(b: &mut Bencher) {
     let _ = env_logger::try_init();
 
-    let mut mmap = Pager::new();
-    let mut sa: SCAllocator<ObjectPage> = SCAllocator::new(512);
+    let mmap = Pager::new();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(512);
 
     let page = mmap.allocate_page();
-    unsafe {
-        sa.refill(page.unwrap());
-    }
+    sa.refill(page.unwrap(), 0).expect("Can't refill");
 
     let layout = Layout::from_size_align(512, 1).unwrap();
     let ptr = sa.allocate(layout).expect("Can't allocate");
@@ -565,75 +341,83 @@ fn jemalloc_allocate_deallocate_big(b: &mut Bencher) {
 
 #[test]
 pub fn check_first_fit() {
-    let op: ObjectPage = Default::default();
+    let op = Box::<ObjectPage8k>::default();
     let layout = Layout::from_size_align(8, 8).unwrap();
     println!("{:?}", op.first_fit(layout));
 }
 
 #[test]
 fn list_pop() {
-    let mut op1: ObjectPage = Default::default();
-    let op1_ptr = &op1 as *const ObjectPage<'_>;
-    let mut op2: ObjectPage = Default::default();
-    let op2_ptr = &op2 as *const ObjectPage<'_>;
-    let mut op3: ObjectPage = Default::default();
-    let op3_ptr = &op3 as *const ObjectPage<'_>;
-    let mut op4: ObjectPage = Default::default();
-    let op4_ptr = &op4 as *const ObjectPage<'_>;
-
-    let mut list: PageList<ObjectPage> = PageList::new();
-    list.insert_front(&mut op1);
-    list.insert_front(&mut op2);
-    list.insert_front(&mut op3);
+    let mmap = Pager::new();
+    let mut pages: Vec<&mut ObjectPage8k> = (0..4)
+        .map(|_| ObjectPage8k::create(mmap.allocate_page().unwrap(), 0).unwrap())
+        .collect();
+    let ptrs: Vec<*const ObjectPage8k> = pages.iter().map(|p| &**p as *const ObjectPage8k).collect();
+    let (op1_ptr, op2_ptr, op3_ptr, op4_ptr) = (ptrs[0], ptrs[1], ptrs[2], ptrs[3]);
+    let op4 = pages.pop().unwrap();
+
+    let mut list: PageList<ObjectPage8k> = PageList::new();
+    for page in pages {
+        list.insert_front(page).unwrap();
+    }
 
     assert!(list.contains(op1_ptr));
     assert!(list.contains(op2_ptr));
     assert!(list.contains(op3_ptr));
     assert!(!list.contains(op4_ptr));
 
-    let popped = list.pop();
-    assert_eq!(popped.unwrap() as *const ObjectPage, op3_ptr);
+    let popped = list.pop().unwrap();
+    assert_eq!(popped.unwrap() as *const ObjectPage8k, op3_ptr);
     assert!(!list.contains(op3_ptr));
 
-    let popped = list.pop();
-    assert_eq!(popped.unwrap() as *const ObjectPage, op2_ptr);
+    let popped = list.pop().unwrap();
+    assert_eq!(popped.unwrap() as *const ObjectPage8k, op2_ptr);
     assert!(!list.contains(op2_ptr));
 
-    list.insert_front(&mut op4);
+    list.insert_front(op4).unwrap();
     assert!(list.contains(op4_ptr));
-    let popped = list.pop();
-    assert_eq!(popped.unwrap() as *const ObjectPage, op4_ptr);
+    let popped = list.pop().unwrap();
+    assert_eq!(popped.unwrap() as *const ObjectPage8k, op4_ptr);
     assert!(!list.contains(op4_ptr));
 
-    let popped = list.pop();
-    assert_eq!(popped.unwrap() as *const ObjectPage, op1_ptr);
+    let popped = list.pop().unwrap();
+    assert_eq!(popped.unwrap() as *const ObjectPage8k, op1_ptr);
     assert!(!list.contains(op1_ptr));
 
-    let popped = list.pop();
+    let popped = list.pop().unwrap();
     assert!(popped.is_none());
 
     assert!(!list.contains(op1_ptr));
     assert!(!list.contains(op2_ptr));
     assert!(!list.contains(op3_ptr));
     assert!(!list.contains(op4_ptr));
+
+    for ptr in ptrs {
+        let page = unsafe { &mut *(ptr as *mut ObjectPage8k) };
+        mmap.release_page(page.retrieve_mapped_pages());
+    }
+    assert_eq!(mmap.currently_allocated(), 0);
 }
 
 #[test]
 pub fn iter_empty_list() {
-    let mut new_head1: ObjectPage = Default::default();
+    let mmap = Pager::new();
+    let new_head1 = ObjectPage8k::create(mmap.allocate_page().unwrap(), 0).unwrap();
     let mut l = PageList::new();
-    l.insert_front(&mut new_head1);
+    l.insert_front(new_head1).unwrap();
     for _p in l.iter_mut() {}
+    mmap.release_page(l.pop().unwrap().unwrap().retrieve_mapped_pages());
 }
 
 #[test]
 pub fn check_is_full_8() {
     let _r = env_logger::try_init();
     let layout = Layout::from_size_align(8, 1).unwrap();
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
 
-    let mut page: ObjectPage = Default::default();
-    page.bitfield.initialize(8, BASE_PAGE_SIZE - 80);
-    let obj_per_page = core::cmp::min((BASE_PAGE_SIZE - 80) / 8, 8 * 64);
+    let mut page = Box::<ObjectPage8k>::default();
+    page.bitfield_mut().initialize(8, capacity);
+    let obj_per_page = core::cmp::min(capacity / 8, 8 * 64);
 
     let mut allocs = 0;
     loop {
@@ -661,10 +445,11 @@ pub fn check_is_full_8() {
 #[test]
 pub fn check_is_full_512() {
     let _r = env_logger::try_init();
-    let mut page: ObjectPage = Default::default();
-    page.bitfield.initialize(512, BASE_PAGE_SIZE - 80);
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let mut page = Box::<ObjectPage8k>::default();
+    page.bitfield_mut().initialize(512, capacity);
     let layout = Layout::from_size_align(512, 1).unwrap();
-    let obj_per_page = core::cmp::min((BASE_PAGE_SIZE - 80) / 512, 6 * 64);
+    let obj_per_page = core::cmp::min(capacity / 512, 8 * 64);
 
     let mut allocs = 0;
     loop {
@@ -674,10 +459,1905 @@ pub fn check_is_full_512() {
 
         allocs += 1;
 
-        if allocs < (BASE_PAGE_SIZE - 80) / 512 {
+        if allocs < obj_per_page {
             assert!(!page.is_full());
             assert!(!page.is_empty(obj_per_page));
         }
     }
     assert!(page.is_full());
 }
+
+/// Many threads allocate from and free to the same `ConcurrentSCAllocator`,
+/// no object must be handed out twice and all pages are empty afterwards.
+#[test]
+pub fn concurrent_sc_allocator_many_threads() {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    const THREADS: usize = 8;
+    const PAGES: usize = 4;
+    const SIZE: usize = 64;
+
+    static PAGER: Pager = Pager::new();
+    let layout = Layout::from_size_align(SIZE, 8).unwrap();
+
+    let sa: ConcurrentSCAllocator<'static, ObjectPage8k<'static>> = ConcurrentSCAllocator::new(SIZE);
+    for _ in 0..PAGES {
+        sa.refill(PAGER.allocate_page().unwrap(), 0).expect("Can't refill");
+    }
+    let sa = Arc::new(sa);
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let sa = sa.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let mut objects = Vec::new();
+                // Take objects until all pages are full, then give back half of them
+                // (while the others still allocate) and take some more.
+                for round in 0..2 {
+                    while let Ok(ptr) = sa.allocate(layout) {
+                        unsafe { ptr::write_bytes(ptr.as_ptr(), thread as u8, SIZE) };
+                        objects.push(ptr);
+                    }
+                    if round == 0 {
+                        for ptr in objects.split_off(objects.len() / 2) {
+                            sa.deallocate(ptr, layout).expect("Can't deallocate");
+                        }
+                    }
+                }
+
+                // Everybody holds their objects at the same time now,
+                // and nobody else wrote to ours.
+                barrier.wait();
+                let addrs: Vec<usize> = objects.iter().map(|ptr| ptr.as_ptr() as usize).collect();
+                for ptr in &objects {
+                    let obj = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), SIZE) };
+                    assert!(obj.iter().all(|b| *b == thread as u8), "{:p} was handed out twice", ptr);
+                }
+                barrier.wait();
+                for ptr in objects {
+                    sa.deallocate(ptr, layout).expect("Can't deallocate");
+                }
+                addrs
+            })
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    for handle in handles {
+        for addr in handle.join().unwrap() {
+            assert!(seen.insert(addr), "Object {:#x} was held by two threads", addr);
+        }
+    }
+
+    let mut sa = Arc::try_unwrap(sa).ok().expect("Threads still hold the allocator");
+    assert_eq!(sa.pages(), PAGES);
+    while let Some(mp) = sa.retrieve_empty_page().unwrap() {
+        PAGER.release_page(mp);
+    }
+    assert_eq!(sa.pages(), 0, "All objects were freed");
+    assert_eq!(PAGER.currently_allocated(), 0);
+}
+
+
+/// Many threads claim slots from the same bitfield at once,
+/// no slot must be handed out twice.
+#[test]
+pub fn concurrent_claim_no_double_allocation() {
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 100;
+
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let obj_per_page = core::cmp::min(capacity / 8, 8 * 64);
+
+    for _ in 0..ROUNDS {
+        let mut bitfield: [AtomicU64; 8] = Default::default();
+        bitfield.initialize(8, capacity);
+        let bitfield = Arc::new(bitfield);
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let bitfield = bitfield.clone();
+                thread::spawn(move || {
+                    let mut claimed = Vec::new();
+                    while let Some((idx, _addr)) =
+                        bitfield.claim_first_fit(0, layout, ObjectPage8k::SIZE, ObjectPage8k::METADATA_SIZE)
+                    {
+                        claimed.push(idx);
+                    }
+                    claimed
+                })
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for handle in handles {
+            for idx in handle.join().unwrap() {
+                assert!(seen.insert(idx), "Slot {} was handed out twice", idx);
+            }
+        }
+        assert_eq!(seen.len(), obj_per_page, "All slots were claimed exactly once");
+        assert!(bitfield.is_full());
+    }
+}
+
+#[test]
+pub fn claim_many_from_bitfield() {
+    use std::mem::MaybeUninit;
+    use std::sync::atomic::AtomicU64;
+
+    let layout = Layout::from_size_align(64, 64).unwrap();
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let obj_per_page = core::cmp::min(capacity / 64, 8 * 64);
+
+    let mut bitfield: [AtomicU64; 8] = Default::default();
+    bitfield.initialize(64, capacity);
+
+    // Claim a batch that spans more than one bitmap word.
+    let mut objects = [MaybeUninit::<NonNull<u8>>::uninit(); 100];
+    let claimed = bitfield.claim_many(0x10000, layout, ObjectPage8k::SIZE, ObjectPage8k::METADATA_SIZE, &mut objects);
+    assert_eq!(claimed, 100);
+
+    let mut seen = HashSet::new();
+    for obj in &objects[..claimed] {
+        let addr = unsafe { obj.assume_init() }.as_ptr() as usize;
+        assert_eq!(addr % layout.align(), 0);
+        assert!(seen.insert(addr), "Object {:#x} was handed out twice", addr);
+        assert!(bitfield.is_allocated((addr - 0x10000) / 64));
+    }
+
+    // The rest of the page can be claimed, but not more.
+    let mut rest = [MaybeUninit::<NonNull<u8>>::uninit(); 512];
+    let claimed = bitfield.claim_many(0x10000, layout, ObjectPage8k::SIZE, ObjectPage8k::METADATA_SIZE, &mut rest);
+    assert_eq!(claimed, obj_per_page - 100);
+    assert!(bitfield.is_full());
+}
+
+/// Red zones keep power-of-two objects aligned to their size, which leaves room
+/// for a single object of the 2048 and 4096 byte classes per page.
+#[cfg(feature = "red-zones")]
+#[test]
+pub fn red_zone_slot_sizes() {
+    assert_eq!(slot_size(64), 128);
+    assert_eq!(slot_size(24), 24 + RED_ZONE_SIZE, "24 byte objects only need an 8 byte alignment");
+    assert_eq!(slot_size(4096), 4096 + RED_ZONE_SIZE);
+    for size in &[2048, 4096] {
+        let sa: SCAllocator<ObjectPage8k> = SCAllocator::new(*size);
+        assert_eq!(sa.obj_per_page, 1);
+    }
+}
+
+/// Writing one byte past an object is caught when the object is freed.
+#[cfg(feature = "red-zones")]
+#[test]
+pub fn red_zone_catches_overflow() {
+    let page_layout = Layout::from_size_align(ObjectPage8k::SIZE, ObjectPage8k::SIZE).unwrap();
+    let page = unsafe { &mut *(alloc::alloc_zeroed(page_layout) as *mut ObjectPage8k) };
+
+    let size = 64;
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let obj_per_page = core::cmp::min(capacity / slot_size(size), 8 * 64);
+    page.bitfield_mut().initialize(slot_size(size), capacity);
+    page.fill_red_zones(size, obj_per_page);
+
+    let slot_layout = Layout::from_size_align(slot_size(size), 8).unwrap();
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let a = NonNull::new(page.allocate(slot_layout)).unwrap();
+    let b = NonNull::new(page.allocate(slot_layout)).unwrap();
+    assert_eq!(a.as_ptr() as usize % size, 0, "Objects stay aligned to their size");
+    assert_eq!(b.as_ptr() as usize % size, 0, "Objects stay aligned to their size");
+    assert!(page.verify_red_zones(size, obj_per_page).is_ok());
+
+    // Well-behaved object
+    unsafe { ptr::write_bytes(a.as_ptr(), 0xff, size) };
+    assert!(page.deallocate(a, layout).is_ok());
+
+    // Off by one
+    unsafe { ptr::write_bytes(b.as_ptr(), 0xff, size + 1) };
+    assert!(page.verify_red_zones(size, obj_per_page).is_err());
+    assert!(page.deallocate(b, layout).is_err());
+    assert!(page.bitfield().is_allocated(1), "Corrupted object stays allocated");
+
+    unsafe { alloc::dealloc(page as *mut ObjectPage8k as *mut u8, page_layout) };
+}
+
+/// Writing to an object after it was freed is caught when its slot is handed out again.
+#[cfg(feature = "poison")]
+#[test]
+pub fn poison_catches_use_after_free() {
+    let page_layout = Layout::from_size_align(ObjectPage8k::SIZE, ObjectPage8k::SIZE).unwrap();
+    let page = unsafe { &mut *(alloc::alloc_zeroed(page_layout) as *mut ObjectPage8k) };
+
+    let size = 32;
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let obj_per_page = core::cmp::min(capacity / slot_size(size), 8 * 64);
+    page.bitfield_mut().initialize(slot_size(size), capacity);
+    #[cfg(feature = "red-zones")]
+    page.fill_red_zones(size, obj_per_page);
+    page.poison_slots(size, obj_per_page);
+
+    let slot_layout = Layout::from_size_align(slot_size(size), 8).unwrap();
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let a = NonNull::new(page.allocate(slot_layout)).unwrap();
+    assert!(page.check_poison(a, size).is_ok(), "Fresh slots are poisoned");
+
+    unsafe { ptr::write_bytes(a.as_ptr(), 0, size) };
+    assert!(page.deallocate(a, layout).is_ok());
+    assert!(page.check_poison(a, size).is_ok(), "Freed slots are poisoned");
+
+    // Use after free, lowest index is re-used first
+    unsafe { *a.as_ptr().add(7) = 1 };
+    let b = NonNull::new(page.allocate(slot_layout)).unwrap();
+    assert_eq!(a, b);
+    assert!(page.check_poison(b, size).is_err());
+
+    unsafe { alloc::dealloc(page as *mut ObjectPage8k as *mut u8, page_layout) };
+}
+
+#[cfg(feature = "quarantine")]
+#[test]
+pub fn quarantine_is_fifo_and_bounded() {
+    let mut q = Quarantine::new();
+    q.set_limit(QuarantineLimit::Bytes(3 * 64));
+    assert!(q.is_empty());
+
+    let layout = Layout::from_size_align(48, 16).unwrap();
+    for addr in &[0x1000, 0x1040, 0x1080] {
+        assert!(!q.is_full(64));
+        q.push(*addr, layout);
+    }
+    assert!(q.is_full(64));
+    assert!(!q.is_full(32), "Byte limit depends on the object size");
+    assert_eq!(q.pop(), Some((0x1000, layout)), "Oldest object leaves first");
+    q.push(0x10c0, layout);
+
+    q.set_limit(QuarantineLimit::Objects(1));
+    assert!(q.over_limit(64));
+    assert_eq!(q.pop(), Some((0x1040, layout)));
+    assert_eq!(q.pop(), Some((0x1080, layout)));
+    assert!(!q.over_limit(64));
+    assert_eq!(q.pop(), Some((0x10c0, layout)));
+    assert_eq!(q.pop(), None);
+
+    // Limits are capped at the capacity
+    q.set_limit(QuarantineLimit::Objects(usize::MAX));
+    for i in 0..QUARANTINE_CAPACITY {
+        q.push(i * 8, layout);
+    }
+    assert!(q.is_full(8));
+    assert_eq!(q.len(), QUARANTINE_CAPACITY);
+}
+
+/// Randomized claims from many threads honour alignment and hand out every slot once.
+#[cfg(feature = "hardened")]
+#[test]
+pub fn random_claim_aligned_no_double_allocation() {
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 8;
+    const BASE: usize = 0x10000;
+
+    // Only every other 24 byte block is 16 byte aligned
+    let layout = Layout::from_size_align(24, 16).unwrap();
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let blocks = core::cmp::min(capacity / 24, 8 * 64);
+    let aligned_blocks = (0..blocks).filter(|idx| (BASE + idx * 24).is_multiple_of(16)).count();
+
+    let mut bitfield: [AtomicU64; 8] = Default::default();
+    bitfield.initialize(24, capacity);
+    let bitfield = Arc::new(bitfield);
+    let rng = Arc::new(XorShiftRng::new(0xdead_beef));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let bitfield = bitfield.clone();
+            let rng = rng.clone();
+            thread::spawn(move || {
+                let mut claimed = Vec::new();
+                while let Some((idx, addr)) = bitfield.claim_random_fit(
+                    BASE,
+                    layout,
+                    ObjectPage8k::SIZE,
+                    ObjectPage8k::METADATA_SIZE,
+                    rng.next_u64(),
+                ) {
+                    assert_eq!(addr % layout.align(), 0);
+                    assert_eq!(addr, BASE + idx * 24);
+                    claimed.push(idx);
+                }
+                claimed
+            })
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    for handle in handles {
+        for idx in handle.join().unwrap() {
+            assert!(seen.insert(idx), "Slot {} was handed out twice", idx);
+        }
+    }
+    assert_eq!(seen.len(), aligned_blocks, "All aligned slots were claimed exactly once");
+}
+
+#[cfg(feature = "hardened")]
+#[test]
+pub fn random_claim_is_not_lowest_first() {
+    use std::sync::atomic::AtomicU64;
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    let rng = XorShiftRng::new(42);
+
+    let mut first_slots = HashSet::new();
+    for _ in 0..16 {
+        let mut bitfield: [AtomicU64; 8] = Default::default();
+        bitfield.initialize(64, capacity);
+        let (idx, _addr) = bitfield
+            .claim_random_fit(0, layout, ObjectPage8k::SIZE, ObjectPage8k::METADATA_SIZE, rng.next_u64())
+            .unwrap();
+        first_slots.insert(idx);
+    }
+    assert!(first_slots.len() > 1, "Slots are picked at random");
+}
+
+/// The concurrent allocator picks random slots too, and reseeding restarts a generator.
+#[cfg(feature = "hardened")]
+#[test]
+pub fn concurrent_allocator_picks_random_slots() {
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let rng = XorShiftRng::new(7);
+
+    let mut first_offsets = HashSet::new();
+    for _ in 0..8 {
+        let mut sa: ConcurrentSCAllocator<ObjectPage8k> = ConcurrentSCAllocator::new(64);
+        sa.set_rng(&rng);
+        sa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+        let ptr = sa.allocate(layout).unwrap();
+        first_offsets.insert(ptr.as_ptr() as usize % ObjectPage8k::SIZE);
+        sa.deallocate(ptr, layout).unwrap();
+        pager.release_page(sa.retrieve_empty_page().unwrap().unwrap());
+    }
+    assert!(first_offsets.len() > 1, "Slots are picked at random");
+
+    let reseeded = XorShiftRng::new(1);
+    reseeded.reseed(7);
+    let fresh = XorShiftRng::new(7);
+    assert_eq!(reseeded.next_u64(), fresh.next_u64());
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// With out-of-line descriptors 8 byte objects fill the whole page.
+#[test]
+pub fn descriptor_bitfield_covers_whole_page() {
+    use std::sync::atomic::AtomicU64;
+
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let mut bitfield: [AtomicU64; 16] = Default::default();
+    bitfield.initialize(8, PageDescriptor8k::SIZE - PageDescriptor8k::METADATA_SIZE);
+
+    let mut claimed = 0;
+    while let Some((idx, addr)) = bitfield.claim_first_fit(
+        0x20000,
+        layout,
+        PageDescriptor8k::SIZE,
+        PageDescriptor8k::METADATA_SIZE,
+    ) {
+        assert_eq!(addr, 0x20000 + idx * 8);
+        claimed += 1;
+    }
+    assert_eq!(claimed, PageDescriptor8k::MAX_OBJECTS);
+    assert_eq!(claimed * 8, PageDescriptor8k::SIZE);
+    assert!(bitfield.is_full());
+}
+
+/// `PageDescriptor8k` pages can be refilled, allocated from and freed to
+/// through an `SCAllocator` and a `ZoneAllocator`.
+///
+/// This is the only test that registers the (process-wide) descriptor table.
+#[test]
+pub fn descriptor_pages_allocate_and_deallocate() {
+    const PAGES: usize = 4;
+    // The mapping is aligned to its (power-of-two) size, so every page is 8 KiB aligned.
+    let heap = create_mapping(PAGES * PageDescriptor8k::SIZE, EntryFlags::WRITABLE).unwrap();
+    let heap_start = heap.start_address().value();
+    let table = create_mapping(PAGES * size_of::<PageDescriptor8k>(), EntryFlags::WRITABLE).unwrap();
+    register_descriptor_table(heap_start, table).unwrap();
+    let page = |i: usize| unsafe {
+        MappedPages::from_raw_parts(heap_start + i * PageDescriptor8k::SIZE, PageDescriptor8k::SIZE, EntryFlags::WRITABLE)
+    };
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut sa: SCAllocator<PageDescriptor8k> = SCAllocator::new(64);
+    sa.refill(page(0), 3).unwrap();
+
+    let mut objects = Vec::new();
+    while let Ok(ptr) = sa.allocate(layout) {
+        let addr = ptr.as_ptr() as usize;
+        assert!(addr >= heap_start && addr + 64 <= heap_start + PageDescriptor8k::SIZE);
+        assert_eq!(unsafe { PageDescriptor8k::heap_id_of(addr) }, Some(3));
+        objects.push(ptr);
+    }
+    assert_eq!(objects.len(), sa.obj_per_page);
+    #[cfg(not(any(feature = "red-zones", feature = "tagging")))]
+    assert_eq!(objects.len() * 64, PageDescriptor8k::SIZE);
+
+    for ptr in objects {
+        sa.deallocate(ptr, layout).unwrap();
+    }
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    let mp = sa.retrieve_empty_page().unwrap().expect("The page should be empty");
+    assert_eq!(mp.start_address().value(), heap_start);
+    // The descriptor was freed, the page can be refilled again.
+    sa.refill(mp, 3).unwrap();
+    assert!(sa.retrieve_empty_page().unwrap().is_some());
+
+    let mut zone: ZoneAllocator<PageDescriptor8k> = ZoneAllocator::new();
+    let big = Layout::from_size_align(3000, 8).unwrap();
+    zone.refill(layout, page(1), 5).unwrap();
+    zone.refill(big, page(2), 5).unwrap();
+    let small = zone.allocate(layout).unwrap();
+    let large = zone.allocate(big).unwrap();
+    assert_eq!(unsafe { PageDescriptor8k::heap_id_of(small.as_ptr() as usize) }, Some(5));
+    assert_eq!(unsafe { PageDescriptor8k::heap_id_of(large.as_ptr() as usize) }, Some(5));
+    assert_eq!(PageDescriptor8k::HEAP_ID_OFFSET, None);
+    // The tags live in the pages, so tagging doesn't grow the descriptor table.
+    assert!(size_of::<PageDescriptor8k>() <= 256);
+    #[cfg(feature = "tagging")]
+    {
+        let tagged = zone.allocate_tagged(layout, 7).unwrap();
+        let mut usage = [TagUsage::default(); 8];
+        zone.tag_usage(&mut usage);
+        assert_eq!((usage[UNTAGGED as usize].objects, usage[7].objects), (2, 1));
+        zone.deallocate(tagged, layout).unwrap();
+    }
+    zone.deallocate(small, layout).unwrap();
+    zone.deallocate(large, big).unwrap();
+    // Quarantined objects keep their pages in use.
+    #[cfg(not(feature = "quarantine"))]
+    {
+        assert!(zone.retrieve_empty_page().unwrap().is_some());
+        assert!(zone.retrieve_empty_page().unwrap().is_some());
+        assert!(zone.retrieve_empty_page().unwrap().is_none());
+    }
+    drop(zone);
+
+    // A locked zone works with descriptor pages as well.
+    struct HeapPages(std::sync::Mutex<Vec<MappedPages>>);
+    impl PageSource for HeapPages {
+        fn allocate_page(&self) -> Option<MappedPages> {
+            self.0.lock().unwrap().pop()
+        }
+
+        fn release_page(&self, mp: MappedPages) {
+            self.0.lock().unwrap().push(mp);
+        }
+    }
+    let source = HeapPages(std::sync::Mutex::new(vec![page(3)]));
+    let watermarks = Watermarks {
+        high_empty_pages: None,
+        low_free_slots: 0,
+    };
+    let locked: LockedZoneAllocator<Spinlock, PageDescriptor8k> = LockedZoneAllocator::new(&source, 6, watermarks);
+    let ptr = locked.allocate(layout).unwrap();
+    assert!(source.0.lock().unwrap().is_empty());
+    assert_eq!(unsafe { PageDescriptor8k::heap_id_of(ptr.as_ptr() as usize) }, Some(6));
+    locked.deallocate(ptr, layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    locked.with_zone(|zone| zone.flush_quarantine());
+    assert_eq!(locked.with_zone(|zone| zone.trim()), Ok(1));
+    assert_eq!(source.0.lock().unwrap().len(), 1);
+
+    // The descriptor table points into the heap, keep it mapped.
+    mem::forget(heap);
+}
+
+/// Every power-of-two alignment up to the page size is routed to a size class
+/// in which every slot satisfies it (with and without red zones).
+#[test]
+pub fn over_aligned_layouts_get_aligned_slots() {
+    use std::sync::atomic::AtomicU64;
+
+    const PAGE: usize = 0x40000;
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+
+    for align in (0..=13).map(|shift| 1 << shift) {
+        for size in &[1, 8, 24, 64, 100, 1000, 4096, <ZoneAllocator>::MAX_ALLOC_SIZE] {
+            let layout = Layout::from_size_align(*size, align).unwrap();
+            let class = match <ZoneAllocator>::get_slab(<ZoneAllocator>::class_size(layout)) {
+                Slab::Base(idx) => <ZoneAllocator>::BASE_ALLOC_SIZES[idx],
+                _ => panic!("{:?} is not served", layout),
+            };
+            assert!(class >= *size);
+            assert_eq!(<ZoneAllocator>::get_max_size(<ZoneAllocator>::class_size(layout)), Some(class));
+
+            // All slots of the class can be handed out for `layout`.
+            let obj_per_page = core::cmp::min(capacity / slot_size(class), 8 * 64);
+            let mut bitfield: [AtomicU64; 8] = Default::default();
+            bitfield.initialize(slot_size(class), capacity);
+            let sc_layout = Layout::from_size_align(slot_size(class), align).unwrap();
+
+            let mut claimed = 0;
+            while let Some((_idx, addr)) =
+                bitfield.claim_first_fit(PAGE, sc_layout, ObjectPage8k::SIZE, ObjectPage8k::METADATA_SIZE)
+            {
+                assert_eq!(addr % align, 0, "{:?} got a misaligned slot in class {}", layout, class);
+                claimed += 1;
+            }
+            assert_eq!(claimed, obj_per_page, "{:?} can't use all slots of class {}", layout, class);
+        }
+    }
+
+    // Nothing is aligned beyond the page
+    let layout = Layout::from_size_align(8, 2 * ObjectPage8k::SIZE).unwrap();
+    assert!(matches!(
+        <ZoneAllocator>::get_slab(<ZoneAllocator>::class_size(layout)),
+        Slab::Unsupported
+    ));
+}
+
+/// A zone hands out aligned objects for every power-of-two alignment up to the page size.
+#[test]
+pub fn zone_allocates_aligned_objects() {
+    let pager = Pager::new();
+    let mut zone: ZoneAllocator = ZoneAllocator::with_page_source(
+        &pager,
+        0,
+        Watermarks {
+            high_empty_pages: None,
+            low_free_slots: 0,
+        },
+    );
+
+    for align in (0..=13).map(|shift| 1 << shift) {
+        for size in &[1, 8, 24, 64, 100, 1000, 4096, <ZoneAllocator>::MAX_ALLOC_SIZE] {
+            let layout = Layout::from_size_align(*size, align).unwrap();
+            let objects: Vec<_> = (0..3)
+                .map(|_| zone.allocate(layout).unwrap_or_else(|e| panic!("Can't allocate {:?}: {}", layout, e)))
+                .collect();
+            for ptr in objects {
+                assert_eq!(ptr.as_ptr() as usize % align, 0, "{:?} got a misaligned object", layout);
+                unsafe { ptr::write_bytes(ptr.as_ptr(), 0xab, *size) };
+                zone.deallocate(ptr, layout).expect("Can't deallocate");
+            }
+        }
+    }
+
+    assert_eq!(zone.live_objects(), 0);
+}
+
+/// Zero-sized layouts get a dangling, well-aligned pointer from a zone without any pages.
+#[test]
+pub fn zero_sized_allocations_need_no_pages() {
+    let mut zone: ZoneAllocator = ZoneAllocator::new();
+
+    for align in (0..=13).map(|shift| 1 << shift) {
+        let layout = Layout::from_size_align(0, align).unwrap();
+        let ptr = zone.allocate(layout).expect("Can't allocate a zero-sized object");
+        assert_eq!(ptr.as_ptr() as usize, align);
+        zone.deallocate(ptr, layout).expect("Can't deallocate a zero-sized object");
+
+        let mut objects = [core::mem::MaybeUninit::uninit(); 4];
+        assert_eq!(zone.allocate_bulk(layout, &mut objects, BulkMode::AllOrNothing), Ok(objects.len()));
+
+        // Zero-sized objects can't be reallocated in place to a non-zero size (and vice versa).
+        let bigger = Layout::from_size_align(8, align).unwrap();
+        assert!(<ZoneAllocator>::fits_in_place(ptr, layout, layout));
+        assert!(!<ZoneAllocator>::fits_in_place(ptr, layout, bigger));
+    }
+    assert_eq!(zone.empty_pages(), 0);
+}
+
+/// Draining a zone without pages gives back nothing, and a drop policy doesn't touch it.
+#[test]
+pub fn drain_empty_zone() {
+    let mut zone: ZoneAllocator = ZoneAllocator::new();
+    assert_eq!(zone.drop_policy(), DropPolicy::Leak);
+    assert_eq!(zone.live_objects(), 0);
+
+    let (pages, live) = zone.drain(DrainMode::Refuse).expect("Can't drain an unused zone");
+    assert!(pages.is_empty());
+    assert_eq!(live, 0);
+
+    zone.set_drop_policy(DropPolicy::ReleaseIfUnused);
+    let pages = zone.into_pages().ok().expect("Can't take the pages of an unused zone");
+    assert_eq!(pages.len(), 0);
+}
+
+/// A zone that refills itself from `pager` and never gives empty pages back on its own.
+fn zone_with_pager(pager: &Pager) -> ZoneAllocator<'_> {
+    ZoneAllocator::with_page_source(
+        pager,
+        0,
+        Watermarks {
+            high_empty_pages: None,
+            low_free_slots: 0,
+        },
+    )
+}
+
+/// `DrainMode::Refuse` leaves a zone with live objects alone,
+/// `DrainMode::Report` takes every page out and counts the live objects.
+#[test]
+pub fn drain_modes() {
+    let pager = Pager::new();
+    let mut zone = zone_with_pager(&pager);
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(4000, 8).unwrap();
+    let a = zone.allocate(small).unwrap();
+    let b = zone.allocate(small).unwrap();
+    zone.allocate(large).unwrap();
+    zone.deallocate(b, small).unwrap();
+    assert_eq!(zone.pages(), 2);
+
+    assert!(zone.drain(DrainMode::Refuse).is_err());
+    assert_eq!(zone.pages(), 2, "Refuse took pages out");
+    assert_eq!(zone.live_objects(), 2);
+    let mut zone = zone.into_pages().err().expect("into_pages took the pages of a used zone");
+
+    // The zone is still usable after a refused drain.
+    zone.deallocate(a, small).unwrap();
+    let (pages, live) = zone.drain(DrainMode::Report).expect("Can't drain the zone");
+    assert_eq!(live, 1);
+    assert_eq!(pages.len(), 2);
+    assert_eq!(zone.pages(), 0);
+    assert_eq!(zone.live_objects(), 0);
+    pages.for_each(|mp| pager.release_page(mp));
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Every `DropPolicy` does what it says with the pages of a zone, with and without live objects.
+#[test]
+pub fn drop_policies() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    assert_eq!(DropPolicy::default(), DropPolicy::Leak);
+
+    for (policy, with_live_objects, released) in [
+        (DropPolicy::Leak, false, false),
+        (DropPolicy::Leak, true, false),
+        (DropPolicy::ReleaseIfUnused, false, true),
+        (DropPolicy::ReleaseIfUnused, true, false),
+        (DropPolicy::Release, false, true),
+        (DropPolicy::Release, true, true),
+    ] {
+        let pager = Pager::new();
+        let mut zone = zone_with_pager(&pager);
+        zone.set_drop_policy(policy);
+        let ptr = zone.allocate(layout).unwrap();
+        if !with_live_objects {
+            zone.deallocate(ptr, layout).unwrap();
+        }
+        assert_eq!(pager.currently_allocated(), 1);
+
+        drop(zone);
+        assert_eq!(
+            pager.currently_allocated() == 0,
+            released,
+            "{:?} with live objects: {}",
+            policy,
+            with_live_objects
+        );
+    }
+}
+
+/// The leak tracker groups live objects by call site and size class,
+/// and counts objects that don't fit into its table.
+#[cfg(feature = "leak-tracking")]
+#[test]
+pub fn leak_tracker_groups_by_site() {
+    use core::panic::Location;
+
+    let mut entries = [None; 4];
+    let mut tracker = LeakTracker::new(&mut entries);
+    let here = Location::caller();
+    let there = Location::caller();
+
+    tracker.record(0x1000, 8, here);
+    tracker.record(0x2000, 64, there);
+    tracker.record(0x1008, 8, here);
+    tracker.record(0x3000, 8, there);
+    tracker.record(0x1010, 8, here);
+    assert_eq!(tracker.untracked(), 1);
+
+    tracker.forget(0x1000);
+    tracker.forget(0x1010);
+    assert_eq!(tracker.untracked(), 0);
+    tracker.record(0x1018, 8, here);
+
+    let mut sites = Vec::new();
+    tracker.report(|site| sites.push(site));
+    assert_eq!(sites.len(), 3);
+    assert_eq!((sites[0].location, sites[0].size_class, sites[0].count, sites[0].first_seq), (here, 8, 2, 2));
+    assert_eq!((sites[1].location, sites[1].size_class, sites[1].count, sites[1].first_seq), (there, 8, 1, 3));
+    assert_eq!((sites[2].location, sites[2].size_class, sites[2].count, sites[2].first_seq), (there, 64, 1, 1));
+}
+
+/// Objects stay findable in the leak table while others around them are recorded and forgotten.
+#[cfg(feature = "leak-tracking")]
+#[test]
+pub fn leak_tracker_finds_objects_after_removals() {
+    use core::panic::Location;
+
+    let mut entries = [None; 64];
+    let mut tracker = LeakTracker::new(&mut entries);
+    let here = Location::caller();
+    let addrs: Vec<usize> = (0..64).map(|i| 0x10000 + i * 64).collect();
+    for addr in &addrs {
+        tracker.record(*addr, 64, here);
+    }
+    tracker.record(0x20000, 64, here);
+    assert_eq!(tracker.untracked(), 1);
+    tracker.forget(0x20000);
+
+    // Forget every other object, the rest must still be found.
+    for addr in addrs.iter().step_by(2) {
+        tracker.forget(*addr);
+    }
+    assert_eq!(tracker.untracked(), 0);
+    for addr in addrs.iter().skip(1).step_by(2) {
+        tracker.forget(*addr);
+    }
+    tracker.report(|site| panic!("{} objects weren't found", site.count));
+}
+
+/// Freeing objects that were allocated before the leak tracker was set doesn't
+/// hide untracked objects, and `allocate_at` records the given call site.
+#[cfg(feature = "leak-tracking")]
+#[test]
+pub fn leak_tracker_ignores_older_objects() {
+    use core::panic::Location;
+
+    let pager = Pager::new();
+    let mut entries = [None; 1];
+    let mut zone = zone_with_pager(&pager);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let older = zone.allocate(layout).unwrap();
+
+    zone.set_leak_tracker(&mut entries);
+    let site = Location::caller();
+    let tracked = zone.allocate_at(layout, site).unwrap();
+    let untracked = zone.allocate(layout).unwrap();
+    assert_eq!(zone.leak_report(|_site| {}), Ok(1));
+
+    zone.deallocate(older, layout).unwrap();
+    let mut sites = Vec::new();
+    assert_eq!(zone.leak_report(|site| sites.push(site)), Ok(1), "The untracked object was forgotten");
+    assert_eq!(sites.len(), 1);
+    assert_eq!((sites[0].location, sites[0].count), (site, 1));
+
+    zone.deallocate(untracked, layout).unwrap();
+    zone.deallocate(tracked, layout).unwrap();
+    assert_eq!(zone.leak_report(|_site| panic!("Nothing is leaked")), Ok(0));
+}
+
+/// A checkpoint remembers which slots of a page were allocated (for which size class).
+#[test]
+pub fn checkpoint_remembers_allocated_slots() {
+    let mut page = Box::<ObjectPage8k>::default();
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    page.bitfield_mut().initialize(slot_size(64), capacity);
+    page.bitfield().set_bit(0);
+    page.bitfield().set_bit(5);
+
+    let mut buffer = [PageSnapshot::default(); 2];
+    let mut checkpoint = HeapCheckpoint::new(&mut buffer);
+    assert!(!checkpoint.is_taken());
+    HeapCheckpoint::snapshot(&mut checkpoint.snapshots_mut()[0], &*page, 64);
+    checkpoint.take(1);
+    assert_eq!(checkpoint.pages(), 1);
+
+    page.bitfield().set_bit(6);
+    let addr = page.data_addr();
+    assert!(checkpoint.was_allocated(addr, 64, 0));
+    assert!(checkpoint.was_allocated(addr, 64, 5));
+    assert!(!checkpoint.was_allocated(addr, 64, 6));
+    // The page was used by a different size class at the time of the checkpoint.
+    assert!(!checkpoint.was_allocated(addr, 128, 0));
+    assert!(!checkpoint.was_allocated(addr + ObjectPage8k::SIZE, 64, 0));
+
+    // A freed object is forgotten, but only in the size class it belonged to.
+    checkpoint.forget(addr, 128, 5);
+    assert!(checkpoint.was_allocated(addr, 64, 5));
+    checkpoint.forget(addr, 64, 5);
+    assert!(!checkpoint.was_allocated(addr, 64, 5));
+    assert!(checkpoint.was_allocated(addr, 64, 0));
+
+    let mut diff = HeapDiff::default();
+    diff.add(3);
+    diff.add(3);
+    assert_eq!(diff.new_objects(64), 2);
+    assert_eq!(diff.iter().collect::<Vec<_>>(), vec![(64, 2)]);
+}
+
+/// The diff reports the objects allocated since the checkpoint,
+/// including objects in slots that were freed and handed out again.
+#[test]
+pub fn diff_reports_reused_slots() {
+    let pager = Pager::new();
+    let mut buffer = [PageSnapshot::default(); 4];
+    let mut zone = zone_with_pager(&pager);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    assert_eq!(zone.diff(), Err("The zone allocator has no checkpoint"));
+    assert_eq!(zone.checkpoint(), Err("The zone allocator has no checkpoint buffer"));
+    assert!(zone.set_checkpoint_buffer(&mut buffer).is_none());
+    assert_eq!(zone.diff(), Err("The zone allocator has no checkpoint"));
+
+    let old: Vec<_> = (0..4).map(|_| zone.allocate(layout).unwrap()).collect();
+    zone.checkpoint().unwrap();
+    assert!(zone.diff().unwrap().is_empty());
+
+    // Free all old objects, so the new ones can take their slots.
+    for ptr in old {
+        zone.deallocate(ptr, layout).unwrap();
+    }
+    let new: Vec<_> = (0..4).map(|_| zone.allocate(layout).unwrap()).collect();
+    let temporary = zone.allocate(layout).unwrap();
+    zone.deallocate(temporary, layout).unwrap();
+
+    let mut reported = Vec::new();
+    let diff = zone.diff_with(|addr, size_class| reported.push((addr, size_class))).unwrap();
+    assert_eq!(diff.new_objects(64), 4);
+    assert_eq!(diff.total(), 4);
+    for ptr in &new {
+        assert!(reported.contains(&(ptr.as_ptr() as usize, 64)));
+    }
+
+    for ptr in new {
+        zone.deallocate(ptr, layout).unwrap();
+    }
+    assert!(zone.diff().unwrap().is_empty());
+    let buffer = zone.clear_checkpoint().expect("The buffer wasn't given back");
+    assert_eq!(buffer.len(), 4);
+    assert!(zone.diff().is_err());
+
+    // The buffer can be used again (by any zone).
+    let ptr = zone.allocate(layout).unwrap();
+    zone.set_checkpoint_buffer(&mut []);
+    assert_eq!(zone.checkpoint(), Err("The buffer is too small for a checkpoint of the zone"));
+    assert_eq!(zone.set_checkpoint_buffer(buffer).map(|empty| empty.len()), Some(0));
+    zone.checkpoint().unwrap();
+    assert!(zone.diff().unwrap().is_empty());
+    zone.deallocate(ptr, layout).unwrap();
+}
+
+/// Counting live objects and diffing against a checkpoint skip quarantined objects,
+/// but leave them in the quarantine.
+#[cfg(feature = "quarantine")]
+#[test]
+pub fn queries_leave_the_quarantine_alone() {
+    let pager = Pager::new();
+    let mut buffer = [PageSnapshot::default(); 4];
+    let mut zone = zone_with_pager(&pager);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let before = zone.allocate(layout).unwrap();
+    zone.deallocate(before, layout).unwrap();
+    zone.set_checkpoint_buffer(&mut buffer);
+    zone.checkpoint().unwrap();
+    let after = zone.allocate(layout).unwrap();
+    zone.deallocate(after, layout).unwrap();
+
+    assert_eq!(zone.live_objects(), 0);
+    assert!(zone.diff().unwrap().is_empty());
+    // Neither slot was released, so they aren't handed out again.
+    let ptr = zone.allocate(layout).unwrap();
+    assert!(ptr != before && ptr != after);
+    assert_eq!(zone.diff().unwrap().total(), 1);
+    zone.deallocate(ptr, layout).unwrap();
+
+    // Once released, the slot taken before the checkpoint counts as new when it's reused.
+    zone.flush_quarantine();
+    let mut objects = Vec::new();
+    while !objects.contains(&before) {
+        assert!(objects.len() < 256, "The released slot is never handed out");
+        objects.push(zone.allocate(layout).unwrap());
+    }
+    assert_eq!(zone.diff().unwrap().total(), objects.len());
+    for ptr in objects {
+        zone.deallocate(ptr, layout).unwrap();
+    }
+}
+
+/// The tags live behind the last slot of an `ObjectPage8k`: the page still is exactly 8 KiB,
+/// the heap id stays where `HEAP_ID_OFFSET` says, and only the largest size class
+/// gives up space for them (one byte).
+#[cfg(feature = "tagging")]
+#[test]
+pub fn tags_fit_behind_the_slots() {
+    assert_eq!(size_of::<ObjectPage8k>(), ObjectPage8k::SIZE);
+    let capacity = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    assert_eq!(<ZoneAllocator>::MAX_ALLOC_SIZE, capacity - RED_ZONE_SIZE - 1);
+
+    for size in <ZoneAllocator>::BASE_ALLOC_SIZES {
+        let sa: SCAllocator<ObjectPage8k> = SCAllocator::new(size);
+        assert!(sa.obj_per_page > 0);
+        assert!(sa.obj_per_page * (slot_size(size) + 1) <= capacity, "The tags of class {} overlap its slots", size);
+    }
+
+    let mut page = Box::<ObjectPage8k>::default();
+    page.heap_id = 42;
+    let heap_id = unsafe { *((&*page as *const ObjectPage8k as usize + ObjectPage8k::HEAP_ID_OFFSET.unwrap()) as *const usize) };
+    assert_eq!(heap_id, 42);
+
+    let slots = ObjectPage8k::MAX_OBJECTS;
+    assert_eq!(page.tags(slots).len(), slots);
+    assert_eq!(page.tags(slots).as_ptr() as usize, page.data_addr() + capacity - slots);
+    page.tags_mut(slots)[slots - 1] = 7;
+    assert_eq!(page.heap_id, 42);
+    assert_eq!(page.tags(slots)[slots - 1], 7);
+    assert_eq!(page.tags(1), [7]);
+}
+
+/// A zone accounts the live objects per tag, objects allocated without a tag are `UNTAGGED`.
+#[cfg(feature = "tagging")]
+#[test]
+pub fn zone_accounts_objects_per_tag() {
+    let pager = Pager::new();
+    let mut zone = zone_with_pager(&pager);
+    let small = Layout::from_size_align(24, 8).unwrap();
+    let large = Layout::from_size_align(<ZoneAllocator>::MAX_ALLOC_SIZE, 8).unwrap();
+
+    let a = zone.allocate_tagged(small, 1).unwrap();
+    let b = zone.allocate_tagged(small, 1).unwrap();
+    let c = zone.allocate_tagged(large, 2).unwrap();
+    let d = zone.allocate(small).unwrap();
+    let e = zone.allocate_tagged(small, 200).unwrap();
+    // Fill the whole page of the largest class, its tag sits right behind the object.
+    unsafe { ptr::write_bytes(c.as_ptr(), 0xff, large.size()) };
+
+    let mut usage = [TagUsage::default(); 4];
+    zone.tag_usage(&mut usage);
+    assert_eq!(usage[UNTAGGED as usize], TagUsage { objects: 1, bytes: 32 });
+    assert_eq!(usage[1], TagUsage { objects: 2, bytes: 64 });
+    assert_eq!(usage[2], TagUsage { objects: 1, bytes: <ZoneAllocator>::MAX_ALLOC_SIZE });
+    assert_eq!(usage[3], TagUsage::default());
+
+    // A freed slot that is allocated again gets the new tag.
+    zone.deallocate(a, small).unwrap();
+    zone.deallocate(d, small).unwrap();
+    let f = zone.allocate_tagged(small, 3).unwrap();
+    zone.tag_usage(&mut usage);
+    assert_eq!(usage[UNTAGGED as usize], TagUsage::default());
+    assert_eq!(usage[1], TagUsage { objects: 1, bytes: 32 });
+    assert_eq!(usage[3], TagUsage { objects: 1, bytes: 32 });
+
+    for (ptr, layout) in [(b, small), (c, large), (e, small), (f, small)] {
+        zone.deallocate(ptr, layout).unwrap();
+    }
+    zone.tag_usage(&mut usage);
+    assert!(usage.iter().all(|entry| *entry == TagUsage::default()));
+}
+
+/// Events recorded by a `TraceRecorder` come back out of a `TraceReader`,
+/// events that don't fit into the buffer are counted.
+#[cfg(feature = "hooks")]
+#[test]
+pub fn trace_round_trip() {
+    let mut buffer = [0u8; TRACE_HEADER_SIZE + 2 * TRACE_RECORD_SIZE + 5];
+    let recorder = TraceRecorder::new(&mut buffer).unwrap();
+
+    let layout = Layout::from_size_align(40, 16).unwrap();
+
+    recorder.on_refill(0x40000, 64, 1);
+    recorder.on_allocate(0x40040, layout, 64, 1);
+    recorder.on_deallocate(0x40040, layout, 64, 1);
+    assert_eq!(recorder.events(), 2);
+    assert_eq!(recorder.dropped(), 1);
+
+    let events: Vec<TraceEvent> = TraceReader::new(recorder.as_bytes()).unwrap().collect();
+    assert_eq!(
+        events,
+        vec![
+            TraceEvent { kind: TraceKind::Refill, size_class: 64, addr: 0x40000, heap_id: 1, size: 0, align: 0 },
+            TraceEvent { kind: TraceKind::Allocate, size_class: 64, addr: 0x40040, heap_id: 1, size: 40, align: 16 },
+        ]
+    );
+    assert!(TraceReader::new(&[0; TRACE_HEADER_SIZE]).is_err());
+}
+
+/// A zone with a `TraceRecorder` records its refills and the layouts of its objects.
+#[cfg(feature = "hooks")]
+#[test]
+pub fn trace_records_zone_layouts() {
+    let pager = Pager::new();
+    let mut buffer = [0u8; TRACE_HEADER_SIZE + 8 * TRACE_RECORD_SIZE];
+    let recorder = TraceRecorder::new(&mut buffer).unwrap();
+    let layout = Layout::from_size_align(24, 16).unwrap();
+
+    let mut zone = zone_with_pager(&pager);
+    zone.set_hooks(&recorder);
+    let ptr = zone.allocate(layout).unwrap();
+    zone.deallocate(ptr, layout).unwrap();
+    // Draining flushes the quarantine (if any), so the free is recorded in any case.
+    let (pages, _) = zone.drain(DrainMode::Refuse).unwrap();
+    pages.for_each(|mp| pager.release_page(mp));
+    drop(zone);
+
+    let addr = ptr.as_ptr() as usize;
+    let events: Vec<TraceEvent> = TraceReader::new(recorder.as_bytes()).unwrap().collect();
+    assert_eq!(events[0].kind, TraceKind::Refill);
+    assert_eq!(
+        events[1],
+        TraceEvent { kind: TraceKind::Allocate, size_class: 32, addr, heap_id: 0, size: 24, align: 16 }
+    );
+    assert_eq!(
+        events[2],
+        TraceEvent { kind: TraceKind::Deallocate, size_class: 32, addr, heap_id: 0, size: 24, align: 16 }
+    );
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// `alloc`, `realloc` and `dealloc` of `GlobalAlloc` round-trip objects, move them
+/// between size classes, resize them in place and hand out dangling pointers for zero-sized layouts.
+#[test]
+pub fn locked_zone_global_alloc() {
+    use std::alloc::GlobalAlloc;
+
+    let pager = Pager::new();
+    let watermarks = Watermarks {
+        high_empty_pages: Some(0),
+        low_free_slots: 0,
+    };
+    let zone: LockedZoneAllocator<Spinlock> = LockedZoneAllocator::new(&pager, 1, watermarks);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+
+    unsafe {
+        let ptr = zone.alloc(layout);
+        assert!(!ptr.is_null());
+        for i in 0..layout.size() {
+            *ptr.add(i) = i as u8;
+        }
+
+        // 40 and 48 bytes are both in the 64 byte class.
+        let same = zone.realloc(ptr, layout, 48);
+        assert_eq!(same, ptr, "Same-class realloc moved the object");
+
+        let moved = zone.realloc(same, Layout::from_size_align(48, 8).unwrap(), 200);
+        assert!(!moved.is_null());
+        assert_ne!(moved, ptr, "The object must move to the 256 byte class");
+        for i in 0..layout.size() {
+            assert_eq!(*moved.add(i), i as u8, "Realloc didn't copy the contents");
+        }
+
+        let shrunk = zone.realloc(moved, Layout::from_size_align(200, 8).unwrap(), 8);
+        assert!(!shrunk.is_null());
+        assert_eq!(*shrunk.add(7), 7);
+        zone.dealloc(shrunk, Layout::from_size_align(8, 8).unwrap());
+
+        // Zero-sized layouts need no pages, before and after resizing.
+        let zst = Layout::from_size_align(0, 16).unwrap();
+        let empty = zone.alloc(zst);
+        assert!(!empty.is_null());
+        assert_eq!(empty as usize % 16, 0);
+        let grown = zone.realloc(empty, zst, 16);
+        assert!(!grown.is_null());
+        let gone = zone.realloc(grown, Layout::from_size_align(16, 16).unwrap(), 0);
+        assert!(!gone.is_null());
+        zone.dealloc(gone, zst);
+
+        assert!(zone.alloc(Layout::from_size_align(<ZoneAllocator>::MAX_ALLOC_SIZE + 1, 8).unwrap()).is_null());
+    }
+    assert_eq!(zone.with_zone(|zone| zone.live_objects()), 0);
+    #[cfg(feature = "quarantine")]
+    zone.with_zone(|zone| zone.flush_quarantine());
+    zone.with_zone(|zone| zone.trim()).expect("Can't trim the zone");
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// `dealloc` of a corrupted object logs the error and leaks the object instead of panicking.
+#[cfg(feature = "red-zones")]
+#[test]
+pub fn locked_zone_dealloc_leaks_corrupted_objects() {
+    use std::alloc::GlobalAlloc;
+
+    let pager = Pager::new();
+    let watermarks = Watermarks {
+        high_empty_pages: None,
+        low_free_slots: 0,
+    };
+    let zone: LockedZoneAllocator<Spinlock> = LockedZoneAllocator::new(&pager, 1, watermarks);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe {
+        let ptr = zone.alloc(layout);
+        *ptr.add(64) = 0;
+        zone.dealloc(ptr, layout);
+        assert_eq!(zone.with_zone(|zone| zone.live_objects()), 1, "The corrupted object must stay allocated");
+
+        // Realloc keeps the corrupted object and gives back the new block.
+        assert!(zone.realloc(ptr, layout, 500).is_null());
+        assert_eq!(zone.with_zone(|zone| zone.live_objects()), 1);
+        *ptr.add(64) = RED_ZONE_PATTERN;
+        zone.dealloc(ptr, layout);
+    }
+    assert_eq!(zone.with_zone(|zone| zone.live_objects()), 0);
+    #[cfg(feature = "quarantine")]
+    zone.with_zone(|zone| zone.flush_quarantine());
+    zone.with_zone(|zone| zone.trim()).expect("Can't trim the zone");
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Collections work on a `LockedZoneAllocator` through the `Allocator` trait,
+/// which hands out the whole size class and keeps blocks in place within it.
+#[cfg(feature = "unstable")]
+#[test]
+pub fn locked_zone_allocator_api() {
+    use std::alloc::Allocator;
+
+    let pager = Pager::new();
+    let watermarks = Watermarks {
+        high_empty_pages: Some(0),
+        low_free_slots: 0,
+    };
+    let zone: LockedZoneAllocator<Spinlock> = LockedZoneAllocator::new(&pager, 1, watermarks);
+
+    let mut v: Vec<u64, _> = Vec::new_in(&zone);
+    for i in 0..300 {
+        v.push(i);
+    }
+    assert_eq!(v.iter().sum::<u64>(), 300 * 299 / 2);
+    let boxed = Box::new_in([7u8; 100], &zone);
+    assert_eq!(boxed.iter().map(|b| *b as usize).sum::<usize>(), 700);
+    drop(v);
+    drop(boxed);
+
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let block = Allocator::allocate(&zone, layout).unwrap();
+    assert_eq!(block.len(), 64, "The whole size class is usable");
+    let ptr = block.cast::<u8>();
+    unsafe {
+        ptr.as_ptr().write_bytes(0xaa, 40);
+
+        let same = zone.grow(ptr, layout, Layout::from_size_align(64, 8).unwrap()).unwrap();
+        assert_eq!(same.cast::<u8>(), ptr, "Growing within the size class moved the block");
+
+        let big = Layout::from_size_align(300, 8).unwrap();
+        let zeroed = zone.grow_zeroed(same.cast(), Layout::from_size_align(64, 8).unwrap(), big).unwrap();
+        assert_eq!(zeroed.len(), 512);
+        let bytes = zeroed.as_ref();
+        assert!(bytes[..40].iter().all(|b| *b == 0xaa));
+        assert!(bytes[64..].iter().all(|b| *b == 0), "grow_zeroed left garbage behind the old block");
+
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let shrunk = zone.shrink(zeroed.cast(), big, small).unwrap();
+        assert_eq!(shrunk.len(), 16);
+        assert_eq!(shrunk.as_ref()[15], 0xaa);
+        Allocator::deallocate(&zone, shrunk.cast(), small);
+    }
+
+    assert_eq!(zone.with_zone(|zone| zone.live_objects()), 0);
+    #[cfg(feature = "quarantine")]
+    zone.with_zone(|zone| zone.flush_quarantine());
+    zone.with_zone(|zone| zone.trim()).expect("Can't trim the zone");
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// A `ConcurrentSCAllocator` hands out its whole object size through the `Allocator` trait,
+/// resizes blocks in place and only moves them for a stricter alignment.
+#[cfg(feature = "unstable")]
+#[test]
+pub fn concurrent_allocator_api() {
+    use std::alloc::Allocator;
+
+    let pager = Pager::new();
+    let mut csa: ConcurrentSCAllocator<ObjectPage8k> = ConcurrentSCAllocator::new(64);
+    csa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+
+    {
+        // Growing to the object size stays within the block.
+        let mut v: Vec<u8, _> = Vec::with_capacity_in(10, &csa);
+        v.extend_from_slice(&[1; 64]);
+        assert_eq!(v.iter().map(|b| *b as usize).sum::<usize>(), 64);
+        let boxed = Box::new_in(5u32, &csa);
+        assert_eq!(*boxed, 5);
+    }
+
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    assert!(Allocator::allocate(&csa, Layout::from_size_align(65, 8).unwrap()).is_err());
+    let block = Allocator::allocate(&csa, layout).unwrap();
+    assert_eq!(block.len(), 64);
+    let ptr = block.cast::<u8>();
+    unsafe {
+        ptr.as_ptr().write_bytes(0x55, 8);
+        let grown = csa.grow(ptr, layout, Layout::from_size_align(64, 8).unwrap()).unwrap();
+        assert_eq!(grown.cast::<u8>(), ptr);
+        assert!(csa.grow(ptr, Layout::from_size_align(64, 8).unwrap(), Layout::from_size_align(128, 8).unwrap()).is_err());
+
+        let zeroed = csa.grow_zeroed(ptr, layout, Layout::from_size_align(32, 8).unwrap()).unwrap();
+        assert!(zeroed.as_ref()[..8].iter().all(|b| *b == 0x55));
+        assert!(zeroed.as_ref()[8..32].iter().all(|b| *b == 0));
+
+        // A stricter alignment may need another slot (depending on where the block is and
+        // which slots are aligned), if there is none the block stays where it is.
+        let aligned = Layout::from_size_align(16, 4096).unwrap();
+        match csa.shrink(zeroed.cast(), Layout::from_size_align(32, 8).unwrap(), aligned) {
+            Ok(moved) => {
+                assert_eq!(moved.cast::<u8>().as_ptr() as usize % 4096, 0);
+                assert_eq!(moved.as_ref()[..8], [0x55; 8]);
+                Allocator::deallocate(&csa, moved.cast(), aligned);
+            }
+            Err(_e) => {
+                assert_eq!(zeroed.as_ref()[..8], [0x55; 8]);
+                Allocator::deallocate(&csa, zeroed.cast(), Layout::from_size_align(32, 8).unwrap());
+            }
+        }
+    }
+
+    // Only an empty page can be retrieved, so every block was freed.
+    pager.release_page(csa.retrieve_empty_page().unwrap().unwrap());
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// A `LockedZoneAllocator` refills its zone from the page source and hands
+/// empty pages above the high watermark back to it.
+#[test]
+pub fn locked_zone_uses_page_source() {
+    let pager = Pager::new();
+    let watermarks = Watermarks {
+        high_empty_pages: Some(1),
+        low_free_slots: 0,
+    };
+    let zone: LockedZoneAllocator<Spinlock> = LockedZoneAllocator::new(&pager, 7, watermarks);
+
+    // Every object of this class needs a page of its own.
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let objects: Vec<NonNull<u8>> = (0..3).map(|_| zone.allocate(layout).expect("Can't allocate")).collect();
+    assert_eq!(pager.currently_allocated(), 3);
+    assert_eq!(zone.with_zone(|zone| zone.pages()), 3);
+
+    for ptr in objects {
+        zone.deallocate(ptr, layout).expect("Can't deallocate");
+    }
+    // Quarantined objects keep their pages in use until the quarantine is flushed.
+    #[cfg(not(feature = "quarantine"))]
+    assert_eq!(pager.currently_allocated(), 1, "Pages above the high watermark go back to the source");
+
+    assert_eq!(zone.with_zone(|zone| zone.live_objects()), 0);
+    #[cfg(feature = "quarantine")]
+    zone.with_zone(|zone| zone.flush_quarantine());
+    zone.with_zone(|zone| zone.trim()).expect("Can't trim the zone");
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Walks the pages of `sa` to count its free slots (what `free_slots` used to do).
+fn count_free_slots(sa: &mut SCAllocator<ObjectPage8k>) -> usize {
+    let mut free = sa.empty_slabs.elements * sa.obj_per_page;
+    for slab_page in sa.slabs.iter_mut() {
+        free += slab_page.free_slots();
+    }
+    free
+}
+
+/// The running free slot count of a size class agrees with its pages
+/// after allocations, frees and page movements.
+#[test]
+pub fn free_slot_count_matches_pages() {
+    use core::mem::MaybeUninit;
+
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(256);
+    let mut other: SCAllocator<ObjectPage8k> = SCAllocator::new(256);
+    for _ in 0..4 {
+        sa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    }
+    assert_eq!(sa.free_slots(), 4 * sa.obj_per_page);
+
+    let mut objects: Vec<NonNull<u8>> = (0..sa.obj_per_page + 5).map(|_| sa.allocate(layout).unwrap()).collect();
+    assert_eq!(sa.free_slots(), count_free_slots(&mut sa));
+
+    let mut bulk = [MaybeUninit::uninit(); 10];
+    assert_eq!(sa.allocate_bulk(layout, &mut bulk, BulkMode::Partial), Ok(bulk.len()));
+    objects.extend(bulk.iter().map(|obj| unsafe { obj.assume_init() }));
+    assert_eq!(sa.free_slots(), count_free_slots(&mut sa));
+
+    for ptr in objects.drain(..7) {
+        sa.deallocate(ptr, layout).unwrap();
+    }
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    assert_eq!(sa.free_slots(), count_free_slots(&mut sa));
+
+    assert_eq!(sa.split_into(&mut other, 1, 1, 1), Ok(2));
+    assert_eq!(sa.free_slots(), count_free_slots(&mut sa));
+    assert_eq!(other.free_slots(), count_free_slots(&mut other));
+
+    sa.merge(&mut other, 0).unwrap();
+    assert_eq!(other.free_slots(), 0);
+    assert_eq!(sa.free_slots(), count_free_slots(&mut sa));
+
+    sa.deallocate_bulk(&objects, layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    assert_eq!(sa.free_slots(), 4 * sa.obj_per_page);
+
+    while let Some(mp) = sa.retrieve_empty_page().unwrap() {
+        pager.release_page(mp);
+    }
+    assert_eq!(sa.free_slots(), 0);
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+static LOW_MEMORY_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_low_memory(_heap_id: usize, _size_class: usize, _free_slots: usize) {
+    LOW_MEMORY_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The low watermark is reported even if the zone can't be refilled.
+#[test]
+pub fn low_watermark_fires_when_refill_fails() {
+    let mut zone: ZoneAllocator = ZoneAllocator::new();
+    zone.set_watermarks(Watermarks {
+        high_empty_pages: None,
+        low_free_slots: 1,
+    });
+    zone.set_low_memory_callback(count_low_memory);
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    assert!(zone.allocate(layout).is_err(), "The zone has no pages and no page source");
+    assert_eq!(LOW_MEMORY_CALLS.load(Ordering::Relaxed), 1);
+}
+
+/// Fractions of the available pages don't overflow and never exceed what's available.
+#[test]
+pub fn split_amount_fraction_does_not_overflow() {
+    let half = SplitAmount::Fraction { numerator: 1, denominator: 2 };
+    assert_eq!(half.of(5), 2);
+
+    let huge = SplitAmount::Fraction { numerator: usize::MAX, denominator: usize::MAX };
+    assert_eq!(huge.of(usize::MAX), usize::MAX);
+    assert_eq!(huge.of(7), 7);
+
+    let more = SplitAmount::Fraction { numerator: usize::MAX, denominator: 2 };
+    assert_eq!(more.of(3), 3, "Capped at the available pages");
+
+    let none = SplitAmount::Fraction { numerator: 3, denominator: 0 };
+    assert_eq!(none.of(10), 0);
+    assert_eq!(SplitAmount::Pages(4).of(2), 2);
+}
+
+/// A zone without pages and without a page source refills itself from a depot
+/// (with any lock) and stamps the pages with its own heap id.
+#[test]
+pub fn zone_refills_from_depot() {
+    #[cfg(feature = "hooks")]
+    let events = EventCounter::default();
+    let pager = Pager::new();
+    let depot: PageDepot<Spinlock> = PageDepot::new(4);
+    let limits = DepotLimits {
+        push_above: 0,
+        max_pages: None,
+    };
+    let layout = Layout::from_size_align(8, 8).unwrap();
+
+    let mut zone_a: ZoneAllocator = ZoneAllocator::new();
+    zone_a.set_page_source(&pager, 1);
+    zone_a.set_depot(&depot, limits, 1);
+    let ptr = zone_a.allocate(layout).expect("Can't allocate from the page source");
+    zone_a.deallocate(ptr, layout).expect("Can't deallocate");
+    assert_eq!(zone_a.live_objects(), 0);
+    #[cfg(feature = "quarantine")]
+    zone_a.flush_quarantine();
+    zone_a.push_to_depot();
+    assert_eq!(zone_a.pages(), 0);
+    assert_eq!(depot.len(), 1);
+
+    let mut zone_b: ZoneAllocator = ZoneAllocator::new();
+    zone_b.set_depot(&depot, limits, 5);
+    #[cfg(feature = "hooks")]
+    zone_b.set_hooks(&events);
+    let ptr = zone_b.allocate(layout).expect("Can't allocate from the depot");
+    assert!(depot.is_empty());
+    #[cfg(feature = "hooks")]
+    assert_eq!(events.refilled_pages.load(Ordering::Relaxed), 1, "The page from the depot wasn't reported");
+    assert_eq!(zone_b.heap_id(), Ok(5));
+    assert_eq!(unsafe { ObjectPage8k::heap_id_of(ptr.as_ptr() as usize) }, Some(5));
+    zone_b.deallocate(ptr, layout).expect("Can't deallocate");
+    assert_eq!(zone_b.live_objects(), 0);
+    #[cfg(feature = "quarantine")]
+    zone_b.flush_quarantine();
+    zone_b.push_to_depot();
+
+    while let Some(mp) = depot.retrieve_page().unwrap() {
+        pager.release_page(mp);
+    }
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Objects freed on one CPU are handed to another CPU through the depot,
+/// and dropping the magazines of a CPU gives its objects back to the allocator.
+#[test]
+pub fn magazines_exchange_through_depot_and_flush_on_drop() {
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let cache: MagazineCache<Spinlock> = MagazineCache::new(layout);
+    for _ in 0..4 {
+        cache.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    }
+
+    {
+        let mut cpu_a = CpuMagazines::new(&cache);
+        let mut cpu_b = CpuMagazines::new(&cache);
+
+        let mut objects = HashSet::new();
+        for _ in 0..200 {
+            let ptr = cache.allocate(&mut cpu_a).expect("Can't allocate");
+            assert!(objects.insert(ptr), "Object {:p} was handed out twice", ptr);
+        }
+        for ptr in objects.iter() {
+            cache.deallocate(&mut cpu_a, *ptr).expect("Can't deallocate");
+        }
+        assert!(cpu_a.len() <= 2 * Magazine::CAPACITY, "The rest went to the depot");
+
+        // Full magazines come from the depot, not from the allocator.
+        let allocated = cache.with_allocator(|sca| sca.allocation_count());
+        let moved: Vec<NonNull<u8>> = (0..Magazine::CAPACITY).map(|_| cache.allocate(&mut cpu_b).unwrap()).collect();
+        assert_eq!(cache.with_allocator(|sca| sca.allocation_count()), allocated);
+        for ptr in moved.iter() {
+            assert!(objects.contains(ptr));
+            cache.deallocate(&mut cpu_b, *ptr).unwrap();
+        }
+        assert!(!cpu_b.is_empty());
+    }
+
+    cache.flush_depot().unwrap();
+    let mut pages = 0;
+    while let Some(mp) = cache.retrieve_empty_page().unwrap() {
+        pager.release_page(mp);
+        pages += 1;
+    }
+    assert_eq!(pages, 4, "All objects went back to the allocator");
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// CPUs with two full magazines exchange one for an empty magazine from the depot,
+/// once there is none left a batch goes back to the allocator. A freed object is
+/// cached even if that batch can't be freed, and pages can be reclaimed while CPUs use the cache.
+#[test]
+pub fn magazines_exchange_empty_magazines_and_keep_freed_objects() {
+    const PAGES: usize = 12;
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let cache: MagazineCache<Spinlock> = MagazineCache::new(layout);
+    for _ in 0..PAGES {
+        cache.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    }
+    let mut cpu = CpuMagazines::new(&cache);
+
+    let objects: Vec<NonNull<u8>> = (0..(MagazineCache::<Spinlock>::DEPOT_CAPACITY + 2) * Magazine::CAPACITY + 1)
+        .map(|_| cache.allocate(&mut cpu).expect("Can't allocate"))
+        .collect();
+    cache.flush_cpu(&mut cpu).unwrap();
+
+    // Fill both magazines of the CPU and every magazine of the depot.
+    let (rest, last) = objects.split_at(objects.len() - 1);
+    for ptr in rest {
+        cache.deallocate(&mut cpu, *ptr).unwrap();
+    }
+    assert_eq!(cpu.len(), 2 * Magazine::CAPACITY);
+    assert_eq!(cache.full_magazines(), MagazineCache::<Spinlock>::DEPOT_CAPACITY);
+
+    // The batch that goes back to the allocator holds a corrupted object.
+    #[cfg(feature = "red-zones")]
+    {
+        let corrupted = rest[rest.len() - 1];
+        unsafe { *corrupted.as_ptr().add(64) = 0 };
+        assert!(cache.deallocate(&mut cpu, last[0]).is_err());
+    }
+    #[cfg(not(feature = "red-zones"))]
+    cache.deallocate(&mut cpu, last[0]).unwrap();
+    assert_eq!(cpu.len(), 2 * Magazine::CAPACITY - Magazine::CAPACITY / 2 + 1, "The freed object wasn't cached");
+
+    // Reclaiming works while the magazines of a CPU exist.
+    cache.flush_cpu(&mut cpu).unwrap();
+    assert!(cpu.is_empty());
+    let mut pages = 0;
+    while let Some(mp) = cache.retrieve_empty_page().unwrap() {
+        pager.release_page(mp);
+        pages += 1;
+    }
+    assert_eq!(cache.full_magazines(), 0);
+    // The page of the leaked object stays in use.
+    let leaked = if cfg!(feature = "red-zones") { 1 } else { 0 };
+    assert_eq!(pages, PAGES - leaked);
+
+    // An empty CPU gets a batch of objects from the allocator.
+    cache.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    let ptr = cache.allocate(&mut cpu).unwrap();
+    assert_eq!(cpu.len(), Magazine::CAPACITY / 2 - 1);
+    cache.deallocate(&mut cpu, ptr).unwrap();
+    drop(cpu);
+    while let Some(mp) = cache.retrieve_empty_page().unwrap() {
+        pager.release_page(mp);
+    }
+    assert_eq!(pager.currently_allocated(), leaked);
+}
+
+/// `BulkMode::AllOrNothing` gives back everything if the request can't be met,
+/// `BulkMode::Partial` keeps what it got.
+#[test]
+pub fn bulk_all_or_nothing_rolls_back() {
+    use core::mem::MaybeUninit;
+
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(128);
+    sa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    let obj_per_page = sa.obj_per_page;
+
+    let mut objects = vec![MaybeUninit::uninit(); obj_per_page + 5];
+    assert_eq!(sa.allocate_bulk(layout, &mut objects, BulkMode::AllOrNothing), Err(OUT_OF_MEMORY));
+    // The objects were never handed out, so they skip the quarantine.
+    assert_eq!(sa.live_objects(), 0);
+    assert_eq!(sa.free_slots(), obj_per_page);
+    assert_eq!(sa.empty_slabs.elements, 1, "The page is empty again");
+
+    assert_eq!(sa.allocate_bulk(layout, &mut objects, BulkMode::Partial), Ok(obj_per_page));
+    assert_eq!(sa.full_slabs.elements, 1);
+    let allocated: Vec<NonNull<u8>> = objects[..obj_per_page].iter().map(|obj| unsafe { obj.assume_init() }).collect();
+    assert_eq!(allocated.iter().collect::<HashSet<_>>().len(), obj_per_page);
+
+    sa.deallocate_bulk(&allocated, layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    assert_eq!(sa.empty_slabs.elements, 1, "The full page moved straight to the empty list");
+    assert_eq!(sa.full_slabs.elements + sa.slabs.elements, 0);
+
+    pager.release_page(sa.retrieve_empty_page().unwrap().unwrap());
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// The objects `deallocate_bulk` freed before a corrupted one move their page to the right list.
+#[cfg(all(feature = "red-zones", not(feature = "quarantine")))]
+#[test]
+pub fn deallocate_bulk_error_updates_page_list() {
+    use core::mem::MaybeUninit;
+
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(1024);
+    sa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    let obj_per_page = sa.obj_per_page;
+    assert!(obj_per_page >= 2);
+
+    let mut objects = vec![MaybeUninit::uninit(); obj_per_page];
+    assert_eq!(sa.allocate_bulk(layout, &mut objects, BulkMode::AllOrNothing), Ok(obj_per_page));
+    let allocated: Vec<NonNull<u8>> = objects.iter().map(|obj| unsafe { obj.assume_init() }).collect();
+    assert_eq!(sa.full_slabs.elements, 1);
+
+    // Overflow the last object into its red zone.
+    let last = allocated[obj_per_page - 1];
+    unsafe { ptr::write_bytes(last.as_ptr(), 0xff, 1024 + 1) };
+    assert!(sa.deallocate_bulk(&allocated, layout).is_err());
+
+    assert_eq!(sa.full_slabs.elements, 0, "The page isn't full anymore");
+    assert_eq!(sa.slabs.elements, 1);
+    assert_eq!(sa.free_slots(), obj_per_page - 1);
+    assert_eq!(sa.live_objects(), 1, "The corrupted object stays allocated");
+}
+
+static WIDGETS_CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+static WIDGETS_DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+struct Widget {
+    id: u64,
+    ready: bool,
+}
+
+fn construct_widget(widget: &mut core::mem::MaybeUninit<Widget>) {
+    widget.write(Widget { id: 0, ready: true });
+    WIDGETS_CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn destroy_widget(widget: &mut Widget) {
+    assert!(widget.ready, "Only constructed objects are destroyed");
+    WIDGETS_DESTROYED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Objects are constructed when their page is added to the cache and destroyed
+/// when the page leaves the cache (on every allocation and free with `poison`),
+/// dropping the cache gives its pages back.
+#[test]
+pub fn object_cache_constructs_and_destroys() {
+    let pager = Pager::new();
+    let obj_per_page = core::cmp::min(
+        (ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE) / (slot_size(size_of::<Widget>()) + ObjectPage8k::TAG_SIZE),
+        ObjectPage8k::MAX_OBJECTS,
+    );
+    let mut cache: ObjectCache<Widget> = ObjectCache::with_constructor(construct_widget, Some(destroy_widget));
+    cache.set_page_source(&pager, 3);
+
+    {
+        let a = cache.allocate().expect("Can't allocate a widget");
+        assert!(a.ready);
+        let b = cache.allocate_with(Widget { id: 7, ready: true }).expect("Can't allocate a widget");
+        assert_eq!(b.id, 7);
+        assert_ne!(a.as_ptr(), b.as_ptr());
+        assert_eq!(pager.currently_allocated(), 1);
+    }
+
+    let (constructed, destroyed) = if cfg!(feature = "poison") { (1, 2) } else { (obj_per_page, obj_per_page) };
+    assert_eq!(WIDGETS_CONSTRUCTED.load(Ordering::Relaxed), constructed);
+    if cfg!(feature = "poison") {
+        assert_eq!(WIDGETS_DESTROYED.load(Ordering::Relaxed), destroyed);
+    }
+
+    drop(cache);
+    assert_eq!(WIDGETS_DESTROYED.load(Ordering::Relaxed), destroyed);
+    assert_eq!(pager.currently_allocated(), 0, "Dropping the cache releases its pages");
+}
+
+static COUNTERS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+struct Counter(usize);
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        COUNTERS_DROPPED.fetch_add(self.0, Ordering::Relaxed);
+    }
+}
+
+/// Without a constructor a cache moves values in and drops them with their `SlabBox`,
+/// and it can be shared between threads if its objects can be sent between them.
+#[test]
+pub fn object_cache_drops_boxed_values() {
+    fn assert_sync<S: Sync>(_: &S) {}
+
+    let pager = Pager::new();
+    let mut cache: ObjectCache<Counter> = ObjectCache::default();
+    assert!(cache.allocate().is_err(), "The cache has no constructor");
+    assert!(cache.allocate_with(Counter(1)).is_err(), "The cache has no pages");
+    cache.set_page_source(&pager, 0);
+    assert_sync(&cache);
+
+    let boxes: Vec<SlabBox<Counter>> = (1..=4).map(|i| cache.allocate_with(Counter(i)).unwrap()).collect();
+    assert_eq!(boxes.iter().map(|b| b.0).sum::<usize>(), 10);
+    assert_eq!(COUNTERS_DROPPED.load(Ordering::Relaxed), 1, "The value that didn't fit was dropped");
+    drop(boxes);
+    assert_eq!(COUNTERS_DROPPED.load(Ordering::Relaxed), 11);
+
+    drop(cache);
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Dropping a `SlabBox` whose red zone is corrupted leaks its slot instead of panicking.
+#[cfg(feature = "red-zones")]
+#[test]
+pub fn object_cache_leaks_corrupted_objects() {
+    let pager = Pager::new();
+    let mut cache: ObjectCache<[u8; 64]> = ObjectCache::default();
+    cache.set_page_source(&pager, 0);
+
+    let corrupted = cache.allocate_with([1; 64]).unwrap();
+    let intact = cache.allocate_with([2; 64]).unwrap();
+    unsafe { *(corrupted.as_ptr() as *mut u8).add(64) = 0 };
+    drop(corrupted);
+    drop(intact);
+    assert!(cache.retrieve_empty_page().unwrap().is_none(), "The leaked slot keeps its page in use");
+
+    drop(cache);
+    assert_eq!(pager.currently_allocated(), 1, "Only the page of the leaked object stays allocated");
+}
+
+/// A use after free is reported as corruption, the zone doesn't refill itself to hide it.
+#[cfg(all(feature = "poison", not(feature = "quarantine"), not(feature = "hardened")))]
+#[test]
+pub fn poisoned_object_is_not_refilled() {
+    let pager = Pager::new();
+    let mut zone: ZoneAllocator = ZoneAllocator::with_page_source(
+        &pager,
+        0,
+        Watermarks {
+            high_empty_pages: None,
+            low_free_slots: 0,
+        },
+    );
+    let layout = Layout::from_size_align(32, 8).unwrap();
+
+    let ptr = zone.allocate(layout).expect("Can't allocate");
+    zone.deallocate(ptr, layout).expect("Can't deallocate");
+    unsafe { *ptr.as_ptr() = 0 };
+
+    assert_eq!(zone.allocate(layout), Err(CORRUPTED));
+    assert_eq!(zone.pages(), 1, "The zone wasn't refilled");
+    assert!(zone.allocate(layout).is_ok(), "The corrupted slot stays out of use");
+}
+
+/// Counts the events reported to the hooks.
+#[cfg(feature = "hooks")]
+#[derive(Default)]
+struct EventCounter {
+    allocated: AtomicUsize,
+    deallocated: AtomicUsize,
+    refilled_pages: AtomicUsize,
+    merged_pages: AtomicUsize,
+    returned_pages: AtomicUsize,
+}
+
+#[cfg(feature = "hooks")]
+impl AllocatorHooks for EventCounter {
+    fn on_allocate(&self, _addr: usize, _layout: Layout, _size_class: usize, _heap_id: usize) {
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_deallocate(&self, _addr: usize, _layout: Layout, _size_class: usize, _heap_id: usize) {
+        self.deallocated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_refill(&self, _page: usize, _size_class: usize, _heap_id: usize) {
+        self.refilled_pages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_merge(&self, pages: usize, _size_class: usize, _heap_id: usize) {
+        self.merged_pages.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    fn on_page_return(&self, _page: usize, _size_class: usize, _heap_id: usize) {
+        self.returned_pages.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Merging takes the freed (even quarantined) objects along and
+/// reports the pages that leave the other allocator.
+#[test]
+pub fn merge_takes_freed_objects_and_reports_pages() {
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(64);
+    let mut other: SCAllocator<ObjectPage8k> = SCAllocator::new(64);
+    #[cfg(feature = "hooks")]
+    let events = EventCounter::default();
+    #[cfg(feature = "hooks")]
+    other.set_hooks(&events);
+
+    other.refill(pager.allocate_page().unwrap(), 1).unwrap();
+    other.refill(pager.allocate_page().unwrap(), 1).unwrap();
+    let ptr = other.allocate(layout).unwrap();
+    other.deallocate(ptr, layout).unwrap();
+
+    sa.merge(&mut other, 0).unwrap();
+    assert_eq!(other.pages(), 0);
+    assert_eq!(sa.pages(), 2);
+    assert_eq!(sa.live_objects(), 0, "The freed object came along as free");
+    #[cfg(feature = "hooks")]
+    assert_eq!(events.returned_pages.load(Ordering::Relaxed), 2);
+
+    while let Some(mp) = sa.retrieve_empty_page().unwrap() {
+        pager.release_page(mp);
+    }
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Frees are reported once they happened (i.e., after the quarantine) and not if they fail,
+/// pages that move in `split_into` are reported like in `merge`.
+#[cfg(feature = "hooks")]
+#[test]
+pub fn hooks_report_completed_frees_and_split_pages() {
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let events = EventCounter::default();
+    let other_events = EventCounter::default();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(64);
+    let mut other: SCAllocator<ObjectPage8k> = SCAllocator::new(64);
+    sa.set_hooks(&events);
+    other.set_hooks(&other_events);
+    let deallocated = || events.deallocated.load(Ordering::Relaxed);
+
+    sa.refill(pager.allocate_page().unwrap(), 1).unwrap();
+    sa.refill(pager.allocate_page().unwrap(), 1).unwrap();
+    let a = sa.allocate(layout).unwrap();
+    let b = sa.allocate(layout).unwrap();
+
+    sa.deallocate(a, layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    {
+        assert_eq!(deallocated(), 0, "The object is still in the quarantine");
+        sa.flush_quarantine();
+    }
+    assert_eq!(deallocated(), 1);
+
+    #[cfg(feature = "red-zones")]
+    {
+        unsafe { *b.as_ptr().add(64) = 0 };
+        assert!(sa.deallocate(b, layout).is_err());
+        assert!(sa.deallocate_bulk(&[b], layout).is_err());
+        assert_eq!(deallocated(), 1, "A failed free was reported");
+        unsafe { *b.as_ptr().add(64) = RED_ZONE_PATTERN };
+    }
+    sa.deallocate_bulk(&[b], layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    assert_eq!(deallocated(), 2);
+
+    assert_eq!(sa.split_into(&mut other, 2, 1, 0), Ok(1));
+    assert_eq!(events.returned_pages.load(Ordering::Relaxed), 1);
+    assert_eq!(other_events.merged_pages.load(Ordering::Relaxed), 1);
+
+    for sca in [&mut sa, &mut other] {
+        while let Some(mp) = sca.retrieve_empty_page().unwrap() {
+            pager.release_page(mp);
+        }
+    }
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Objects that `allocate_bulk` takes back when it can't meet an `AllOrNothing` request
+/// were never handed out, so neither their allocation nor their free is reported.
+#[cfg(feature = "hooks")]
+#[test]
+pub fn hooks_skip_bulk_rollback() {
+    use core::mem::MaybeUninit;
+
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let events = EventCounter::default();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(128);
+    sa.set_hooks(&events);
+    sa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    let obj_per_page = sa.obj_per_page;
+
+    let mut objects = vec![MaybeUninit::uninit(); obj_per_page + 1];
+    assert_eq!(sa.allocate_bulk(layout, &mut objects, BulkMode::AllOrNothing), Err(OUT_OF_MEMORY));
+    assert_eq!(events.allocated.load(Ordering::Relaxed), 0);
+    assert_eq!(events.deallocated.load(Ordering::Relaxed), 0);
+
+    assert_eq!(sa.allocate_bulk(layout, &mut objects[..2], BulkMode::AllOrNothing), Ok(2));
+    assert_eq!(events.allocated.load(Ordering::Relaxed), 2);
+    let allocated: Vec<NonNull<u8>> = objects[..2].iter().map(|obj| unsafe { obj.assume_init() }).collect();
+    sa.deallocate_bulk(&allocated, layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    assert_eq!(events.deallocated.load(Ordering::Relaxed), 2);
+
+    pager.release_page(sa.retrieve_empty_page().unwrap().unwrap());
+    assert_eq!(pager.currently_allocated(), 0);
+}
+
+/// Walking a list with a corrupted page reports the corruption instead of panicking.
+#[test]
+pub fn corrupted_page_links_are_reported() {
+    let pager = Pager::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut sa: SCAllocator<ObjectPage8k> = SCAllocator::new(64);
+    sa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    let ptr = sa.allocate(layout).unwrap();
+
+    // Overwrite a link of the (now partial) page without resealing it.
+    let page = unsafe { ObjectPage8k::from_object(ptr.as_ptr() as usize) }.unwrap();
+    let page_ptr = page as *mut ObjectPage8k;
+    *page.prev() = Rawlink::some(unsafe { &mut *page_ptr });
+    assert_eq!(sa.allocate(layout), Err("ObjectPage8k metadata corrupted"));
+    assert_eq!(sa.deallocate(ptr, layout), Err("ObjectPage8k metadata corrupted"));
+
+    *page.prev() = Rawlink::none();
+    page.seal();
+    sa.deallocate(ptr, layout).unwrap();
+    #[cfg(feature = "quarantine")]
+    sa.flush_quarantine();
+    pager.release_page(sa.retrieve_empty_page().unwrap().unwrap());
+
+    let mut csa: ConcurrentSCAllocator<ObjectPage8k> = ConcurrentSCAllocator::new(64);
+    csa.refill(pager.allocate_page().unwrap(), 0).unwrap();
+    let ptr = csa.allocate(layout).unwrap();
+    csa.deallocate(ptr, layout).unwrap();
+    let page = unsafe { ObjectPage8k::from_object(ptr.as_ptr() as usize) }.unwrap();
+    *page.prev() = Rawlink::some(unsafe { &mut *page_ptr });
+    assert!(csa.retrieve_empty_page().is_err());
+
+    *page.prev() = Rawlink::none();
+    page.seal();
+    pager.release_page(csa.retrieve_empty_page().unwrap().unwrap());
+    assert_eq!(pager.currently_allocated(), 0);
+}
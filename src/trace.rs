@@ -0,0 +1,253 @@
+//! A compact binary format for traces of allocator events,
+//! and a recorder that writes it from the allocator hooks.
+//!
+//! A trace starts with a `TRACE_HEADER_SIZE` byte header (`TRACE_MAGIC`,
+//! followed by the version and the record size as little-endian `u32`s),
+//! followed by one `TRACE_RECORD_SIZE` byte record per event:
+//!
+//! | Offset | Size | Content                                     |
+//! |--------|------|---------------------------------------------|
+//! | 0      | 1    | `TraceKind`                                 |
+//! | 1      | 3    | reserved (0)                                |
+//! | 4      | 4    | size class (object size, `u32`)             |
+//! | 8      | 8    | address of the object or page (`u64`)       |
+//! | 16     | 8    | heap id (`u64`)                             |
+//! | 24     | 4    | requested size (`u32`, 0 for refills)       |
+//! | 28     | 4    | requested alignment (`u32`, 0 for refills)  |
+//!
+//! All numbers are little-endian. The `trace_replay` example replays
+//! a trace against a hosted `ZoneAllocator`.
+
+use crate::*;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The first bytes of every trace.
+pub const TRACE_MAGIC: [u8; 8] = *b"SLABTRC\0";
+/// Version of the trace format.
+pub const TRACE_VERSION: u32 = 2;
+/// Size of the header at the start of a trace.
+pub const TRACE_HEADER_SIZE: usize = 16;
+/// Size of one event in a trace.
+pub const TRACE_RECORD_SIZE: usize = 32;
+
+/// What happened in a `TraceEvent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TraceKind {
+    /// An object was allocated.
+    Allocate = 1,
+    /// An object was freed.
+    Deallocate = 2,
+    /// A new page was added to a size class (`addr` is the page address).
+    Refill = 3,
+}
+
+/// One event in a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub kind: TraceKind,
+    /// The size class involved (i.e., its object size).
+    pub size_class: usize,
+    /// Address of the object (or the page for `TraceKind::Refill`).
+    pub addr: usize,
+    pub heap_id: usize,
+    /// The size the object was requested with (0 for `TraceKind::Refill`).
+    pub size: usize,
+    /// The alignment the object was requested with (0 for `TraceKind::Refill`).
+    pub align: usize,
+}
+
+impl TraceEvent {
+    /// Writes the event into the first `TRACE_RECORD_SIZE` bytes of `record`.
+    pub fn encode(&self, record: &mut [u8]) {
+        record[0] = self.kind as u8;
+        record[1..4].copy_from_slice(&[0; 3]);
+        record[4..8].copy_from_slice(&(self.size_class as u32).to_le_bytes());
+        record[8..16].copy_from_slice(&(self.addr as u64).to_le_bytes());
+        record[16..24].copy_from_slice(&(self.heap_id as u64).to_le_bytes());
+        record[24..28].copy_from_slice(&(self.size as u32).to_le_bytes());
+        record[28..32].copy_from_slice(&(self.align as u32).to_le_bytes());
+    }
+
+    /// Reads an event from the first `TRACE_RECORD_SIZE` bytes of `record`.
+    ///
+    /// Returns `None` if the record is too short or has an unknown kind.
+    pub fn decode(record: &[u8]) -> Option<TraceEvent> {
+        if record.len() < TRACE_RECORD_SIZE {
+            return None;
+        }
+        let kind = match record[0] {
+            1 => TraceKind::Allocate,
+            2 => TraceKind::Deallocate,
+            3 => TraceKind::Refill,
+            _ => return None,
+        };
+        let mut size_class = [0; 4];
+        size_class.copy_from_slice(&record[4..8]);
+        let mut addr = [0; 8];
+        addr.copy_from_slice(&record[8..16]);
+        let mut heap_id = [0; 8];
+        heap_id.copy_from_slice(&record[16..24]);
+        let mut size = [0; 4];
+        size.copy_from_slice(&record[24..28]);
+        let mut align = [0; 4];
+        align.copy_from_slice(&record[28..32]);
+
+        Some(TraceEvent {
+            kind,
+            size_class: u32::from_le_bytes(size_class) as usize,
+            addr: u64::from_le_bytes(addr) as usize,
+            heap_id: u64::from_le_bytes(heap_id) as usize,
+            size: u32::from_le_bytes(size) as usize,
+            align: u32::from_le_bytes(align) as usize,
+        })
+    }
+}
+
+/// Records the allocate, free and refill events of an allocator into a buffer,
+/// when set as its hooks (see `ZoneAllocator::set_hooks`).
+///
+/// Events that don't fit into the buffer anymore are counted, but not recorded.
+/// Several CPUs can record at the same time, every event claims its own record.
+pub struct TraceRecorder<'b> {
+    buffer: *mut u8,
+    len: usize,
+    /// Offset of the next record (may grow past `len` once the buffer is full).
+    cursor: AtomicUsize,
+    /// Number of events that didn't fit into the buffer.
+    dropped: AtomicUsize,
+    _buffer: PhantomData<&'b mut [u8]>,
+}
+
+// Every event writes to its own part of the buffer.
+unsafe impl<'b> Send for TraceRecorder<'b> {}
+unsafe impl<'b> Sync for TraceRecorder<'b> {}
+
+impl<'b> TraceRecorder<'b> {
+    /// Creates a recorder that writes a trace into `buffer`.
+    pub fn new(buffer: &'b mut [u8]) -> Result<TraceRecorder<'b>, &'static str> {
+        if buffer.len() < TRACE_HEADER_SIZE {
+            return Err("The buffer is too small for a trace");
+        }
+        buffer[0..8].copy_from_slice(&TRACE_MAGIC);
+        buffer[8..12].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+        buffer[12..16].copy_from_slice(&(TRACE_RECORD_SIZE as u32).to_le_bytes());
+
+        Ok(TraceRecorder {
+            buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            cursor: AtomicUsize::new(TRACE_HEADER_SIZE),
+            dropped: AtomicUsize::new(0),
+            _buffer: PhantomData,
+        })
+    }
+
+    /// Appends `event` to the trace.
+    pub fn record(&self, event: TraceEvent) {
+        let offset = self.cursor.fetch_add(TRACE_RECORD_SIZE, Ordering::Relaxed);
+        if offset + TRACE_RECORD_SIZE > self.len {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let record = unsafe { core::slice::from_raw_parts_mut(self.buffer.add(offset), TRACE_RECORD_SIZE) };
+        event.encode(record);
+    }
+
+    /// Number of events in the trace.
+    pub fn events(&self) -> usize {
+        (self.trace_len() - TRACE_HEADER_SIZE) / TRACE_RECORD_SIZE
+    }
+
+    /// Number of events that were lost because the buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The trace (header and all recorded events).
+    ///
+    /// This should only be called once the allocator stopped recording,
+    /// otherwise the last records may still be written to.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.buffer, self.trace_len()) }
+    }
+
+    fn trace_len(&self) -> usize {
+        let full_records = (self.len - TRACE_HEADER_SIZE) / TRACE_RECORD_SIZE;
+        core::cmp::min(
+            self.cursor.load(Ordering::Relaxed),
+            TRACE_HEADER_SIZE + full_records * TRACE_RECORD_SIZE,
+        )
+    }
+}
+
+impl<'b> AllocatorHooks for TraceRecorder<'b> {
+    fn on_allocate(&self, addr: usize, layout: Layout, size_class: usize, heap_id: usize) {
+        self.record(TraceEvent {
+            kind: TraceKind::Allocate,
+            size_class,
+            addr,
+            heap_id,
+            size: layout.size(),
+            align: layout.align(),
+        });
+    }
+
+    fn on_deallocate(&self, addr: usize, layout: Layout, size_class: usize, heap_id: usize) {
+        self.record(TraceEvent {
+            kind: TraceKind::Deallocate,
+            size_class,
+            addr,
+            heap_id,
+            size: layout.size(),
+            align: layout.align(),
+        });
+    }
+
+    fn on_refill(&self, page: usize, size_class: usize, heap_id: usize) {
+        self.record(TraceEvent { kind: TraceKind::Refill, size_class, addr: page, heap_id, size: 0, align: 0 });
+    }
+}
+
+/// Iterates over the events of a trace.
+pub struct TraceReader<'b> {
+    records: core::slice::ChunksExact<'b, u8>,
+}
+
+impl<'b> TraceReader<'b> {
+    /// Checks the header of `trace` and returns a reader for its events.
+    ///
+    /// A truncated record at the end of the trace is ignored.
+    pub fn new(trace: &'b [u8]) -> Result<TraceReader<'b>, &'static str> {
+        if trace.len() < TRACE_HEADER_SIZE || trace[0..8] != TRACE_MAGIC {
+            return Err("Not an allocator trace");
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(&trace[8..12]);
+        let mut record_size = [0; 4];
+        record_size.copy_from_slice(&trace[12..16]);
+        if u32::from_le_bytes(version) != TRACE_VERSION
+            || u32::from_le_bytes(record_size) as usize != TRACE_RECORD_SIZE
+        {
+            return Err("Unsupported version of the trace format");
+        }
+
+        Ok(TraceReader {
+            records: trace[TRACE_HEADER_SIZE..].chunks_exact(TRACE_RECORD_SIZE),
+        })
+    }
+}
+
+impl<'b> Iterator for TraceReader<'b> {
+    type Item = TraceEvent;
+
+    /// Returns the next event, skipping records of unknown kinds.
+    fn next(&mut self) -> Option<TraceEvent> {
+        loop {
+            let record = self.records.next()?;
+            if let Some(event) = TraceEvent::decode(record) {
+                return Some(event);
+            }
+        }
+    }
+}
@@ -3,6 +3,7 @@
 //! The ZoneAllocator achieves this by having many `SCAllocator`
 
 use crate::*;
+use core::mem::MaybeUninit;
 
 /// Creates an instance of a zone, we do this in a macro because we
 /// re-use the code in const and non-const functions
@@ -10,6 +11,16 @@ use crate::*;
 /// We can get rid of this once the const fn feature is fully stabilized.
 macro_rules! new_zone {
     () => {
+        new_zone!(
+            None,
+            0,
+            Watermarks {
+                high_empty_pages: None,
+                low_free_slots: 0,
+            }
+        )
+    };
+    ($page_source:expr, $heap_id:expr, $watermarks:expr) => {
         ZoneAllocator {
             // TODO(perf): We should probably pick better classes
             // rather than powers-of-two (see SuperMalloc etc.)
@@ -24,12 +35,95 @@ macro_rules! new_zone {
                 SCAllocator::new(1 << 10), // 1024 (TODO: maybe get rid of this class?)
                 SCAllocator::new(1 << 11), // 2048 (TODO: maybe get rid of this class?)
                 SCAllocator::new(1 << 12), // 4096 
-                SCAllocator::new(ZoneAllocator::MAX_ALLOC_SIZE),    // 8104 (can't do 8192 because of metadata in ObjectPage)
-            ]
+                SCAllocator::new(Self::MAX_ALLOC_SIZE),    // MAX_ALLOC_SIZE (can't do 8192 because of metadata in ObjectPage8k)
+            ],
+            page_source: $page_source,
+            home_heap_id: $heap_id,
+            watermarks: $watermarks,
+            low_memory_callback: None,
+            low_signalled: [false; MAX_BASE_SIZE_CLASSES],
+            depot: None,
+            depot_limits: DepotLimits {
+                push_above: 0,
+                max_pages: None,
+            },
+            drop_policy: DropPolicy::Leak,
+            checkpoint: None,
+            #[cfg(feature = "leak-tracking")]
+            leak_tracker: None,
+            #[cfg(feature = "hooks")]
+            hooks: None,
         }
     };
 }
 
+/// Limits on how a `ZoneAllocator` uses a shared `PageDepot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepotLimits {
+    /// Once the zone holds more than this number of empty pages
+    /// it pushes the surplus into the depot (after a deallocation).
+    pub push_above: usize,
+    /// The zone doesn't pull pages from the depot once it has this many pages
+    /// (in total, over all size classes).
+    pub max_pages: Option<usize>,
+}
+
+/// Watermarks on the free capacity of a `ZoneAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// If set, empty pages above this number are handed back to the page source
+    /// after a deallocation.
+    pub high_empty_pages: Option<usize>,
+    /// A size class is low on memory once it has fewer free slots than this,
+    /// which triggers the low-memory callback.
+    pub low_free_slots: usize,
+}
+
+/// How many pages of a size class to move with `ZoneAllocator::split_into`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitAmount {
+    /// Move (up to) this number of pages.
+    Pages(usize),
+    /// Move `numerator / denominator` of the available pages (rounded down).
+    Fraction { numerator: usize, denominator: usize },
+}
+
+impl SplitAmount {
+    /// The number of pages to move if `available` pages are available.
+    pub(crate) fn of(&self, available: usize) -> usize {
+        match *self {
+            SplitAmount::Pages(pages) => core::cmp::min(pages, available),
+            SplitAmount::Fraction { numerator, denominator } => {
+                // The product of two `usize` always fits into a `u128`,
+                // a zero denominator moves nothing.
+                (available as u128 * numerator as u128)
+                    .checked_div(denominator as u128)
+                    .map_or(0, |pages| core::cmp::min(pages, available as u128) as usize)
+            }
+        }
+    }
+}
+
+/// Decides which pages `ZoneAllocator::split_into` moves, applied to every size class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitPolicy {
+    /// How many of the empty pages to move.
+    pub empty: SplitAmount,
+    /// How many of the partially used pages to move.
+    ///
+    /// The live objects in these pages then belong to the other heap.
+    pub partial: SplitAmount,
+}
+
+/// Called when a size class falls below the low watermark.
+///
+/// Receives the heap id, the size class (i.e., its object size)
+/// and the number of free slots left in the class.
+///
+/// This is invoked from within the allocator, so it must not allocate from
+/// the same zone; it is meant to wake up a task that prefetches pages.
+pub type LowMemoryCallback = fn(heap_id: usize, size_class: usize, free_slots: usize);
+
 /// A zone allocator for arbitrary sized allocations.
 ///
 /// Has a bunch of `SCAllocator` and through that can serve allocation
@@ -38,61 +132,156 @@ macro_rules! new_zone {
 ///
 /// The allocator provides to refill functions `refill` and `refill_large`
 /// to provide the underlying `SCAllocator` with more memory in case it runs out.
-pub struct ZoneAllocator<'a> {
-    small_slabs: [SCAllocator<'a, ObjectPage8k<'a>>; ZoneAllocator::MAX_BASE_SIZE_CLASSES],
+///
+/// Alternatively, a `PageSource` can be set with `set_page_source`. The zone then
+/// refills itself from the source when it runs out of memory, and `trim`
+/// hands empty pages back to it.
+pub struct ZoneAllocator<'a, P: AllocablePage = ObjectPage8k<'a>> {
+    small_slabs: [SCAllocator<'a, P>; MAX_BASE_SIZE_CLASSES],
     // big_slabs: [SCAllocator<'a, LargeObjectPage<'a>>; ZoneAllocator::MAX_LARGE_SIZE_CLASSES],
+    /// Where we get new pages from (and give them back to), if set.
+    page_source: Option<&'a dyn PageSource>,
+    /// The heap id we stamp pages from `page_source` and `depot` with.
+    home_heap_id: usize,
+    /// Watermarks on the free capacity.
+    watermarks: Watermarks,
+    /// Invoked when a size class falls below the low watermark.
+    low_memory_callback: Option<LowMemoryCallback>,
+    /// Size classes for which we already invoked `low_memory_callback`
+    /// (we only invoke it again once the class recovered).
+    low_signalled: [bool; MAX_BASE_SIZE_CLASSES],
+    /// A depot of empty pages shared with other heaps, if set.
+    depot: Option<&'a dyn Depot<'a, P>>,
+    /// How this zone uses `depot`.
+    depot_limits: DepotLimits,
+    /// What happens to our pages when the zone is dropped.
+    drop_policy: DropPolicy,
+    /// The checkpoint buffer and the live objects at the last checkpoint, if any (see `checkpoint`).
+    checkpoint: Option<HeapCheckpoint<'a>>,
+    /// Records where the live objects were allocated, if set.
+    #[cfg(feature = "leak-tracking")]
+    leak_tracker: Option<LeakTracker<'a>>,
+    /// Called on allocator events, if set.
+    #[cfg(feature = "hooks")]
+    hooks: Option<&'a dyn AllocatorHooks>,
 }
 
-impl<'a> Default for ZoneAllocator<'a> {
-    fn default() -> ZoneAllocator<'a> {
+impl<'a, P: AllocablePage> Default for ZoneAllocator<'a, P> {
+    fn default() -> ZoneAllocator<'a, P> {
         new_zone!()
     }
 }
 
+impl<'a, P: AllocablePage> Drop for ZoneAllocator<'a, P> {
+    fn drop(&mut self) {
+        #[cfg(feature = "leak-tracking")]
+        let _ = self.leak_report(|site| {
+            error!(
+                "Leaked {} objects of size {} allocated at {} (first one #{})",
+                site.count, site.size_class, site.location, site.first_seq
+            )
+        });
+
+        let mode = match self.drop_policy {
+            DropPolicy::Leak => return,
+            DropPolicy::ReleaseIfUnused => DrainMode::Refuse,
+            DropPolicy::Release => DrainMode::Report,
+        };
+
+        match self.drain(mode) {
+            Ok((pages, live)) => {
+                if live > 0 {
+                    error!("Dropped a zone with {} live objects, their pages are unmapped", live);
+                }
+                match self.page_source {
+                    Some(source) => pages.for_each(|mp| source.release_page(mp)),
+                    None => drop(pages),
+                }
+            }
+            Err(_e) => error!("Dropped a zone with {} live objects, leaking its pages", self.live_objects()),
+        }
+    }
+}
+
+/// Where an allocation was requested (only recorded with the `leak-tracking` feature).
+#[cfg(feature = "leak-tracking")]
+pub(crate) type CallSite = &'static core::panic::Location<'static>;
+#[cfg(not(feature = "leak-tracking"))]
+#[derive(Clone, Copy)]
+pub(crate) struct CallSite;
+
+/// The call site of our caller (which passes on its own call site if it is `#[track_caller]`).
+#[cfg(feature = "leak-tracking")]
+#[track_caller]
+pub(crate) fn call_site() -> CallSite {
+    core::panic::Location::caller()
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+pub(crate) fn call_site() -> CallSite {
+    CallSite
+}
+
+/// How many size classes (i.e., `SCAllocator`s) a `ZoneAllocator` has.
+pub(crate) const MAX_BASE_SIZE_CLASSES: usize = 11;
+
 #[allow(dead_code)]
-enum Slab {
+pub(crate) enum Slab {
+    /// Zero-sized objects don't need any memory.
+    ZeroSized,
     Base(usize),
     Large(usize),
     Unsupported,
 }
 
 
-impl<'a> ZoneAllocator<'a> {
-    /// Maximum size that allocated within 2 pages. (8 KiB - 88 bytes)
+impl<'a, P: AllocablePage> ZoneAllocator<'a, P> {
+    /// Maximum size that allocated within 2 pages (8 KiB minus the page meta-data,
+    /// the red zone and the tag of the object).
     /// This is also the maximum object size that this allocator can handle.
-    pub const MAX_ALLOC_SIZE: usize = ObjectPage8k::SIZE - ObjectPage8k::METADATA_SIZE;
+    pub const MAX_ALLOC_SIZE: usize = P::SIZE - P::METADATA_SIZE - RED_ZONE_SIZE - P::TAG_SIZE;
 
     /// Maximum size which is allocated with ObjectPages8k (4 KiB pages).
     ///
-    /// e.g. this is 8 KiB - 88 bytes of meta-data.
-    pub const MAX_BASE_ALLOC_SIZE: usize = ZoneAllocator::MAX_ALLOC_SIZE;
+    /// e.g. this is 8 KiB minus the meta-data at the end of the page.
+    pub const MAX_BASE_ALLOC_SIZE: usize = Self::MAX_ALLOC_SIZE;
 
-    /// How many allocators of type SCAllocator<ObjectPage8k> we have.
-    pub const MAX_BASE_SIZE_CLASSES: usize = 11;
+    /// How many allocators of type SCAllocator<P> we have.
+    pub const MAX_BASE_SIZE_CLASSES: usize = MAX_BASE_SIZE_CLASSES;
 
     /// The set of sizes the allocator has lists for.
-    pub const BASE_ALLOC_SIZES: [usize; ZoneAllocator::MAX_BASE_SIZE_CLASSES] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, ZoneAllocator::MAX_BASE_ALLOC_SIZE];
-
-    /// A slab must have greater than this number of empty pages to return one.
-    const SLAB_EMPTY_PAGES_THRESHOLD: usize = 0;
+    pub const BASE_ALLOC_SIZES: [usize; MAX_BASE_SIZE_CLASSES] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, Self::MAX_BASE_ALLOC_SIZE];
 
     #[cfg(feature = "unstable")]
-    pub const fn new() -> ZoneAllocator<'a> {
+    pub const fn new() -> ZoneAllocator<'a, P> {
         new_zone!()
     }
 
     #[cfg(not(feature = "unstable"))]
-    pub fn new() -> ZoneAllocator<'a> {
+    pub fn new() -> ZoneAllocator<'a, P> {
         new_zone!()
     }
 
+    /// Creates a zone that refills itself from `source` (see `set_page_source`)
+    /// and uses the given `watermarks`.
+    #[cfg(feature = "unstable")]
+    pub const fn with_page_source(source: &'a dyn PageSource, heap_id: usize, watermarks: Watermarks) -> ZoneAllocator<'a, P> {
+        new_zone!(Some(source), heap_id, watermarks)
+    }
+
+    #[cfg(not(feature = "unstable"))]
+    pub fn with_page_source(source: &'a dyn PageSource, heap_id: usize, watermarks: Watermarks) -> ZoneAllocator<'a, P> {
+        new_zone!(Some(source), heap_id, watermarks)
+    }
+
 
     /// Return maximum size an object of size `current_size` can use.
     ///
     /// Used to optimize `realloc`.
     pub fn get_max_size(current_size: usize) -> Option<usize> {
         match current_size {
-            0..=8 => Some(8),
+            0 => Some(0),
+            1..=8 => Some(8),
             9..=16 => Some(16),
             17..=32 => Some(32),
             33..=64 => Some(64),
@@ -102,15 +291,56 @@ impl<'a> ZoneAllocator<'a> {
             513..=1024 => Some(1024),
             1025..=2048 => Some(2048),
             2049..=4096 => Some(4096),
-            4097..=ZoneAllocator::MAX_ALLOC_SIZE => Some(ZoneAllocator::MAX_ALLOC_SIZE),
+            4097.. if current_size <= Self::MAX_ALLOC_SIZE => Some(Self::MAX_ALLOC_SIZE),
             _ => None,
         }
     }
 
+    /// Checks if an object at `ptr`, allocated with `old_layout`, can be used as is
+    /// for `new_layout`.
+    ///
+    /// This is the case if both layouts are served by the same size class
+    /// (taking their alignment into account) and `ptr` satisfies the alignment of `new_layout`.
+    pub fn fits_in_place(ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> bool {
+        let same_class = match (
+            Self::get_max_size(Self::class_size(old_layout)),
+            Self::get_max_size(Self::class_size(new_layout)),
+        ) {
+            (Some(old_class), Some(new_class)) => old_class == new_class,
+            _ => false,
+        };
+        same_class && (ptr.as_ptr() as usize).is_multiple_of(new_layout.align())
+    }
+
+    /// The size by which we pick the size class for `layout`.
+    ///
+    /// Pages are aligned and objects are laid out back to back from the start of
+    /// the page, so objects of the power-of-two size classes are aligned to their size.
+    /// An alignment larger than the requested size therefore selects a larger class.
+    /// The largest class holds a single object at the start of the page,
+    /// it serves alignments up to the page size.
+    pub(crate) fn class_size(layout: Layout) -> usize {
+        if layout.size() == 0 || layout.align() <= layout.size() {
+            layout.size()
+        } else if layout.align() <= 4096 {
+            layout.align()
+        } else if layout.align() <= P::SIZE {
+            core::cmp::max(layout.size(), 4096 + 1)
+        } else {
+            usize::MAX
+        }
+    }
+
+    /// A well-aligned dangling pointer that we hand out for zero-sized `layout`s.
+    pub(crate) fn dangling(layout: Layout) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(layout.align() as *mut u8) }
+    }
+
     /// Figure out index into zone array to get the correct slab allocator for that size.
-    fn get_slab(requested_size: usize) -> Slab {
+    pub(crate) fn get_slab(requested_size: usize) -> Slab {
         match requested_size {
-            0..=8 => Slab::Base(0),
+            0 => Slab::ZeroSized,
+            1..=8 => Slab::Base(0),
             9..=16 => Slab::Base(1),
             17..=32 => Slab::Base(2),
             33..=64 => Slab::Base(3),
@@ -120,51 +350,739 @@ impl<'a> ZoneAllocator<'a> {
             513..=1024 => Slab::Base(7),
             1025..=2048 => Slab::Base(8),
             2049..=4096 => Slab::Base(9),
-            4097..=ZoneAllocator::MAX_ALLOC_SIZE => Slab::Base(10),
+            4097.. if requested_size <= Self::MAX_ALLOC_SIZE => Slab::Base(10),
             _ => Slab::Unsupported,
         }
     }
 }
 
-impl<'a> ZoneAllocator<'a> {
+impl<'a, P: AllocablePage> ZoneAllocator<'a, P> {
     /// Returns the heap id from the first page of the first slab
     pub fn heap_id(&self) -> Result<usize, &'static str> {
         self.small_slabs[0].heap_id().ok_or("There were no pages in the heap")
     }
 
     /// Removes all the pages of `allocator` and adds them to the appropriate lists in this allocator.
-    pub fn merge(&mut self, allocator: &mut ZoneAllocator<'a>, heap_id: usize) -> Result<(), &'static str> {
-        for size in &ZoneAllocator::BASE_ALLOC_SIZES {
-            match ZoneAllocator::get_slab(*size) {
+    pub fn merge(&mut self, allocator: &mut ZoneAllocator<'a, P>, heap_id: usize) -> Result<(), &'static str> {
+        for size in &Self::BASE_ALLOC_SIZES {
+            match Self::get_slab(*size) {
                 Slab::Base(idx) => {
+                    #[cfg(feature = "leak-tracking")]
+                    let merged = allocator.small_slabs[idx].live_objects();
                     self.small_slabs[idx].merge(&mut allocator.small_slabs[idx], heap_id)?;
+                    // Our tracker didn't see the objects of `allocator` being allocated.
+                    #[cfg(feature = "leak-tracking")]
+                    if let Some(tracker) = self.leak_tracker.as_mut() {
+                        tracker.add_foreign(merged);
+                    }
                 }
-                Slab::Large(_idx) => return Err("AllocationError::InvalidLayout"),
-                Slab::Unsupported => return Err("AllocationError::InvalidLayout"),
+                Slab::ZeroSized => return Err(INVALID_LAYOUT),
+                Slab::Large(_idx) => return Err(INVALID_LAYOUT),
+                Slab::Unsupported => return Err(INVALID_LAYOUT),
             }
         }
         Ok(())
     }
 
-        /// Returns an ObjectPage from the SCAllocator with the maximum number of empty pages,
+    /// Moves some of the pages of this allocator into `allocator` (i.e., the opposite of `merge`).
+    ///
+    /// The pages are chosen per size class according to `policy` and are stamped with `heap_id`.
+    /// This is used to seed a new (e.g., per-CPU) heap from an existing one.
+    /// Returns the number of pages that were moved.
+    pub fn split_into(&mut self, allocator: &mut ZoneAllocator<'a, P>, heap_id: usize, policy: SplitPolicy) -> Result<usize, &'static str> {
+        let mut moved = 0;
+        for size in &Self::BASE_ALLOC_SIZES {
+            match Self::get_slab(*size) {
+                Slab::Base(idx) => {
+                    let sca = &mut self.small_slabs[idx];
+                    let empty_pages = policy.empty.of(sca.empty_slabs.elements);
+                    let partial_pages = policy.partial.of(sca.slabs.elements);
+                    moved += sca.split_into(&mut allocator.small_slabs[idx], heap_id, empty_pages, partial_pages)?;
+                    self.check_low_watermark(idx);
+                }
+                Slab::ZeroSized => return Err(INVALID_LAYOUT),
+                Slab::Large(_idx) => return Err(INVALID_LAYOUT),
+                Slab::Unsupported => return Err(INVALID_LAYOUT),
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Allocates up to `objects.len()` blocks of memory described by `layout`
+    /// and writes their addresses to `objects`.
+    ///
+    /// The zone refills the size class (like `allocate`) until all objects are
+    /// allocated or no more memory can be found. See `SCAllocator::allocate_bulk`.
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    pub fn allocate_bulk(
+        &mut self,
+        layout: Layout,
+        objects: &mut [MaybeUninit<NonNull<u8>>],
+        mode: BulkMode,
+    ) -> Result<usize, &'static str> {
+        match Self::get_slab(Self::class_size(layout)) {
+            Slab::Base(idx) => {
+                let mut allocated = self.small_slabs[idx].allocate_bulk(layout, objects, BulkMode::Partial)?;
+                while allocated < objects.len() && self.refill_on_oom(layout).is_ok() {
+                    let more = self.small_slabs[idx].allocate_bulk(layout, &mut objects[allocated..], BulkMode::Partial)?;
+                    if more == 0 {
+                        break;
+                    }
+                    allocated += more;
+                }
+
+                let allocated_objects = unsafe {
+                    &*(&objects[..allocated] as *const [MaybeUninit<NonNull<u8>>] as *const [NonNull<u8>])
+                };
+                if allocated < objects.len() && mode == BulkMode::AllOrNothing {
+                    self.small_slabs[idx].deallocate_bulk(allocated_objects, layout)?;
+                    return Err(OUT_OF_MEMORY);
+                }
+
+                // Don't hand out (and leak) objects whose tags can't be set.
+                #[cfg(feature = "tagging")]
+                for obj in allocated_objects {
+                    if let Err(e) = self.small_slabs[idx].set_tag(*obj, UNTAGGED) {
+                        let _ = self.small_slabs[idx].deallocate_bulk(allocated_objects, layout);
+                        return Err(e);
+                    }
+                }
+                let caller = call_site();
+                for obj in allocated_objects {
+                    self.track_allocation(*obj, idx, caller);
+                }
+                self.check_low_watermark(idx);
+                Ok(allocated)
+            }
+            Slab::ZeroSized => {
+                for obj in objects.iter_mut() {
+                    *obj = MaybeUninit::new(Self::dangling(layout));
+                }
+                Ok(objects.len())
+            }
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
+        }
+    }
+
+    /// Deallocates many objects described by `layout` at once, see `SCAllocator::deallocate_bulk`.
+    pub fn deallocate_bulk(&mut self, objects: &[NonNull<u8>], layout: Layout) -> Result<(), &'static str> {
+        match Self::get_slab(Self::class_size(layout)) {
+            Slab::Base(idx) => {
+                let ret = self.small_slabs[idx].deallocate_bulk(objects, layout);
+                for obj in objects {
+                    self.untrack_allocation(*obj);
+                    self.forget_in_checkpoint(*obj, idx);
+                }
+                self.push_to_depot();
+                self.check_high_watermark();
+                self.check_low_watermark(idx);
+                ret
+            }
+            // Nothing was allocated for zero-sized objects.
+            Slab::ZeroSized => Ok(()),
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
+        }
+    }
+
+    /// Returns an ObjectPage from the SCAllocator with the maximum number of empty pages,
     /// if there are more empty pages than the threshold.
+    ///
+    /// Fails if the metadata of the empty pages is corrupted.
     pub fn retrieve_empty_page(
         &mut self
-    ) -> Option<MappedPages> {
-        let (max_empty_pages, idx) = self.small_slab_with_max_empty_pages();
-        if max_empty_pages > ZoneAllocator::SLAB_EMPTY_PAGES_THRESHOLD {
+    ) -> Result<Option<MappedPages>, &'static str> {
+        let (surplus, idx) = self.small_slab_with_max_surplus();
+        if surplus > 0 {
             self.small_slabs[idx].retrieve_empty_page()
         }
         else {
-            None
+            Ok(None)
         }
     }
 
+    /// Sets how many empty pages the size class serving `size` keeps
+    /// when pages are retrieved or reclaimed from this zone (0 by default).
+    pub fn set_empty_pages_threshold(&mut self, size: usize, threshold: usize) -> Result<(), &'static str> {
+        match Self::get_slab(size) {
+            Slab::Base(idx) => {
+                self.small_slabs[idx].set_empty_pages_threshold(threshold);
+                Ok(())
+            }
+            Slab::ZeroSized => Err(INVALID_LAYOUT),
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
+        }
+    }
+
+    /// Takes up to `target_pages` empty pages out of this zone, choosing them according to `policy`.
+    ///
+    /// Fewer pages are returned if not enough empty pages exist above the
+    /// per class thresholds. Fails if the metadata of the empty pages is corrupted
+    /// (the pages reclaimed until then are unmapped).
+    pub fn reclaim(&mut self, target_pages: usize, policy: ReclaimPolicy) -> Result<ReclaimedPages<'a, P>, &'static str> {
+        let mut reclaimed = ReclaimedPages::new();
+
+        match policy {
+            ReclaimPolicy::InClassOrder => {
+                for sca in self.small_slabs.iter_mut() {
+                    while reclaimed.len() < target_pages && sca.surplus_empty_pages() > 0 {
+                        match sca.take_empty_page()? {
+                            Some(page) => reclaimed.push(page)?,
+                            None => break,
+                        }
+                    }
+                }
+            }
+            ReclaimPolicy::LargestPool => {
+                while reclaimed.len() < target_pages {
+                    let (surplus, idx) = self.small_slab_with_max_surplus();
+                    if surplus == 0 {
+                        break;
+                    }
+                    match self.small_slabs[idx].take_empty_page()? {
+                        Some(page) => reclaimed.push(page)?,
+                        None => break,
+                    }
+                }
+            }
+            ReclaimPolicy::OldestEmpty => {
+                while reclaimed.len() < target_pages {
+                    let mut oldest: Option<(usize, usize)> = None;
+                    for (idx, sca) in self.small_slabs.iter_mut().enumerate() {
+                        if sca.surplus_empty_pages() == 0 {
+                            continue;
+                        }
+                        if let Some(since) = sca.oldest_empty_since() {
+                            let older = match oldest {
+                                Some((oldest_since, _)) => since < oldest_since,
+                                None => true,
+                            };
+                            if older {
+                                oldest = Some((since, idx));
+                            }
+                        }
+                    }
+
+                    let page = match oldest {
+                        Some((_, idx)) => self.small_slabs[idx].take_oldest_empty_page()?,
+                        None => None,
+                    };
+                    match page {
+                        Some(page) => reclaimed.push(page)?,
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// The number of objects that are currently allocated from this zone.
+    pub fn live_objects(&mut self) -> usize {
+        self.small_slabs.iter_mut().map(|sca| sca.live_objects()).sum()
+    }
+
+    /// Takes every page out of this zone, including partially used and full pages.
+    ///
+    /// If objects are still allocated, `mode` decides whether the zone keeps
+    /// its pages and returns an error (`DrainMode::Refuse`), or whether the pages are
+    /// taken out anyway (`DrainMode::Report`). Returns the pages along with the
+    /// number of objects that were still allocated in them.
+    pub fn drain(&mut self, mode: DrainMode) -> Result<(ReclaimedPages<'a, P>, usize), &'static str> {
+        let live = self.live_objects();
+        if live > 0 && mode == DrainMode::Refuse {
+            return Err("Objects are still allocated from the zone");
+        }
+
+        let mut pages = ReclaimedPages::new();
+        for sca in self.small_slabs.iter_mut() {
+            sca.drain_into(&mut pages)?;
+        }
+        self.low_signalled = [false; MAX_BASE_SIZE_CLASSES];
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.clear();
+        }
+        #[cfg(feature = "leak-tracking")]
+        if let Some(tracker) = self.leak_tracker.as_mut() {
+            tracker.clear();
+        }
+        Ok((pages, live))
+    }
+
+    /// Consumes the zone and returns all of its pages.
+    ///
+    /// Fails (and gives the zone back) if objects are still allocated from it.
+    // Giving the zone back is the point of the error, and we can't box it
+    // (we may be the heap).
+    #[allow(clippy::result_large_err)]
+    pub fn into_pages(mut self) -> Result<ReclaimedPages<'a, P>, ZoneAllocator<'a, P>> {
+        match self.drain(DrainMode::Refuse) {
+            Ok((pages, _live)) => Ok(pages),
+            Err(_e) => Err(self),
+        }
+    }
+
+    /// The number of pages that hold objects, i.e., the number of snapshots a checkpoint needs.
+    pub fn used_pages(&self) -> usize {
+        self.pages() - self.empty_pages()
+    }
+
+    /// Sets the buffer that checkpoints are stored in, with room for one snapshot per page
+    /// that holds objects (see `used_pages`).
+    ///
+    /// This drops the current checkpoint (if any) and returns the previous buffer.
+    pub fn set_checkpoint_buffer(&mut self, buffer: &'a mut [PageSnapshot]) -> Option<&'a mut [PageSnapshot]> {
+        self.checkpoint
+            .replace(HeapCheckpoint::new(buffer))
+            .map(HeapCheckpoint::into_buffer)
+    }
+
+    /// Records which objects are currently live in the checkpoint buffer
+    /// (see `set_checkpoint_buffer`), replacing the previous checkpoint.
+    ///
+    /// The zone keeps the checkpoint until `clear_checkpoint` is called,
+    /// and removes the objects that are freed from it.
+    /// Returns an error if there is no buffer or it is too small.
+    pub fn checkpoint(&mut self) -> Result<(), &'static str> {
+        let used_pages = self.used_pages();
+        let checkpoint = self.checkpoint.as_mut().ok_or("The zone allocator has no checkpoint buffer")?;
+        let snapshots = checkpoint.snapshots_mut();
+        if snapshots.len() < used_pages {
+            return Err("The buffer is too small for a checkpoint of the zone");
+        }
+
+        let mut len = 0;
+        for sca in self.small_slabs.iter_mut() {
+            let size_class = sca.size;
+            sca.for_each_used_page(|page| {
+                HeapCheckpoint::snapshot(&mut snapshots[len], page, size_class);
+                len += 1;
+            });
+        }
+        checkpoint.take(len);
+        // Quarantined objects were freed already.
+        #[cfg(feature = "quarantine")]
+        for sca in self.small_slabs.iter() {
+            for addr in sca.quarantined() {
+                let (page, slot) = Self::slot_of(addr, sca.size);
+                checkpoint.forget(page, sca.size, slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the checkpoint of this zone (deallocations don't have to update it any more)
+    /// and gives back its buffer.
+    pub fn clear_checkpoint(&mut self) -> Option<&'a mut [PageSnapshot]> {
+        self.checkpoint.take().map(HeapCheckpoint::into_buffer)
+    }
+
+    /// Counts the objects that are live now but weren't at the checkpoint, per size class.
+    ///
+    /// Returns an error if no checkpoint was taken.
+    pub fn diff(&mut self) -> Result<HeapDiff, &'static str> {
+        self.diff_with(|_addr, _size_class| {})
+    }
+
+    /// Like `diff`, but also calls `f` with the address and size class of every new object.
+    pub fn diff_with<F: FnMut(usize, usize)>(&mut self, mut f: F) -> Result<HeapDiff, &'static str> {
+        let checkpoint = self
+            .checkpoint
+            .as_ref()
+            .filter(|checkpoint| checkpoint.is_taken())
+            .ok_or("The zone allocator has no checkpoint")?;
+        let mut diff = HeapDiff::new(Self::BASE_ALLOC_SIZES);
+        for (idx, sca) in self.small_slabs.iter_mut().enumerate() {
+            let size_class = sca.size;
+            sca.for_each_live_object(|page, slot| {
+                if !checkpoint.was_allocated(page.data_addr(), size_class, slot) {
+                    diff.add(idx);
+                    f(page.data_addr() + slot * slot_size(size_class), size_class);
+                }
+            });
+        }
+        Ok(diff)
+    }
+
+    /// Sets what happens to the pages of this zone when it is dropped.
+    ///
+    /// Pages that are given back go to the page source if one is set,
+    /// otherwise their `MappedPages` are dropped.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// What happens to the pages of this zone when it is dropped.
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    /// Allocates a block of memory described by `layout` and records `location`
+    /// as its call site (instead of our caller's).
+    ///
+    /// This is for wrappers that know where an allocation came from, but can't
+    /// pass it on through `#[track_caller]` (e.g., a global allocator).
+    #[cfg(feature = "leak-tracking")]
+    pub fn allocate_at(
+        &mut self,
+        layout: Layout,
+        location: &'static core::panic::Location<'static>,
+    ) -> Result<NonNull<u8>, &'static str> {
+        self.allocate_from(layout, location)
+    }
+
+    /// Allocates a block of memory described by `layout` on behalf of `caller`.
+    pub(crate) fn allocate_from(&mut self, layout: Layout, caller: CallSite) -> Result<NonNull<u8>, &'static str> {
+        match Self::get_slab(Self::class_size(layout)) {
+            Slab::Base(idx) => {
+                let ret = match self.small_slabs[idx].allocate(layout) {
+                    Ok(ptr) => Ok(ptr),
+                    // The class is exhausted, report it even if we can't refill it.
+                    Err(OUT_OF_MEMORY) => self
+                        .refill_on_oom(layout)
+                        .and_then(|_| self.small_slabs[idx].allocate(layout)),
+                    Err(e) => Err(e),
+                };
+                if let Ok(ptr) = ret {
+                    // Don't hand out (and leak) an object whose tag can't be set.
+                    #[cfg(feature = "tagging")]
+                    if let Err(e) = self.small_slabs[idx].set_tag(ptr, UNTAGGED) {
+                        let _ = self.small_slabs[idx].deallocate(ptr, layout);
+                        return Err(e);
+                    }
+                    self.track_allocation(ptr, idx, caller);
+                }
+                self.check_low_watermark(idx);
+                ret
+            }
+            Slab::ZeroSized => Ok(Self::dangling(layout)),
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
+        }
+    }
+
+    /// Allocates a block of memory described by `layout` and records `tag` for it.
+    #[cfg(feature = "tagging")]
+    #[cfg_attr(feature = "leak-tracking", track_caller)]
+    pub fn allocate_tagged(&mut self, layout: Layout, tag: AllocTag) -> Result<NonNull<u8>, &'static str> {
+        let ptr = self.allocate_from(layout, call_site())?;
+        if let Slab::Base(idx) = Self::get_slab(Self::class_size(layout)) {
+            if let Err(e) = self.small_slabs[idx].set_tag(ptr, tag) {
+                let _ = crate::Allocator::deallocate(self, ptr, layout);
+                return Err(e);
+            }
+        }
+        Ok(ptr)
+    }
+
+    /// Adds up the live objects of this zone per tag: `usage[tag]` is set to the usage of `tag`.
+    ///
+    /// Tags that don't have an entry in `usage` are not counted.
+    #[cfg(feature = "tagging")]
+    pub fn tag_usage(&mut self, usage: &mut [TagUsage]) {
+        for entry in usage.iter_mut() {
+            *entry = TagUsage::default();
+        }
+        for sca in self.small_slabs.iter_mut() {
+            let (size_class, obj_per_page) = (sca.size, sca.obj_per_page);
+            sca.for_each_live_object(|page, slot| {
+                if let Some(entry) = usage.get_mut(page.tags(obj_per_page)[slot] as usize) {
+                    entry.objects += 1;
+                    entry.bytes += size_class;
+                }
+            });
+        }
+    }
+
+    /// Starts recording where the live objects of this zone are allocated,
+    /// using `entries` as the table (it holds one object per entry).
+    ///
+    /// Objects that were allocated before are not tracked
+    /// (freeing them doesn't count as freeing an untracked object).
+    #[cfg(feature = "leak-tracking")]
+    pub fn set_leak_tracker(&mut self, entries: &'a mut [Option<LeakEntry>]) {
+        let live = self.live_objects();
+        let mut tracker = LeakTracker::new(entries);
+        tracker.add_foreign(live);
+        self.leak_tracker = Some(tracker);
+    }
+
+    /// Calls `f` for every call site and size class with live objects (see `LeakTracker::report`).
+    ///
+    /// Returns the number of live objects that couldn't be recorded because the table was full,
+    /// or an error if no leak tracker was set.
+    #[cfg(feature = "leak-tracking")]
+    pub fn leak_report<F: FnMut(LeakSite)>(&self, f: F) -> Result<usize, &'static str> {
+        let tracker = self.leak_tracker.as_ref().ok_or("The zone allocator has no leak tracker")?;
+        tracker.report(f);
+        Ok(tracker.untracked())
+    }
+
+    #[cfg(feature = "leak-tracking")]
+    fn track_allocation(&mut self, ptr: NonNull<u8>, idx: usize, caller: CallSite) {
+        if let Some(tracker) = self.leak_tracker.as_mut() {
+            tracker.record(ptr.as_ptr() as usize, Self::BASE_ALLOC_SIZES[idx], caller);
+        }
+    }
+
+    #[cfg(not(feature = "leak-tracking"))]
+    fn track_allocation(&mut self, _ptr: NonNull<u8>, _idx: usize, _caller: CallSite) {}
+
+    #[cfg(feature = "leak-tracking")]
+    fn untrack_allocation(&mut self, ptr: NonNull<u8>) {
+        if let Some(tracker) = self.leak_tracker.as_mut() {
+            tracker.forget(ptr.as_ptr() as usize);
+        }
+    }
+
+    #[cfg(not(feature = "leak-tracking"))]
+    fn untrack_allocation(&mut self, _ptr: NonNull<u8>) {}
+
+    /// Removes the freed object at `ptr` (of size class `idx`) from the checkpoint, if any.
+    fn forget_in_checkpoint(&mut self, ptr: NonNull<u8>, idx: usize) {
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            let size_class = self.small_slabs[idx].size;
+            let (page, slot) = Self::slot_of(ptr.as_ptr() as usize, size_class);
+            checkpoint.forget(page, size_class, slot);
+        }
+    }
+
+    /// The page and the slot index of the object at `addr` (of `size_class`).
+    fn slot_of(addr: usize, size_class: usize) -> (usize, usize) {
+        let page = addr & !(P::SIZE - 1);
+        (page, (addr - page) / slot_size(size_class))
+    }
+
     pub fn exchange_pages_within_heap(&mut self, layout: Layout, heap_id: usize) -> Result<(), &'static str> {
-        let mp = self.retrieve_empty_page().ok_or("Couldn't find an empty page to exchange within the heap")?;
+        let (surplus, idx) = self.small_slab_with_max_surplus();
+        let mp = if surplus > 0 { self.small_slabs[idx].retrieve_empty_page()? } else { None }
+            .ok_or("Couldn't find an empty page to exchange within the heap")?;
+        hook!(self, on_exchange(mp.start_address().value(), Self::BASE_ALLOC_SIZES[idx], Self::class_size(layout), heap_id));
         self.refill(layout, mp, heap_id)
     }  
 
+    /// Sets the `PageSource` this zone refills itself from when it runs out of memory.
+    ///
+    /// Pages from the source are stamped with `heap_id`.
+    pub fn set_page_source(&mut self, source: &'a dyn PageSource, heap_id: usize) {
+        self.page_source = Some(source);
+        self.home_heap_id = heap_id;
+    }
+
+    /// Removes the `PageSource` of this zone (if any) and returns it.
+    pub fn take_page_source(&mut self) -> Option<&'a dyn PageSource> {
+        self.page_source.take()
+    }
+
+    /// Sets the watermarks for this zone.
+    ///
+    /// Returning pages above the high watermark requires a page source,
+    /// see `set_page_source`.
+    pub fn set_watermarks(&mut self, watermarks: Watermarks) {
+        self.watermarks = watermarks;
+    }
+
+    /// Returns the watermarks of this zone.
+    pub fn watermarks(&self) -> Watermarks {
+        self.watermarks
+    }
+
+    /// Sets the function to call when a size class falls below the low watermark.
+    pub fn set_low_memory_callback(&mut self, callback: LowMemoryCallback) {
+        self.low_memory_callback = Some(callback);
+    }
+
+    /// Invokes the low-memory callback if the size class `idx` just fell below
+    /// the low watermark, or re-arms it if the class has recovered.
+    fn check_low_watermark(&mut self, idx: usize) {
+        let callback = match self.low_memory_callback {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        let free_slots = self.small_slabs[idx].free_slots();
+        if free_slots < self.watermarks.low_free_slots {
+            if !self.low_signalled[idx] {
+                self.low_signalled[idx] = true;
+                let heap_id = self.small_slabs[idx].heap_id().unwrap_or(self.home_heap_id);
+                callback(heap_id, self.small_slabs[idx].size(), free_slots);
+            }
+        } else {
+            self.low_signalled[idx] = false;
+        }
+    }
+
+    /// Hands empty pages above the high watermark back to the page source.
+    fn check_high_watermark(&mut self) {
+        if let (Some(high), Some(source)) = (self.watermarks.high_empty_pages, self.page_source) {
+            self.return_empty_pages(source, high);
+        }
+    }
+
+    /// Hands empty pages (above the per class thresholds) back to `source`
+    /// until the zone holds at most `keep` empty pages.
+    ///
+    /// Returns the number of pages that were given back.
+    fn return_empty_pages(&mut self, source: &dyn PageSource, keep: usize) -> usize {
+        let mut returned = 0;
+        while self.empty_pages() > keep {
+            match self.retrieve_empty_page() {
+                Ok(Some(mp)) => source.release_page(mp),
+                // A corruption is reported by the next allocation from the size class.
+                Ok(None) | Err(_) => break,
+            }
+            returned += 1;
+        }
+        returned
+    }
+
+    /// Sets the depot this zone shares empty pages with.
+    ///
+    /// Pages pulled from the depot when the zone runs out of memory are stamped with `heap_id`.
+    pub fn set_depot(&mut self, depot: &'a dyn Depot<'a, P>, limits: DepotLimits, heap_id: usize) {
+        self.depot = Some(depot);
+        self.depot_limits = limits;
+        self.home_heap_id = heap_id;
+    }
+
+    /// The total number of pages in this zone allocator.
+    pub fn pages(&self) -> usize {
+        self.small_slabs.iter().map(|sca| sca.pages()).sum()
+    }
+
+    /// Sets the RNG all size classes use to pick slots and partial pages.
+    #[cfg(feature = "hardened")]
+    pub fn set_rng(&mut self, rng: &'a dyn SlotRng) {
+        for sca in self.small_slabs.iter_mut() {
+            sca.set_rng(rng);
+        }
+    }
+
+    /// Sets the hooks that are called on the events of this zone and all of its size classes.
+    #[cfg(feature = "hooks")]
+    pub fn set_hooks(&mut self, hooks: &'a dyn AllocatorHooks) {
+        self.hooks = Some(hooks);
+        for sca in self.small_slabs.iter_mut() {
+            sca.set_hooks(hooks);
+        }
+    }
+
+    /// Checks all objects of all size classes for corruption (see `SCAllocator::verify`).
+    pub fn verify(&mut self) -> Result<(), &'static str> {
+        let mut res = Ok(());
+        for sca in self.small_slabs.iter_mut() {
+            if let Err(e) = sca.verify() {
+                res = Err(e);
+            }
+        }
+        res
+    }
+
+    /// Pushes the empty pages above `DepotLimits::push_above` into the depot.
+    ///
+    /// Returns the number of pages that were pushed.
+    pub fn push_to_depot(&mut self) -> usize {
+        let depot = match self.depot {
+            Some(depot) => depot,
+            None => return 0,
+        };
+
+        let mut pushed = 0;
+        while self.empty_pages() > self.depot_limits.push_above {
+            let (surplus, idx) = self.small_slab_with_max_surplus();
+            if surplus == 0 {
+                break;
+            }
+            let page = match self.small_slabs[idx].take_empty_page() {
+                Ok(Some(page)) => page,
+                // A corruption is reported by the next allocation from the size class.
+                Ok(None) | Err(_) => break,
+            };
+            if let Err(page) = depot.push(page) {
+                // The depot is full, keep the page (it is leaked if it's corrupted).
+                let _ = self.small_slabs[idx].insert_empty_page(page);
+                break;
+            }
+            pushed += 1;
+        }
+        pushed
+    }
+
+    /// Moves a page from the depot into the size class for `layout`,
+    /// unless this zone already reached `DepotLimits::max_pages`.
+    pub fn pull_from_depot(&mut self, layout: Layout, heap_id: usize) -> Result<(), &'static str> {
+        let depot = self.depot.ok_or("The zone allocator has no depot")?;
+        if let Some(max_pages) = self.depot_limits.max_pages {
+            if self.pages() >= max_pages {
+                return Err("The zone allocator reached its page limit for the depot");
+            }
+        }
+
+        match Self::get_slab(Self::class_size(layout)) {
+            Slab::Base(idx) => {
+                let page = depot.pull(heap_id)?.ok_or("The depot is empty")?;
+                hook!(self, on_refill(page.data_addr(), Self::BASE_ALLOC_SIZES[idx], heap_id));
+                self.small_slabs[idx].insert_empty_page(page)
+            }
+            Slab::ZeroSized => Err(INVALID_LAYOUT),
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
+        }
+    }
+
+    /// Gets a new page for the size class of `layout` after an allocation failed.
+    ///
+    /// We first try to move an empty page over from another size class,
+    /// then from the depot (if we have one) and only then we ask the page
+    /// source (if we have one). Pages from the depot or the page source are
+    /// stamped with the heap id given to `set_depot` or `set_page_source`.
+    fn refill_on_oom(&mut self, layout: Layout) -> Result<(), &'static str> {
+        let heap_id = match (self.page_source, self.depot) {
+            (None, None) => self
+                .small_slabs
+                .iter()
+                .find_map(|sca| sca.heap_id())
+                .ok_or("There were no pages in the heap")?,
+            _ => self.home_heap_id,
+        };
+
+        let exchanged = self.exchange_pages_within_heap(layout, heap_id);
+        if exchanged.is_ok() {
+            return exchanged;
+        }
+        if self.depot.is_some() && self.pull_from_depot(layout, heap_id).is_ok() {
+            return Ok(());
+        }
+
+        match self.page_source {
+            Some(source) => {
+                let mp = source.allocate_page().ok_or(OUT_OF_MEMORY)?;
+                self.refill(layout, mp, heap_id)
+            }
+            None => exchanged,
+        }
+    }
+
+    /// Hands all empty pages (above the threshold) back to the page source.
+    ///
+    /// Returns the number of pages that were given back,
+    /// or an error if the zone has no page source.
+    pub fn trim(&mut self) -> Result<usize, &'static str> {
+        let source = self.page_source.ok_or("The zone allocator has no page source")?;
+        Ok(self.return_empty_pages(source, 0))
+    }
+
+    /// Releases the quarantined objects of every size class (their slots can be reused again).
+    #[cfg(feature = "quarantine")]
+    pub fn flush_quarantine(&mut self) {
+        for sca in self.small_slabs.iter_mut() {
+            sca.flush_quarantine();
+        }
+    }
+
         /// The total number of empty pages in this zone allocator
     pub fn empty_pages(&self) -> usize {
         let mut empty_pages = 0;
@@ -187,24 +1105,26 @@ impl<'a> ZoneAllocator<'a> {
         }
         (max_empty_pages, id)
     }
+
+    /// Number of empty pages above the threshold and index of the small slab with the most of them.
+    fn small_slab_with_max_surplus(&self) -> (usize, usize) {
+        let mut max_surplus = 0;
+        let mut id = 0;
+        for i in 0..self.small_slabs.len() {
+            let surplus = self.small_slabs[i].surplus_empty_pages();
+            if surplus > max_surplus {
+                max_surplus = surplus;
+                id = i;
+            }
+        }
+        (max_surplus, id)
+    }
 }
 
-unsafe impl<'a> crate::Allocator<'a> for ZoneAllocator<'a> {
+unsafe impl<'a, P: AllocablePage> crate::Allocator<'a> for ZoneAllocator<'a, P> {
     /// Allocate a pointer to a block of memory described by `layout`.
     fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, &'static str> {
-        match ZoneAllocator::get_slab(layout.size()) {
-            Slab::Base(idx) => {
-                match self.small_slabs[idx].allocate(layout) {
-                    Ok(ptr) => Ok(ptr),
-                    Err(_e) => {
-                        self.exchange_pages_within_heap(layout, self.heap_id()?)?;
-                        self.small_slabs[idx].allocate(layout)
-                    }
-                }
-            }
-            Slab::Large(_idx) => Err("AllocationError::InvalidLayout"),
-            Slab::Unsupported => Err("AllocationError::InvalidLayout"),
-        }
+        self.allocate_from(layout, call_site())
     }
 
     /// Deallocates a pointer to a block of memory, which was
@@ -214,10 +1134,22 @@ unsafe impl<'a> crate::Allocator<'a> for ZoneAllocator<'a> {
     ///  * `ptr` - Address of the memory location to free.
     ///  * `layout` - Memory layout of the block pointed to by `ptr`.
     fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str> {
-        match ZoneAllocator::get_slab(layout.size()) {
-            Slab::Base(idx) => self.small_slabs[idx].deallocate(ptr, layout),
-            Slab::Large(_idx) => Err("AllocationError::InvalidLayout"),
-            Slab::Unsupported => Err("AllocationError::InvalidLayout"),
+        match Self::get_slab(Self::class_size(layout)) {
+            Slab::Base(idx) => {
+                let ret = self.small_slabs[idx].deallocate(ptr, layout);
+                if ret.is_ok() {
+                    self.untrack_allocation(ptr);
+                    self.forget_in_checkpoint(ptr, idx);
+                }
+                self.push_to_depot();
+                self.check_high_watermark();
+                self.check_low_watermark(idx);
+                ret
+            }
+            // Nothing was allocated for zero-sized objects.
+            Slab::ZeroSized => Ok(()),
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
         }
     }
 
@@ -231,12 +1163,15 @@ unsafe impl<'a> crate::Allocator<'a> for ZoneAllocator<'a> {
         mp: MappedPages,
         heap_id: usize
     ) -> Result<(), &'static str> {
-        match ZoneAllocator::get_slab(layout.size()) {
+        match Self::get_slab(Self::class_size(layout)) {
             Slab::Base(idx) => {
-                self.small_slabs[idx].refill(mp, heap_id)
+                let ret = self.small_slabs[idx].refill(mp, heap_id);
+                self.check_low_watermark(idx);
+                ret
             }
-            Slab::Large(_idx) => Err("AllocationError::InvalidLayout"),
-            Slab::Unsupported => Err("AllocationError::InvalidLayout"),
+            Slab::ZeroSized => Err(INVALID_LAYOUT),
+            Slab::Large(_idx) => Err(INVALID_LAYOUT),
+            Slab::Unsupported => Err(INVALID_LAYOUT),
         }
     }
 